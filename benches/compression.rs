@@ -0,0 +1,62 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use ipfs_embed::{Config, Ipfs, WritableStore};
+use libipld::block::Block;
+use libipld::cbor::DagCborCodec;
+use libipld::ipld::Ipld;
+use libipld::multihash::SHA2_256;
+use std::collections::BTreeMap;
+use tempdir::TempDir;
+
+/// A text heavy DAG-CBOR block of about 16KiB.
+fn payload(i: u64) -> Ipld {
+    let mut map = BTreeMap::new();
+    for j in 0..256 {
+        let key = format!("key-{}-{}", i, j);
+        map.insert(key, Ipld::String(format!("the quick brown fox {}", j)));
+    }
+    Ipld::Map(map)
+}
+
+fn create_store(compress: bool) -> (Ipfs, TempDir) {
+    let tmp = TempDir::new("").unwrap();
+    let mut config = Config::from_path_local(tmp.path()).unwrap();
+    config.network.enable_mdns = false;
+    config.compress_blocks = compress;
+    (Ipfs::new(config).unwrap(), tmp)
+}
+
+fn bench_store(c: &mut Criterion, name: &str, compress: bool) {
+    let (store, _tmp) = create_store(compress);
+    let mut i = 0;
+    c.bench_function(&format!("insert {}: 16KiB dag-cbor", name), |b| {
+        b.iter_batched(
+            || {
+                i += 1;
+                Block::encode(DagCborCodec, SHA2_256, &payload(i)).unwrap()
+            },
+            |block| async_std::task::block_on(store.insert(&block)).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+
+    let block = Block::encode(DagCborCodec, SHA2_256, &payload(0)).unwrap();
+    async_std::task::block_on(store.insert(&block)).unwrap();
+    c.bench_function(&format!("get_local {}: 16KiB dag-cbor", name), |b| {
+        b.iter(|| black_box(store.get_local(&block.cid).unwrap()))
+    });
+}
+
+fn uncompressed(c: &mut Criterion) {
+    bench_store(c, "uncompressed", false);
+}
+
+fn compressed(c: &mut Criterion) {
+    bench_store(c, "compressed", true);
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(20);
+    targets = uncompressed, compressed
+}
+criterion_main!(benches);
@@ -1,5 +1,9 @@
 use clap::Clap;
+use ipfs_embed::{Multiaddr, PeerId};
 use libipld::cid::Cid;
+use libipld::codec_impl::Multicodec;
+use libipld::multihash::{BLAKE2B_256, SHA2_256, SHA2_512};
+use std::convert::TryFrom;
 use std::path::PathBuf;
 
 #[derive(Clone, Debug, Clap)]
@@ -10,15 +14,92 @@ pub struct Opts {
     pub path: PathBuf,
     #[clap(short = "t", long = "tree")]
     pub tree: Option<String>,
+    /// File with the node key. Defaults to `key` in the database directory.
+    /// The key is created if the file doesn't exist.
+    #[clap(long = "key-file")]
+    pub key_file: Option<PathBuf>,
+    /// `swarm.key` file with the pre-shared key of a private network.
+    #[clap(long = "swarm-key")]
+    pub swarm_key: Option<PathBuf>,
+    /// Address to listen on. Can be repeated.
+    #[clap(
+        long = "listen",
+        number_of_values = 1,
+        default_value = "/ip4/0.0.0.0/tcp/0"
+    )]
+    pub listen: Vec<Multiaddr>,
+    /// Address to advertise to peers. Can be repeated.
+    #[clap(long = "external", number_of_values = 1)]
+    pub external: Vec<Multiaddr>,
+    /// Milliseconds between background flushes of the database.
+    #[clap(long = "flush-every-ms", default_value = "500")]
+    pub flush_every_ms: u64,
+    /// Flushes the database after this many inserted blocks.
+    #[clap(long = "flush-every")]
+    pub flush_every: Option<u64>,
+    /// Only uses the local blocks, blocks that aren't stored aren't fetched.
+    #[clap(long = "offline")]
+    pub offline: bool,
 }
 
 #[derive(Clone, Debug, Clap)]
 pub enum SubCommand {
-    Tree,
+    Tree(TreeCommand),
     Ls(LsCommand),
     Cat(CatCommand),
+    Get(GetCommand),
+    Put(PutCommand),
+    Add(AddCommand),
     Refs(RefsCommand),
+    Missing(MissingCommand),
+    Pin(PinCommand),
     Unpin(UnpinCommand),
+    Gc(GcCommand),
+    Repair(RepairCommand),
+    Peers(PeersCommand),
+    Connect(ConnectCommand),
+    Findprovs(FindprovsCommand),
+    Provide(ProvideCommand),
+    Unprovide(UnprovideCommand),
+    Ban(BanCommand),
+    Unban(UnbanCommand),
+    Id(IdCommand),
+    Version(VersionCommand),
+    Export(ExportCommand),
+    Import(ImportCommand),
+    BitswapStats(BitswapStatsCommand),
+    Wantlist(WantlistCommand),
+    Watch(WatchCommand),
+    Stats(StatsCommand),
+    Dups(DupsCommand),
+    Dag(DagCommand),
+    Pubsub(PubsubCommand),
+    Dht(DhtCommand),
+    #[cfg(feature = "gateway")]
+    Gateway(GatewayCommand),
+    #[cfg(feature = "metrics")]
+    Metrics(MetricsCommand),
+}
+
+/// Lists the trees of the database.
+#[derive(Clone, Debug, Clap)]
+pub struct TreeCommand {
+    #[clap(subcommand)]
+    pub cmd: Option<TreeSubCommand>,
+}
+
+#[derive(Clone, Debug, Clap)]
+pub enum TreeSubCommand {
+    Rm(TreeRmCommand),
+}
+
+/// Drops a tree and all its entries.
+#[derive(Clone, Debug, Clap)]
+pub struct TreeRmCommand {
+    pub name: String,
+    /// Doesn't ask for confirmation.
+    #[clap(short = "y", long = "yes")]
+    pub yes: bool,
 }
 
 #[derive(Clone, Debug, Clap)]
@@ -31,19 +112,406 @@ pub struct LsCommand {
     pub dead: bool,
     #[clap(long = "all", conflicts_with_all(&["pinned", "live", "dead"]))]
     pub all: bool,
+    #[clap(long = "json")]
+    pub json: bool,
+    /// Maximum number of blocks to print.
+    #[clap(long = "limit")]
+    pub limit: Option<usize>,
+    /// Starts listing after this block.
+    #[clap(long = "after")]
+    pub after: Option<Cid>,
 }
 
 #[derive(Clone, Debug, Clap)]
 pub struct CatCommand {
     pub cid: Cid,
+    /// Prints the bytes of the block instead of decoding it, like `--codec
+    /// raw`.
+    #[clap(long = "raw")]
+    pub raw: bool,
+    /// Encoding of the output, one of dag-json, dag-cbor, raw or
+    /// json-pretty. Defaults to dag-json, or raw for blocks with the raw
+    /// codec.
+    #[clap(long = "codec", parse(try_from_str = parse_output_codec))]
+    pub codec: Option<OutputCodec>,
+}
+
+/// Encoding of a decoded block.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutputCodec {
+    DagJson,
+    DagCbor,
+    /// The stored bytes.
+    Raw,
+    /// DAG-JSON with indentation.
+    JsonPretty,
+}
+
+fn parse_output_codec(codec: &str) -> Result<OutputCodec, String> {
+    Ok(match codec {
+        "dag-json" => OutputCodec::DagJson,
+        "dag-cbor" => OutputCodec::DagCbor,
+        "raw" => OutputCodec::Raw,
+        "json-pretty" => OutputCodec::JsonPretty,
+        _ => {
+            return Err(format!(
+                "unsupported codec {}, expected one of dag-json, dag-cbor, raw, json-pretty",
+                codec
+            ))
+        }
+    })
+}
+
+#[derive(Clone, Debug, Clap)]
+pub struct GetCommand {
+    pub cid: Cid,
+    #[clap(short = "o", long = "output")]
+    pub output: Option<PathBuf>,
+    #[clap(long = "timeout")]
+    pub timeout: Option<u64>,
+}
+
+/// Reads stdin into raw blocks and prints the cid of a DAG-CBOR list
+/// linking to them in order.
+#[derive(Clone, Debug, Clap)]
+pub struct AddCommand {
+    /// Size of the raw blocks, up to 1MiB.
+    #[clap(long = "chunk-size", default_value = "262144")]
+    pub chunk_size: usize,
+    /// Pins the list.
+    #[clap(long = "pin")]
+    pub pin: bool,
+}
+
+#[derive(Clone, Debug, Clap)]
+pub struct PutCommand {
+    #[clap(long = "codec", default_value = "dag-json", parse(try_from_str = parse_codec))]
+    pub codec: Multicodec,
+    #[clap(long = "hash", default_value = "sha2-256", parse(try_from_str = parse_hash))]
+    pub hash: u64,
+    pub file: PathBuf,
+}
+
+fn parse_codec(codec: &str) -> Result<Multicodec, String> {
+    Ok(match codec {
+        "raw" => Multicodec::Raw,
+        "dag-cbor" => Multicodec::DagCbor,
+        "dag-json" => Multicodec::DagJson,
+        "dag-pb" => Multicodec::DagPb,
+        _ => {
+            return Err(format!(
+                "unsupported codec {}, expected one of raw, dag-cbor, dag-json, dag-pb",
+                codec
+            ))
+        }
+    })
+}
+
+fn parse_hash(hash: &str) -> Result<u64, String> {
+    Ok(match hash {
+        "sha2-256" => SHA2_256,
+        "sha2-512" => SHA2_512,
+        "blake2b-256" => BLAKE2B_256,
+        _ => {
+            return Err(format!(
+                "unsupported hash {}, expected one of sha2-256, sha2-512, blake2b-256",
+                hash
+            ))
+        }
+    })
 }
 
 #[derive(Clone, Debug, Clap)]
 pub struct RefsCommand {
     pub cid: Cid,
+    /// Prints every block reachable from the cid with its depth.
+    #[clap(short = "r", long = "recursive")]
+    pub recursive: bool,
+    #[clap(long = "max-depth", requires = "recursive")]
+    pub max_depth: Option<usize>,
+}
+
+/// Prints the blocks reachable from the cid, or from all pinned blocks, that
+/// aren't stored locally.
+#[derive(Clone, Debug, Clap)]
+pub struct MissingCommand {
+    pub cid: Option<Cid>,
+}
+
+#[derive(Clone, Debug, Clap)]
+pub struct PinCommand {
+    #[clap(subcommand)]
+    pub cmd: Option<PinSubCommand>,
+    pub cid: Option<Cid>,
+    #[clap(short = "r", long = "recursive", conflicts_with = "label")]
+    pub recursive: bool,
+    /// Pins under a label, which only `unpin --label` removes.
+    #[clap(long = "label")]
+    pub label: Option<String>,
+}
+
+#[derive(Clone, Debug, Clap)]
+pub enum PinSubCommand {
+    Ls(PinLsCommand),
+    Rm(PinRmCommand),
+}
+
+/// Lists the pinned blocks.
+#[derive(Clone, Debug, Clap)]
+pub struct PinLsCommand {
+    /// Prints the labels of the named pins of each block.
+    #[clap(long = "labels")]
+    pub labels: bool,
+}
+
+/// Removes the unnamed pins of blocks, or all pins with `--all`.
+#[derive(Clone, Debug, Clap)]
+pub struct PinRmCommand {
+    #[clap(required_unless = "all")]
+    pub cids: Vec<Cid>,
+    /// Removes all pins, including the pins of labels.
+    #[clap(long = "all", conflicts_with = "cids")]
+    pub all: bool,
 }
 
 #[derive(Clone, Debug, Clap)]
 pub struct UnpinCommand {
     pub cid: Cid,
+    /// Removes the pin of a label instead of an unnamed pin.
+    #[clap(long = "label")]
+    pub label: Option<String>,
+}
+
+#[derive(Clone, Debug, Clap)]
+pub struct GcCommand {
+    #[clap(long = "dry-run")]
+    pub dry_run: bool,
+}
+
+/// Rebuilds the references of the blocks from their data and recounts the
+/// referers, printing the blocks that were fixed.
+#[derive(Clone, Debug, Clap)]
+pub struct RepairCommand {}
+
+#[derive(Clone, Debug, Clap)]
+pub struct PeersCommand {
+    #[clap(long = "wait")]
+    pub wait: Option<u64>,
+}
+
+#[derive(Clone, Debug, Clap)]
+pub struct ConnectCommand {
+    pub address: Multiaddr,
+}
+
+#[derive(Clone, Debug, Clap)]
+pub struct FindprovsCommand {
+    pub cid: Cid,
+}
+
+/// Makes a local block public, so that it is announced in the DHT.
+#[derive(Clone, Debug, Clap)]
+pub struct ProvideCommand {
+    pub cid: Cid,
+}
+
+/// Makes a block private, so that it isn't announced anymore.
+#[derive(Clone, Debug, Clap)]
+pub struct UnprovideCommand {
+    pub cid: Cid,
+}
+
+#[derive(Clone, Debug, Clap)]
+pub struct BanCommand {
+    pub peer_id: PeerId,
+}
+
+#[derive(Clone, Debug, Clap)]
+pub struct UnbanCommand {
+    pub peer_id: PeerId,
+}
+
+/// Prints the crate, libp2p and protocol versions for bug reports.
+#[derive(Clone, Debug, Clap)]
+pub struct VersionCommand {
+    #[clap(long = "json")]
+    pub json: bool,
+}
+
+#[derive(Clone, Debug, Clap)]
+pub struct IdCommand {
+    #[clap(long = "json")]
+    pub json: bool,
+}
+
+#[derive(Clone, Debug, Clap)]
+pub struct ExportCommand {
+    pub cid: Cid,
+    /// File to write to, `-` for stdout.
+    pub file: PathBuf,
+    #[clap(long = "skip-missing")]
+    pub skip_missing: bool,
+}
+
+#[derive(Clone, Debug, Clap)]
+pub struct ImportCommand {
+    pub file: PathBuf,
+    #[clap(long = "pin")]
+    pub pin: bool,
+}
+
+#[derive(Clone, Debug, Clap)]
+pub struct BitswapStatsCommand {
+    #[clap(long = "json")]
+    pub json: bool,
+}
+
+/// Lists the outstanding wants. The given cids are fetched in the background
+/// and the wantlist is printed after `--wait` seconds.
+#[derive(Clone, Debug, Clap)]
+pub struct WantlistCommand {
+    pub cids: Vec<Cid>,
+    #[clap(long = "wait")]
+    pub wait: Option<u64>,
+}
+
+/// Prints the wants, cancels, provides, unprovides and roots of blocks with
+/// the unix time in seconds as they happen, until interrupted. The given
+/// cids are fetched in the background.
+#[derive(Clone, Debug, Clap)]
+pub struct WatchCommand {
+    pub cids: Vec<Cid>,
+}
+
+#[derive(Clone, Debug, Clap)]
+pub struct StatsCommand {
+    #[clap(long = "json")]
+    pub json: bool,
+}
+
+/// Finds blocks with identical bytes under different cids, for example the
+/// same data imported with different hashes. Prints the size and the cids
+/// of each group.
+#[derive(Clone, Debug, Clap)]
+pub struct DupsCommand {
+    /// Only prints the number of redundant blocks and their bytes.
+    #[clap(long = "stats")]
+    pub stats: bool,
+    #[clap(long = "json", requires = "stats")]
+    pub json: bool,
+}
+
+#[derive(Clone, Debug, Clap)]
+pub struct PubsubCommand {
+    #[clap(subcommand)]
+    pub cmd: PubsubSubCommand,
+}
+
+#[derive(Clone, Debug, Clap)]
+pub enum PubsubSubCommand {
+    Pub(PubsubPubCommand),
+    Sub(PubsubSubCommandArgs),
+}
+
+#[derive(Clone, Debug, Clap)]
+pub struct PubsubPubCommand {
+    pub topic: String,
+    pub message: String,
+    /// Seconds to wait for a peer subscribed to the topic.
+    #[clap(long = "wait", default_value = "10")]
+    pub wait: u64,
+}
+
+#[derive(Clone, Debug, Clap)]
+pub struct PubsubSubCommandArgs {
+    pub topic: String,
+}
+
+/// Stores and looks up records signed by the node key in the DHT.
+#[derive(Clone, Debug, Clap)]
+pub struct DhtCommand {
+    #[clap(subcommand)]
+    pub cmd: DhtSubCommand,
+}
+
+#[derive(Clone, Debug, Clap)]
+pub enum DhtSubCommand {
+    Put(DhtPutCommand),
+    Get(DhtGetCommand),
+}
+
+#[derive(Clone, Debug, Clap)]
+pub struct DhtPutCommand {
+    pub key: String,
+    pub value: String,
+}
+
+/// Prints the publisher and the value of each record.
+#[derive(Clone, Debug, Clap)]
+pub struct DhtGetCommand {
+    pub key: String,
+}
+
+/// Serves blocks over HTTP at `GET /ipfs/<cid>` until interrupted.
+#[cfg(feature = "gateway")]
+#[derive(Clone, Debug, Clap)]
+pub struct GatewayCommand {
+    #[clap(long = "listen", default_value = "127.0.0.1:8080")]
+    pub listen: String,
+}
+
+/// Serves Prometheus metrics over HTTP at `GET /metrics` until interrupted.
+#[cfg(feature = "metrics")]
+#[derive(Clone, Debug, Clap)]
+pub struct MetricsCommand {
+    #[clap(long = "listen", default_value = "127.0.0.1:9100")]
+    pub listen: String,
+}
+
+#[derive(Clone, Debug, Clap)]
+pub struct DagCommand {
+    #[clap(subcommand)]
+    pub cmd: DagSubCommand,
+}
+
+#[derive(Clone, Debug, Clap)]
+pub enum DagSubCommand {
+    Get(DagGetCommand),
+    Chain(DagChainCommand),
+}
+
+#[derive(Clone, Debug, Clap)]
+pub struct DagGetCommand {
+    /// Path of the form `<cid>/a/b/c`. Segments are map keys or list indices.
+    #[clap(parse(try_from_str = parse_ipld_path))]
+    pub path: IpldPath,
+}
+
+/// Follows the links under a field from block to block until a block without
+/// it, like the previous versions of a head. Prints the traversed cids, the
+/// last one is the end of the chain.
+#[derive(Clone, Debug, Clap)]
+pub struct DagChainCommand {
+    pub cid: Cid,
+    /// Field of the link to the next block.
+    pub field: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct IpldPath {
+    pub cid: Cid,
+    pub path: String,
+}
+
+fn parse_ipld_path(path: &str) -> Result<IpldPath, String> {
+    let path = path.trim_start_matches("/ipfs/");
+    let (cid, path) = match path.find('/') {
+        Some(i) => (&path[..i], &path[(i + 1)..]),
+        None => (path, ""),
+    };
+    let cid = Cid::try_from(cid).map_err(|err| format!("invalid cid {}: {}", cid, err))?;
+    Ok(IpldPath {
+        cid,
+        path: path.to_string(),
+    })
 }
@@ -1,66 +1,720 @@
 use crate::command::*;
+use async_std::stream::StreamExt;
 use clap::Clap;
-use ipfs_embed::{Cid, Config, Metadata, Store, WritableStore};
+use ipfs_embed::{
+    load_or_create_keypair, load_psk, open_db, Cid, Config, Error, Metadata, Multiaddr, PeerId,
+    ReadonlyStore, StorageEvent, Store, WritableStore,
+};
 use libipld::block::Block;
+use libipld::cbor::DagCborCodec;
+use libipld::cid::RAW;
 use libipld::codec::Codec;
 use libipld::codec_impl::Multicodec;
+use libipld::ipld::Ipld;
 use libipld::json::DagJsonCodec;
-use libipld::multihash::Multihash;
+use libipld::multihash::{Multihash, MultihashDigest, SHA2_256};
+use libipld::path::DagPath;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::{Read, Write};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 mod command;
 
+/// Number of blocks `ls` reads at once.
+const LS_PAGE_SIZE: usize = 1000;
+
+/// Number of chunks `add` inserts at once.
+const ADD_BATCH_SIZE: usize = 64;
+
+/// Tree with the peers denied by `ban`, so they stay banned across runs.
+const DENIED_PEERS_TREE: &str = "denied_peers";
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
     let opts = Opts::parse();
-    let db = sled::open(opts.path)?;
+    // doesn't need the database
+    if let SubCommand::Version(VersionCommand { json }) = &opts.cmd {
+        return print_version(*json);
+    }
+    let db = match open_db(
+        &opts.path,
+        sled::Config::new().flush_every_ms(Some(opts.flush_every_ms)),
+    ) {
+        Ok(db) => db,
+        // printed with `Display` as the `Debug` output of `main` isn't actionable
+        Err(err @ Error::DatabaseLocked(_)) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        Err(err) => return Err(err.into()),
+    };
     let tree_name = opts.tree.unwrap_or_else(|| ipfs_embed::TREE.to_string());
-    let tree = db.open_tree(tree_name)?;
-    let config = Config::new(tree, Default::default());
+    let tree = db.open_tree(&tree_name)?;
+    let denied_peers = db.open_tree(DENIED_PEERS_TREE)?;
+    let mut config = Config::new(tree, Default::default());
+    let key_file = match opts.key_file {
+        Some(key_file) => key_file,
+        None => opts.path.join(ipfs_embed::KEY_FILE),
+    };
+    config.network.node_key = load_or_create_keypair(key_file)?;
+    if let Some(swarm_key) = opts.swarm_key {
+        config.network.psk = Some(load_psk(swarm_key)?);
+    }
+    config.network.listen_addresses = opts.listen;
+    config.network.public_addresses = opts.external;
+    config.flush_every = opts.flush_every;
+    config.offline = opts.offline;
+    for key in denied_peers.iter().keys() {
+        config
+            .network
+            .denied_peers
+            .insert(PeerId::from_bytes(key?.to_vec()).map_err(|_| "invalid peer id")?);
+    }
+    if let SubCommand::Get(GetCommand {
+        timeout: Some(timeout),
+        ..
+    }) = &opts.cmd
+    {
+        config.timeout = Duration::from_secs(*timeout);
+    }
+    if let SubCommand::Put(PutCommand { hash, .. }) = &opts.cmd {
+        config.hash_code = *hash;
+    }
+    if let SubCommand::Pubsub(_) = &opts.cmd {
+        config.network.enable_pubsub = true;
+    }
     let store = Store::<Multicodec, Multihash>::new(config)?;
     match opts.cmd {
-        SubCommand::Tree => {
+        SubCommand::Tree(TreeCommand { cmd: None }) => {
             for name in db.tree_names() {
                 println!("{}", std::str::from_utf8(&name).unwrap());
             }
         }
+        SubCommand::Tree(TreeCommand {
+            cmd: Some(TreeSubCommand::Rm(TreeRmCommand { name, yes })),
+        }) => {
+            if name == tree_name || name == DENIED_PEERS_TREE {
+                return Err(format!("tree {} is in use", name).into());
+            }
+            // opening a tree that doesn't exist would create it
+            if !db.tree_names().iter().any(|tree| tree == name.as_bytes()) {
+                return Err(format!("tree {} doesn't exist", name).into());
+            }
+            let entries = db.open_tree(&name)?.len();
+            if !yes && !confirm(&format!("drop tree {} with {} entries?", name, entries))? {
+                return Ok(());
+            }
+            db.drop_tree(&name)?;
+            println!("removed {} entries", entries);
+        }
         SubCommand::Ls(LsCommand {
             pinned,
             live,
             dead,
             all,
+            json,
+            limit,
+            after,
         }) => {
-            println!(
-                "{:10} {:10} {:10} {:10} cid",
-                "pins", "parents", "children", "public"
-            );
-            for res in store.blocks() {
-                let cid = res?;
-                let metadata = store.metadata(&cid)?;
-                let is_pinned = metadata.pins > 0;
-                let is_live = metadata.referers > 0 || metadata.pins > 0;
-                let all = all || (!pinned && !live && !dead);
-                let print = all || pinned && is_pinned || live && is_live || dead && !is_live;
-                if print {
-                    print_metadata(&cid, &metadata);
+            if !json {
+                println!(
+                    "{:10} {:10} {:10} {:10} cid",
+                    "pins", "parents", "children", "public"
+                );
+            }
+            let mut cursor = after;
+            let mut printed = 0;
+            'pages: loop {
+                let page = store.blocks_page(cursor.as_ref(), LS_PAGE_SIZE)?;
+                for (cid, metadata) in store.metadata_batch(&page.blocks)? {
+                    if limit.map(|limit| printed >= limit).unwrap_or(false) {
+                        break 'pages;
+                    }
+                    let is_pinned = metadata.pins > 0;
+                    let is_live = metadata.referers > 0 || metadata.pins > 0;
+                    let all = all || (!pinned && !live && !dead);
+                    let print = all || pinned && is_pinned || live && is_live || dead && !is_live;
+                    if print && json {
+                        print_metadata_json(&cid, &metadata)?;
+                    } else if print {
+                        print_metadata(&cid, &metadata);
+                    }
+                    if print {
+                        printed += 1;
+                    }
                 }
+                cursor = match page.next {
+                    Some(next) => Some(next),
+                    None => break,
+                };
             }
         }
-        SubCommand::Cat(CatCommand { cid }) => {
+        SubCommand::Cat(CatCommand { cid, raw, codec }) => {
             if let Some(bytes) = store.get_local(&cid)? {
-                let data = bytes.to_vec().into_boxed_slice();
-                let block = Block::<Multicodec, Multihash>::new(cid, data);
-                let json = DagJsonCodec.encode(&block.decode_ipld()?)?;
-                println!("{}", std::str::from_utf8(&json)?);
+                let codec = match codec {
+                    _ if raw => OutputCodec::Raw,
+                    Some(codec) => codec,
+                    None if cid.codec() == RAW => OutputCodec::Raw,
+                    None => OutputCodec::DagJson,
+                };
+                let decode = || {
+                    let data = bytes.to_vec().into_boxed_slice();
+                    Block::<Multicodec, Multihash>::new(cid.clone(), data).decode_ipld()
+                };
+                match codec {
+                    OutputCodec::Raw => std::io::stdout().write_all(&bytes)?,
+                    OutputCodec::DagCbor => {
+                        std::io::stdout().write_all(&DagCborCodec.encode(&decode()?)?)?;
+                    }
+                    OutputCodec::DagJson => {
+                        let json = DagJsonCodec.encode(&decode()?)?;
+                        println!("{}", std::str::from_utf8(&json)?);
+                    }
+                    OutputCodec::JsonPretty => {
+                        let json = DagJsonCodec.encode(&decode()?)?;
+                        let json: serde_json::Value = serde_json::from_slice(&json)?;
+                        println!("{}", serde_json::to_string_pretty(&json)?);
+                    }
+                }
+            }
+        }
+        SubCommand::Get(GetCommand { cid, output, .. }) => {
+            let block = async_std::task::block_on(store.get(cid))?;
+            if let Some(output) = output {
+                std::fs::write(output, &block.data)?;
+            } else {
+                std::io::stdout().write_all(&block.data)?;
+            }
+        }
+        SubCommand::Put(PutCommand { codec, file, .. }) => {
+            let bytes = std::fs::read(file)?;
+            let ipld = codec.decode_ipld(&bytes)?;
+            let block = async_std::task::block_on(store.put(codec, &ipld))?;
+            println!("{}", block.cid);
+        }
+        SubCommand::Add(AddCommand { chunk_size, pin }) => {
+            let stdin = std::io::stdin();
+            let cid = async_std::task::block_on(add(&store, stdin.lock(), chunk_size, pin))?;
+            println!("{}", cid);
+        }
+        SubCommand::Refs(RefsCommand {
+            cid,
+            recursive,
+            max_depth,
+        }) => {
+            if recursive {
+                print_refs_recursive(&store, cid, max_depth)?;
+            } else {
+                let metadata = store.metadata(&cid)?;
+                for cid in metadata.refs {
+                    println!("{}", cid);
+                }
+            }
+        }
+        SubCommand::Missing(MissingCommand { cid }) => {
+            let roots = match cid {
+                Some(cid) => vec![cid],
+                None => store.pinned().collect::<Result<_, _>>()?,
+            };
+            let mut printed = HashSet::new();
+            for root in roots {
+                for cid in store.missing(&root)? {
+                    if printed.insert(cid.clone()) {
+                        println!("{}", cid);
+                    }
+                }
+            }
+        }
+        SubCommand::Pin(PinCommand {
+            cmd: Some(PinSubCommand::Ls(PinLsCommand { labels })),
+            ..
+        }) => {
+            for cid in store.pinned() {
+                let cid = cid?;
+                if labels {
+                    let labels = store.metadata(&cid)?.labels;
+                    if labels.is_empty() {
+                        println!("{} -", cid);
+                    } else {
+                        println!("{} {}", cid, labels.join(","));
+                    }
+                } else {
+                    println!("{}", cid);
+                }
+            }
+        }
+        SubCommand::Pin(PinCommand {
+            cmd: Some(PinSubCommand::Rm(PinRmCommand { cids, all })),
+            ..
+        }) => {
+            if all {
+                let count = async_std::task::block_on(store.unpin_all())?;
+                println!("unpinned {} blocks", count);
+            }
+            for cid in cids {
+                async_std::task::block_on(store.unpin(&cid))?;
+            }
+        }
+        SubCommand::Pin(PinCommand {
+            cid: Some(cid),
+            recursive,
+            label,
+            ..
+        }) => {
+            if let Some(label) = label {
+                async_std::task::block_on(store.pin_named(&cid, &label))?;
+            } else if recursive {
+                let count = async_std::task::block_on(store.pin_recursive(&cid))?;
+                println!("pinned {} blocks", count);
+            } else {
+                async_std::task::block_on(store.pin(&cid))?;
+            }
+        }
+        SubCommand::Pin(PinCommand { cid: None, .. }) => {
+            return Err("missing cid".into());
+        }
+        SubCommand::Unpin(UnpinCommand { cid, label }) => {
+            if let Some(label) = label {
+                async_std::task::block_on(store.unpin_named(&cid, &label))?;
+            } else {
+                async_std::task::block_on(store.unpin(&cid))?;
+            }
+        }
+        SubCommand::Peers(PeersCommand { wait }) => {
+            // give discovery some time to find peers
+            if let Some(wait) = wait {
+                std::thread::sleep(Duration::from_secs(wait));
+            }
+            for peer in async_std::task::block_on(store.peers())? {
+                let agent_version = peer.agent_version.as_deref().unwrap_or("-");
+                for address in &peer.addresses {
+                    println!("{} {} {}", peer.peer_id, address, agent_version);
+                }
+            }
+        }
+        SubCommand::Connect(ConnectCommand { address }) => {
+            let peer_id = async_std::task::block_on(store.connect(address))?;
+            println!("connected to {}", peer_id);
+        }
+        SubCommand::Findprovs(FindprovsCommand { cid }) => {
+            for peer_id in async_std::task::block_on(store.providers(&cid))? {
+                println!("{}", peer_id);
+            }
+        }
+        SubCommand::Provide(ProvideCommand { cid }) => {
+            async_std::task::block_on(store.set_public(&cid, true))?;
+        }
+        SubCommand::Unprovide(UnprovideCommand { cid }) => {
+            async_std::task::block_on(store.set_public(&cid, false))?;
+        }
+        SubCommand::Ban(BanCommand { peer_id }) => {
+            denied_peers.insert(peer_id.as_bytes(), &[])?;
+            store.ban(peer_id)?;
+        }
+        SubCommand::Unban(UnbanCommand { peer_id }) => {
+            denied_peers.remove(peer_id.as_bytes())?;
+            store.unban(peer_id)?;
+        }
+        SubCommand::Id(IdCommand { json }) => {
+            let addresses = async_std::task::block_on(store.addresses())?;
+            if json {
+                print_id_json(&store, &addresses)?;
+            } else {
+                println!("{}", store.peer_id());
+                for address in addresses {
+                    println!("{}", address);
+                }
+            }
+        }
+        SubCommand::Version(_) => unreachable!("handled before opening the database"),
+        SubCommand::Export(ExportCommand {
+            cid,
+            file,
+            skip_missing,
+        }) => {
+            let count = if file.as_os_str() == "-" {
+                let stdout = std::io::stdout();
+                let stdout = std::io::BufWriter::new(stdout.lock());
+                store.export_car(&cid, stdout, skip_missing)?
+            } else {
+                let file = std::io::BufWriter::new(std::fs::File::create(file)?);
+                store.export_car(&cid, file, skip_missing)?
+            };
+            eprintln!("exported {} blocks", count);
+        }
+        SubCommand::Import(ImportCommand { file, pin }) => {
+            let file = std::io::BufReader::new(std::fs::File::open(file)?);
+            for root in store.import_car(file, pin)? {
+                println!("{}", root);
+            }
+        }
+        SubCommand::BitswapStats(BitswapStatsCommand { json }) => {
+            let stats = async_std::task::block_on(store.bitswap_stats())?;
+            let counters = [
+                ("blocks_sent", stats.blocks_sent),
+                ("blocks_received", stats.blocks_received),
+                ("wants_sent", stats.wants_sent),
+                ("wants_received", stats.wants_received),
+                ("duplicate_blocks_received", stats.duplicate_blocks_received),
+                ("bytes_sent", stats.bytes_sent),
+                ("bytes_received", stats.bytes_received),
+            ];
+            print_counters(&counters, json)?;
+        }
+        SubCommand::Wantlist(WantlistCommand { cids, wait }) => {
+            for cid in cids {
+                let store = store.clone();
+                async_std::task::spawn(async move { store.get(cid).await });
+            }
+            if let Some(wait) = wait {
+                std::thread::sleep(Duration::from_secs(wait));
+            }
+            for want in async_std::task::block_on(store.wantlist())? {
+                let providers: Vec<_> = want
+                    .providers
+                    .iter()
+                    .map(|peer_id| peer_id.to_string())
+                    .collect();
+                if providers.is_empty() {
+                    println!("{} {} -", want.cid, want.priority);
+                } else {
+                    println!("{} {} {}", want.cid, want.priority, providers.join(","));
+                }
             }
         }
-        SubCommand::Refs(RefsCommand { cid }) => {
-            let metadata = store.metadata(&cid)?;
-            for cid in metadata.refs {
-                println!("{}", cid.to_string());
+        SubCommand::Watch(WatchCommand { cids }) => {
+            for cid in cids {
+                let store = store.clone();
+                async_std::task::spawn(async move { store.get(cid).await });
             }
+            async_std::task::block_on(async {
+                let mut events = store.watch_storage()?;
+                while let Some(event) = events.next().await {
+                    let time = SystemTime::now().duration_since(UNIX_EPOCH)?;
+                    let time = format!("{}.{:03}", time.as_secs(), time.subsec_millis());
+                    match event {
+                        StorageEvent::Want(cid, priority) => {
+                            println!("{} want {} {}", time, cid, priority)
+                        }
+                        StorageEvent::Cancel(cid) => println!("{} cancel {}", time, cid),
+                        StorageEvent::Provide(cid) => println!("{} provide {}", time, cid),
+                        StorageEvent::Unprovide(cid) => println!("{} unprovide {}", time, cid),
+                        StorageEvent::Root(cid) => println!("{} root {}", time, cid),
+                        StorageEvent::Unroot(cid) => println!("{} unroot {}", time, cid),
+                        StorageEvent::Lagged => println!("{} lagged", time),
+                    }
+                }
+                Ok::<_, Box<dyn std::error::Error>>(())
+            })?;
+        }
+        SubCommand::Stats(StatsCommand { json }) => {
+            let stats = store.stats()?;
+            let bandwidth = store.bandwidth_stats();
+            let counters = [
+                ("blocks", stats.blocks),
+                ("pinned", stats.pinned),
+                ("live", stats.live),
+                ("dead", stats.dead),
+                ("bytes", stats.bytes),
+                ("public", stats.public),
+                ("total_inbound", bandwidth.total_inbound),
+                ("total_outbound", bandwidth.total_outbound),
+                ("inbound_per_sec", bandwidth.inbound_per_sec),
+                ("outbound_per_sec", bandwidth.outbound_per_sec),
+            ];
+            print_counters(&counters, json)?;
+        }
+        SubCommand::Dups(DupsCommand { stats, json }) => {
+            // fingerprint of the bytes independent of the codec and hash of the cid
+            let mut groups: HashMap<Vec<u8>, (u64, Vec<Cid>)> = HashMap::new();
+            for cid in store.blocks() {
+                let cid = cid?;
+                if let Some(data) = store.get_local(&cid)? {
+                    let fingerprint = Multihash::new(SHA2_256, &data)?.digest().to_vec();
+                    let group = groups
+                        .entry(fingerprint)
+                        .or_insert_with(|| (data.len() as u64, vec![]));
+                    group.1.push(cid);
+                }
+            }
+            let mut blocks = 0;
+            let mut bytes = 0;
+            for (size, cids) in groups.values().filter(|(_, cids)| cids.len() > 1) {
+                let redundant = cids.len() as u64 - 1;
+                blocks += redundant;
+                bytes += redundant * size;
+                if !stats {
+                    let cids: Vec<_> = cids.iter().map(|cid| cid.to_string()).collect();
+                    println!("{} {}", size, cids.join(" "));
+                }
+            }
+            if stats {
+                let counters = [("duplicate_blocks", blocks), ("duplicate_bytes", bytes)];
+                print_counters(&counters, json)?;
+            }
+        }
+        SubCommand::Dag(DagCommand {
+            cmd: DagSubCommand::Get(DagGetCommand { path }),
+        }) => {
+            let path = DagPath::new(&path.cid, path.path.as_str());
+            let (cid, ipld) = async_std::task::block_on(resolve_path(&store, &path))?;
+            match ipld {
+                // raw leaves are printed as is
+                Ipld::Bytes(bytes) if cid.codec() == RAW => std::io::stdout().write_all(&bytes)?,
+                ipld => {
+                    let json = DagJsonCodec.encode(&ipld)?;
+                    println!("{}", std::str::from_utf8(&json)?);
+                }
+            }
+        }
+        SubCommand::Dag(DagCommand {
+            cmd: DagSubCommand::Chain(DagChainCommand { cid, field }),
+        }) => {
+            let (_, path) = async_std::task::block_on(store.resolve_chain(&cid, &field))?;
+            for cid in path {
+                println!("{}", cid);
+            }
+        }
+        #[cfg(feature = "gateway")]
+        SubCommand::Gateway(GatewayCommand { listen }) => {
+            async_std::task::block_on(async {
+                let gateway = ipfs_embed::Gateway::bind(store.clone(), listen.as_str()).await?;
+                println!("listening on http://{}", gateway.local_addr()?);
+                gateway.run().await
+            })?;
+        }
+        #[cfg(feature = "metrics")]
+        SubCommand::Metrics(MetricsCommand { listen }) => {
+            async_std::task::block_on(async {
+                let server =
+                    ipfs_embed::MetricsServer::bind(store.clone(), listen.as_str()).await?;
+                println!("listening on http://{}/metrics", server.local_addr()?);
+                server.run().await
+            })?;
+        }
+        SubCommand::Dht(DhtCommand { cmd }) => match cmd {
+            DhtSubCommand::Put(DhtPutCommand { key, value }) => {
+                async_std::task::block_on(store.dht_put(key.as_bytes(), value.into_bytes()))?;
+            }
+            DhtSubCommand::Get(DhtGetCommand { key }) => {
+                for record in async_std::task::block_on(store.dht_get(key.as_bytes()))? {
+                    let value = String::from_utf8_lossy(&record.value);
+                    println!("{} {}", record.publisher, value);
+                }
+            }
+        },
+        SubCommand::Pubsub(PubsubCommand { cmd }) => match cmd {
+            PubsubSubCommand::Pub(PubsubPubCommand {
+                topic,
+                message,
+                wait,
+            }) => {
+                // the message can't be sent until a subscribed peer was found
+                let deadline = Instant::now() + Duration::from_secs(wait);
+                loop {
+                    let data = message.clone().into_bytes();
+                    match async_std::task::block_on(store.publish(&topic, data)) {
+                        Err(Error::Publish(_)) if Instant::now() < deadline => {
+                            std::thread::sleep(Duration::from_millis(100));
+                        }
+                        res => break res?,
+                    }
+                }
+            }
+            PubsubSubCommand::Sub(PubsubSubCommandArgs { topic }) => {
+                async_std::task::block_on(async {
+                    let mut messages = store.subscribe(&topic).await?;
+                    while let Some(message) = messages.next().await {
+                        let source = message.source.map(|peer_id| peer_id.to_string());
+                        println!(
+                            "{} {}",
+                            source.as_deref().unwrap_or("-"),
+                            String::from_utf8_lossy(&message.data)
+                        );
+                    }
+                    Ok::<_, ipfs_embed::Error>(())
+                })?;
+            }
+        },
+        SubCommand::Gc(GcCommand { dry_run }) => {
+            let collected = async_std::task::block_on(store.collect_garbage(dry_run))?;
+            if dry_run {
+                for cid in &collected.blocks {
+                    println!("{}", cid);
+                }
+                println!(
+                    "would free {} blocks ({} bytes)",
+                    collected.blocks.len(),
+                    collected.bytes
+                );
+            } else {
+                println!(
+                    "freed {} blocks ({} bytes)",
+                    collected.blocks.len(),
+                    collected.bytes
+                );
+            }
+        }
+        SubCommand::Repair(_) => {
+            let repaired = store.repair()?;
+            for cid in &repaired.refs {
+                println!("refs {}", cid);
+            }
+            for cid in &repaired.referers {
+                println!("referers {}", cid);
+            }
+            println!(
+                "repaired refs of {} blocks and referers of {} blocks",
+                repaired.refs.len(),
+                repaired.referers.len()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Prints the blocks reachable from `root` breadth first, each once with its
+/// depth.
+fn print_refs_recursive(
+    store: &Store<Multicodec, Multihash>,
+    root: Cid,
+    max_depth: Option<usize>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut visited = HashSet::new();
+    visited.insert(root.clone());
+    let mut queue = VecDeque::new();
+    queue.push_back((root, 0));
+    while let Some((cid, depth)) = queue.pop_front() {
+        if max_depth
+            .map(|max_depth| depth >= max_depth)
+            .unwrap_or(false)
+        {
+            continue;
         }
-        SubCommand::Unpin(UnpinCommand { cid }) => {
-            async_std::task::block_on(store.unpin(&cid))?;
+        for cid in store.metadata(&cid)?.refs {
+            if visited.insert(cid.clone()) {
+                println!("{} {}", depth + 1, cid);
+                queue.push_back((cid, depth + 1));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Resolves a path like `ReadonlyStore::get_path`. Returns the cid of the
+/// block the value was found in along with the value.
+async fn resolve_path(
+    store: &Store<Multicodec, Multihash>,
+    path: &DagPath<'_>,
+) -> Result<(Cid, Ipld), Box<dyn std::error::Error>> {
+    let mut cid = path.root().clone();
+    let mut root = store.get_ipld(&cid).await?;
+    let mut ipld = &root;
+    for segment in path.path().iter() {
+        ipld = ipld.get(segment)?;
+        if let Ipld::Link(link) = ipld {
+            cid = link.clone();
+            root = store.get_ipld(&cid).await?;
+            ipld = &root;
+        }
+    }
+    Ok((cid, ipld.clone()))
+}
+
+/// Asks a yes/no question on stdin. Defaults to no.
+fn confirm(question: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Prints named counters as a table or as a DAG-JSON map.
+/// Stores the data read from `reader` in raw blocks of `chunk_size` bytes,
+/// followed by a list linking to them. The chunks are inserted unpinned in
+/// batches, the list keeps them alive. Returns the cid of the list.
+async fn add<R: Read>(
+    store: &Store<Multicodec, Multihash>,
+    mut reader: R,
+    chunk_size: usize,
+    pin: bool,
+) -> Result<Cid, Box<dyn std::error::Error>> {
+    if chunk_size == 0 || chunk_size > ipfs_embed::MAX_BLOCK_SIZE {
+        return Err(format!("chunk size must be 1 to {}", ipfs_embed::MAX_BLOCK_SIZE).into());
+    }
+    let mut links = vec![];
+    let mut batch = Vec::with_capacity(ADD_BATCH_SIZE);
+    loop {
+        let mut chunk = Vec::with_capacity(chunk_size);
+        (&mut reader)
+            .take(chunk_size as u64)
+            .read_to_end(&mut chunk)?;
+        if chunk.is_empty() {
+            break;
+        }
+        let block = Block::encode(Multicodec::Raw, SHA2_256, &Ipld::Bytes(chunk))?;
+        links.push(Ipld::Link(block.cid.clone()));
+        batch.push(block);
+        if batch.len() == ADD_BATCH_SIZE {
+            store.insert_blocks(&batch, false).await?;
+            batch.clear();
+        }
+    }
+    if !batch.is_empty() {
+        store.insert_blocks(&batch, false).await?;
+    }
+    let root = Block::encode(Multicodec::DagCbor, SHA2_256, &Ipld::List(links))?;
+    store
+        .insert_blocks(std::slice::from_ref(&root), pin)
+        .await?;
+    Ok(root.cid)
+}
+
+fn print_version(json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if json {
+        let protocols = ipfs_embed::PROTOCOLS
+            .iter()
+            .map(|protocol| Ipld::String(protocol.to_string()))
+            .collect();
+        let mut map = BTreeMap::new();
+        map.insert(
+            "Version".to_string(),
+            Ipld::String(ipfs_embed::VERSION.to_string()),
+        );
+        map.insert(
+            "Libp2pVersion".to_string(),
+            Ipld::String(ipfs_embed::LIBP2P_VERSION.to_string()),
+        );
+        map.insert(
+            "ProtocolVersion".to_string(),
+            Ipld::String(ipfs_embed::PROTOCOL_VERSION.to_string()),
+        );
+        map.insert("Protocols".to_string(), Ipld::List(protocols));
+        let json = DagJsonCodec.encode(&Ipld::Map(map))?;
+        println!("{}", std::str::from_utf8(&json)?);
+    } else {
+        println!("ipfs-embed {}", ipfs_embed::VERSION);
+        println!("libp2p {}", ipfs_embed::LIBP2P_VERSION);
+        println!("protocol version {}", ipfs_embed::PROTOCOL_VERSION);
+        for protocol in ipfs_embed::PROTOCOLS {
+            println!("{}", protocol);
+        }
+    }
+    Ok(())
+}
+
+fn print_counters(counters: &[(&str, u64)], json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if json {
+        let map = counters
+            .iter()
+            .map(|(name, value)| (name.to_string(), Ipld::Integer(*value as _)))
+            .collect();
+        let json = DagJsonCodec.encode(&Ipld::Map(map))?;
+        println!("{}", std::str::from_utf8(&json)?);
+    } else {
+        for (name, value) in counters {
+            println!("{:26} {}", name, value);
         }
     }
     Ok(())
@@ -76,3 +730,51 @@ fn print_metadata(cid: &Cid, metadata: &Metadata) {
         cid.to_string()
     );
 }
+
+fn print_metadata_json(cid: &Cid, metadata: &Metadata) -> Result<(), Box<dyn std::error::Error>> {
+    let mut map = BTreeMap::new();
+    map.insert("cid".to_string(), Ipld::String(cid.to_string()));
+    map.insert("pins".to_string(), Ipld::Integer(metadata.pins.into()));
+    map.insert(
+        "referers".to_string(),
+        Ipld::Integer(metadata.referers.into()),
+    );
+    map.insert(
+        "children".to_string(),
+        Ipld::Integer(metadata.refs.len() as _),
+    );
+    map.insert("public".to_string(), Ipld::Bool(metadata.public));
+    let labels = metadata.labels.iter().cloned().map(Ipld::String).collect();
+    map.insert("labels".to_string(), Ipld::List(labels));
+    let json = DagJsonCodec.encode(&Ipld::Map(map))?;
+    println!("{}", std::str::from_utf8(&json)?);
+    Ok(())
+}
+
+/// Prints the node identity in the shape of `ipfs id`.
+fn print_id_json(
+    store: &Store<Multicodec, Multihash>,
+    addresses: &[Multiaddr],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let peer_id = store.peer_id().to_string();
+    let public_key = base64::encode(store.public_key().clone().into_protobuf_encoding());
+    let addresses = addresses
+        .iter()
+        .map(|address| Ipld::String(format!("{}/p2p/{}", address, peer_id)))
+        .collect();
+    let mut map = BTreeMap::new();
+    map.insert("ID".to_string(), Ipld::String(peer_id));
+    map.insert("PublicKey".to_string(), Ipld::String(public_key));
+    map.insert("Addresses".to_string(), Ipld::List(addresses));
+    map.insert(
+        "AgentVersion".to_string(),
+        Ipld::String(store.agent_version().to_string()),
+    );
+    map.insert(
+        "ProtocolVersion".to_string(),
+        Ipld::String(ipfs_embed::PROTOCOL_VERSION.to_string()),
+    );
+    let json = DagJsonCodec.encode(&Ipld::Map(map))?;
+    println!("{}", std::str::from_utf8(&json)?);
+    Ok(())
+}
@@ -0,0 +1,293 @@
+use crate::storage::{verify, BlockNotLocal, Storage};
+use libipld::block::Block;
+use libipld::cbor::DagCborCodec;
+use libipld::cid::Cid;
+use libipld::codec::Codec;
+use libipld::error::Result;
+use libipld::ipld::Ipld;
+use libipld::multihash::MultihashDigest;
+use std::collections::BTreeMap;
+use std::io::{ErrorKind, Read, Write};
+use thiserror::Error;
+
+/// Number of blocks inserted per transaction on import.
+const IMPORT_BATCH_SIZE: usize = 256;
+
+/// The CAR file is malformed.
+#[derive(Debug, Error)]
+#[error("Invalid CAR file: {0}.")]
+pub struct InvalidCar(pub &'static str);
+
+/// Reads a varint. Returns `None` on EOF before the first byte.
+fn read_varint<R: Read>(reader: &mut R) -> Result<Option<u64>> {
+    let mut n = 0u64;
+    for i in 0..10 {
+        let mut byte = [0u8];
+        if let Err(err) = reader.read_exact(&mut byte) {
+            if i == 0 && err.kind() == ErrorKind::UnexpectedEof {
+                return Ok(None);
+            }
+            return Err(err.into());
+        }
+        n |= ((byte[0] & 0x7f) as u64) << (i * 7);
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(n));
+        }
+    }
+    Err(InvalidCar("varint overflow").into())
+}
+
+fn write_varint<W: Write>(writer: &mut W, mut n: u64) -> Result<()> {
+    let mut buf = [0u8; 10];
+    let mut i = 0;
+    loop {
+        buf[i] = n as u8 & 0x7f;
+        n >>= 7;
+        if n == 0 {
+            break;
+        }
+        buf[i] |= 0x80;
+        i += 1;
+    }
+    writer.write_all(&buf[..=i])?;
+    Ok(())
+}
+
+/// Writes a CARv1 file.
+pub struct CarWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> CarWriter<W> {
+    /// Writes the header with the root cids.
+    pub fn new(mut writer: W, roots: &[Cid]) -> Result<Self> {
+        let mut header = BTreeMap::new();
+        let roots = roots.iter().cloned().map(Ipld::Link).collect();
+        header.insert("roots".to_string(), Ipld::List(roots));
+        header.insert("version".to_string(), Ipld::Integer(1));
+        let header = DagCborCodec.encode(&Ipld::Map(header))?;
+        write_varint(&mut writer, header.len() as u64)?;
+        writer.write_all(&header)?;
+        Ok(Self { writer })
+    }
+
+    /// Writes a block.
+    pub fn write(&mut self, cid: &Cid, data: &[u8]) -> Result<()> {
+        let cid = cid.to_bytes();
+        write_varint(&mut self.writer, (cid.len() + data.len()) as u64)?;
+        self.writer.write_all(&cid)?;
+        self.writer.write_all(data)?;
+        Ok(())
+    }
+
+    /// Flushes and returns the underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads a CARv1 file.
+pub struct CarReader<R: Read> {
+    reader: R,
+    roots: Vec<Cid>,
+}
+
+impl<R: Read> CarReader<R> {
+    /// Reads the header with the root cids.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let len = read_varint(&mut reader)?.ok_or(InvalidCar("missing header"))?;
+        let mut header = vec![0; len as usize];
+        reader.read_exact(&mut header)?;
+        let header = match DagCborCodec.decode_ipld(&header)? {
+            Ipld::Map(header) => header,
+            _ => return Err(InvalidCar("header is not a map").into()),
+        };
+        if header.get("version") != Some(&Ipld::Integer(1)) {
+            return Err(InvalidCar("unsupported version").into());
+        }
+        let roots = match header.get("roots") {
+            Some(Ipld::List(roots)) => roots
+                .iter()
+                .map(|root| match root {
+                    Ipld::Link(cid) => Ok(cid.clone()),
+                    _ => Err(InvalidCar("root is not a link").into()),
+                })
+                .collect::<Result<_>>()?,
+            _ => return Err(InvalidCar("missing roots").into()),
+        };
+        Ok(Self { reader, roots })
+    }
+
+    /// Returns the root cids from the header.
+    pub fn roots(&self) -> &[Cid] {
+        &self.roots
+    }
+
+    /// Reads the next block. Returns `None` at the end of the file.
+    pub fn next_block(&mut self) -> Result<Option<(Cid, Vec<u8>)>> {
+        let len = match read_varint(&mut self.reader)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        let mut section = vec![0; len as usize];
+        self.reader.read_exact(&mut section)?;
+        let mut data = &section[..];
+        let cid = Cid::read_bytes(&mut data)?;
+        Ok(Some((cid, data.to_vec())))
+    }
+}
+
+/// Reads the blocks of a CAR file into the store. Each block is checked
+/// against the digest of its cid and inserted unpinned, in batches of
+/// `IMPORT_BATCH_SIZE` blocks. With `pin` the roots are pinned. Returns the
+/// roots.
+pub(crate) fn import<C: Codec, M: MultihashDigest, R: Read>(
+    storage: &Storage,
+    reader: R,
+    pin: bool,
+) -> Result<Vec<Cid>> {
+    let mut car = CarReader::new(reader)?;
+    let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+    while let Some((cid, data)) = car.next_block()? {
+        verify::<M>(&cid, &data)?;
+        batch.push(Block::<C, M>::new(cid, data.into_boxed_slice()));
+        if batch.len() == IMPORT_BATCH_SIZE {
+            storage.insert_blocks(&batch, false)?;
+            batch.clear();
+        }
+    }
+    if !batch.is_empty() {
+        storage.insert_blocks(&batch, false)?;
+    }
+    if pin {
+        for root in car.roots() {
+            storage.pin(root)?;
+        }
+    }
+    Ok(car.roots)
+}
+
+/// Writes the DAG rooted at `root` to a CAR file. Each block is written as
+/// soon as it is read, so memory use doesn't grow with the size of the
+/// blocks. Blocks that aren't stored locally fail with `BlockNotLocal`, or
+/// are skipped with `skip_missing`. Returns the number of blocks written.
+pub(crate) fn export<W: Write>(
+    storage: &Storage,
+    root: &Cid,
+    writer: W,
+    skip_missing: bool,
+) -> Result<usize> {
+    let mut car = CarWriter::new(writer, std::slice::from_ref(root))?;
+    let mut count = 0;
+    for res in storage.walk(root) {
+        let (cid, data) = res?;
+        let data = match data {
+            Some(data) => data,
+            None if skip_missing => {
+                log::warn!("skipping missing block {}", cid.to_string());
+                continue;
+            }
+            None => return Err(BlockNotLocal(cid.to_string()).into()),
+        };
+        car.write(&cid, &data)?;
+        count += 1;
+    }
+    car.finish()?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::cid::RAW;
+    use libipld::codec_impl::Multicodec;
+    use libipld::error::InvalidMultihash;
+    use libipld::multihash::{Multihash, SHA2_256};
+    use tempdir::TempDir;
+
+    fn create_cid(data: &[u8]) -> Cid {
+        let digest = Multihash::new(SHA2_256, data).unwrap().to_raw().unwrap();
+        Cid::new_v1(RAW, digest)
+    }
+
+    fn create_storage() -> (Storage, TempDir) {
+        let tmp = TempDir::new("").unwrap();
+        let db = sled::open(tmp.path()).unwrap();
+        let storage = Storage::new(db.open_tree("ipfs_tree").unwrap(), None).unwrap();
+        (storage, tmp)
+    }
+
+    #[test]
+    fn test_varint() {
+        let mut buf = vec![];
+        write_varint(&mut buf, 1).unwrap();
+        write_varint(&mut buf, 127).unwrap();
+        write_varint(&mut buf, 128).unwrap();
+        write_varint(&mut buf, 300).unwrap();
+        assert_eq!(buf, vec![1, 127, 0x80, 1, 0xac, 2]);
+    }
+
+    #[test]
+    fn test_car_writer() {
+        let data = b"hello world";
+        let cid = create_cid(data);
+        let mut car = CarWriter::new(vec![], std::slice::from_ref(&cid)).unwrap();
+        car.write(&cid, data).unwrap();
+        let buf = car.finish().unwrap();
+
+        let header_len = buf[0] as usize;
+        let header = DagCborCodec.decode_ipld(&buf[1..=header_len]).unwrap();
+        let mut expected = BTreeMap::new();
+        expected.insert(
+            "roots".to_string(),
+            Ipld::List(vec![Ipld::Link(cid.clone())]),
+        );
+        expected.insert("version".to_string(), Ipld::Integer(1));
+        assert_eq!(header, Ipld::Map(expected));
+
+        let block = &buf[(header_len + 1)..];
+        let cid_bytes = cid.to_bytes();
+        assert_eq!(block[0] as usize, cid_bytes.len() + data.len());
+        assert_eq!(&block[1..=cid_bytes.len()], &cid_bytes[..]);
+        assert_eq!(&block[(cid_bytes.len() + 1)..], &data[..]);
+    }
+
+    #[test]
+    fn test_read_varint() {
+        let mut buf = &[1, 127, 0x80, 1, 0xac, 2][..];
+        assert_eq!(read_varint(&mut buf).unwrap(), Some(1));
+        assert_eq!(read_varint(&mut buf).unwrap(), Some(127));
+        assert_eq!(read_varint(&mut buf).unwrap(), Some(128));
+        assert_eq!(read_varint(&mut buf).unwrap(), Some(300));
+        assert_eq!(read_varint(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn test_import() {
+        let (storage, _tmp) = create_storage();
+        let data = b"hello world";
+        let cid = create_cid(data);
+        let mut car = CarWriter::new(vec![], std::slice::from_ref(&cid)).unwrap();
+        car.write(&cid, data).unwrap();
+        let buf = car.finish().unwrap();
+
+        let roots = import::<Multicodec, Multihash, _>(&storage, &buf[..], true).unwrap();
+        assert_eq!(roots, vec![cid.clone()]);
+        assert_eq!(storage.get_local(&cid).unwrap().unwrap(), &data[..]);
+        assert_eq!(storage.metadata(&cid).unwrap().pins, 1);
+    }
+
+    #[test]
+    fn test_import_invalid_block() {
+        let (storage, _tmp) = create_storage();
+        let cid = create_cid(b"hello world");
+        let mut car = CarWriter::new(vec![], std::slice::from_ref(&cid)).unwrap();
+        car.write(&cid, b"hello world!").unwrap();
+        let buf = car.finish().unwrap();
+
+        let err = import::<Multicodec, Multihash, _>(&storage, &buf[..], false).unwrap_err();
+        assert!(err.downcast_ref::<InvalidMultihash>().is_some());
+        assert!(storage.get_local(&cid).unwrap().is_none());
+    }
+}
@@ -1,14 +1,86 @@
-use crate::network::NetworkConfig;
-use sled::{Error, Tree};
+use crate::error::Result;
+use crate::network::{load_or_create_keypair, NetworkConfig};
+use libipld::multihash::SHA2_256;
+use sled::{Db, Tree};
+use std::io::ErrorKind;
 use std::path::Path;
 use std::time::Duration;
+use thiserror::Error;
 
 pub const TREE: &str = "ipfs_tree";
 
+/// File in the database directory with the node key.
+pub const KEY_FILE: &str = "key";
+
+/// The database is already open, usually by another process.
+#[derive(Debug, Error)]
+#[error("Database {0} is already open by another process.")]
+pub struct DatabaseLocked(pub String);
+
+/// Opens the database at `path` with the other options of `config`.
+///
+/// Sled locks the database while it is open and fails with an io error if it
+/// can't, which is returned as `DatabaseLocked`.
+pub fn open_db<T: AsRef<Path>>(path: T, config: sled::Config) -> Result<Db> {
+    let path = path.as_ref();
+    config.path(path).open().map_err(|err| match err {
+        // sled only reports the lock failure in the message
+        sled::Error::Io(err)
+            if err.kind() == ErrorKind::Other
+                && err.to_string().starts_with("could not acquire lock") =>
+        {
+            DatabaseLocked(path.display().to_string()).into()
+        }
+        err => err.into(),
+    })
+}
+
 pub struct Config {
     pub tree: Tree,
     pub timeout: Duration,
     pub network: NetworkConfig,
+    /// Size limit of the stored blocks. When it is exceeded the least recently
+    /// accessed blocks that aren't pinned or referenced are evicted.
+    pub max_storage_bytes: Option<u64>,
+    /// Rehash blocks read from the store and fail on a mismatch with the
+    /// cid. Off by default as it slows down reads.
+    pub verify_blocks: bool,
+    /// Multihash code of the cids of blocks created with `Store::put`, for
+    /// example `SHA2_512` or `BLAKE2B_256`. Defaults to `SHA2_256`.
+    pub hash_code: u64,
+    /// Size limit of a single block in bytes. Larger blocks are rejected on
+    /// insert, dropped when received from a peer and never sent. Defaults to
+    /// `MAX_BLOCK_SIZE`. Bitswap messages are limited to 512KiB regardless.
+    pub max_block_size: usize,
+    /// Flushes the storage to disk after this many blocks were inserted.
+    /// Otherwise sled flushes in the background, every 500ms by default or
+    /// as set with `sled::Config::flush_every_ms` when opening the database,
+    /// and `Store::flush` flushes explicitly. Writes since the last flush are
+    /// lost on a crash, so flushing more often is more durable but slows
+    /// down bulk inserts like CAR imports. The check happens after each
+    /// batch, so a batch is never split across flushes.
+    pub flush_every: Option<u64>,
+    /// Uses only the local blocks. Gets of blocks that aren't stored fail
+    /// right away with `BlockNotLocal` instead of asking the network, and
+    /// blocks aren't provided. The network still runs, so peers can connect
+    /// and fetch our blocks. Useful for tests.
+    pub offline: bool,
+    /// Size of the blocks that aren't pinned or referenced above which they
+    /// are removed in the background. Blocks fetched with `get` without
+    /// pinning them are removed too. Disabled if `None`.
+    pub gc_threshold: Option<u64>,
+    /// Interval at which the size of the dead blocks is checked against
+    /// `gc_threshold`. Checking iterates over all blocks.
+    pub gc_interval: Duration,
+    /// Compresses inserted blocks with deflate. Blocks that don't get smaller
+    /// are stored as is, and stores with uncompressed blocks can still be
+    /// read. Cids address the uncompressed data, while sizes like
+    /// `max_storage_bytes` and `Stats::bytes` count the stored bytes.
+    #[cfg(feature = "compression")]
+    pub compress_blocks: bool,
+    /// Deflate level from 0 to 9. Defaults to 6.
+    #[cfg(feature = "compression")]
+    pub compression_level: u32,
 }
 
 impl Config {
@@ -18,22 +90,53 @@ impl Config {
             tree,
             timeout: Duration::from_millis(20000),
             network,
+            max_storage_bytes: None,
+            verify_blocks: false,
+            hash_code: SHA2_256,
+            max_block_size: crate::MAX_BLOCK_SIZE,
+            flush_every: None,
+            offline: false,
+            gc_threshold: None,
+            gc_interval: Duration::from_secs(60),
+            #[cfg(feature = "compression")]
+            compress_blocks: false,
+            #[cfg(feature = "compression")]
+            compression_level: 6,
         }
     }
 
-    /// Creates a default configuration.
-    pub fn from_path<T: AsRef<Path>>(path: T) -> Result<Self, Error> {
-        let db = sled::open(path)?;
+    /// Creates a default configuration. The node key is loaded from
+    /// `KEY_FILE` in the database directory, or created on first use.
+    pub fn from_path<T: AsRef<Path>>(path: T) -> Result<Self> {
+        let db = open_db(&path, Default::default())?;
         let tree = db.open_tree(TREE)?;
-        let network = NetworkConfig::new();
+        let mut network = NetworkConfig::new();
+        network.node_key = load_or_create_keypair(path.as_ref().join(KEY_FILE))?;
         Ok(Self::new(tree, network))
     }
 
-    /// Creates a default local network configuration.
-    pub fn from_path_local<T: AsRef<Path>>(path: T) -> Result<Self, Error> {
-        let db = sled::open(path)?;
+    /// Creates a default local network configuration. The node key is
+    /// persisted like with `from_path`.
+    pub fn from_path_local<T: AsRef<Path>>(path: T) -> Result<Self> {
+        let db = open_db(&path, Default::default())?;
         let tree = db.open_tree(TREE)?;
-        let network = NetworkConfig::new_local();
+        let mut network = NetworkConfig::new_local();
+        network.node_key = load_or_create_keypair(path.as_ref().join(KEY_FILE))?;
         Ok(Self::new(tree, network))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_database_locked() {
+        let tmp = TempDir::new("").unwrap();
+        let _config = Config::from_path_local(tmp.path()).unwrap();
+        let err = Config::from_path_local(tmp.path()).err().unwrap();
+        assert!(matches!(err, Error::DatabaseLocked(_)));
+    }
+}
@@ -0,0 +1,160 @@
+use crate::car::InvalidCar;
+use crate::config::DatabaseLocked;
+use crate::network::{ConnectionFailed, PubsubDisabled, PubsubPublishError, RecordNotFound};
+use crate::storage::{BlockNotLocal, WantTimeout};
+use crate::store::NetworkStopped;
+use libipld::error::{
+    BlockNotFound, BlockTooLarge, Error as IpldError, InvalidMultihash, TypeError,
+    UnsupportedCodec, UnsupportedMultihash,
+};
+use libp2p::TransportError;
+use thiserror::Error;
+
+/// Result type of the store.
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Error returned by the store.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The block couldn't be retrieved from the network.
+    #[error(transparent)]
+    BlockNotFound(#[from] BlockNotFound),
+    /// Retrieving the block from the network took longer than the timeout.
+    #[error("Timed out retrieving block {0}.")]
+    Timeout(String),
+    /// The block isn't stored locally.
+    #[error(transparent)]
+    BlockNotLocal(#[from] BlockNotLocal),
+    /// The block doesn't match its cid or can't be decoded with its codec.
+    #[error(transparent)]
+    Codec(IpldError),
+    /// The CAR file is malformed.
+    #[error(transparent)]
+    InvalidCar(#[from] InvalidCar),
+    /// Dialing a peer failed.
+    #[error(transparent)]
+    ConnectionFailed(#[from] ConnectionFailed),
+    /// Pubsub isn't enabled in the network config.
+    #[error(transparent)]
+    PubsubDisabled(#[from] PubsubDisabled),
+    /// Publishing a pubsub message failed, for example because no peer is
+    /// subscribed to the topic.
+    #[error(transparent)]
+    Publish(#[from] PubsubPublishError),
+    /// No record with a valid signature was found in the DHT.
+    #[error(transparent)]
+    RecordNotFound(#[from] RecordNotFound),
+    /// The network task isn't running anymore.
+    #[error(transparent)]
+    NetworkStopped(#[from] NetworkStopped),
+    /// Listening on an address failed.
+    #[error(transparent)]
+    Transport(#[from] TransportError<std::io::Error>),
+    /// The database is already open by another process.
+    #[error(transparent)]
+    DatabaseLocked(#[from] DatabaseLocked),
+    #[error(transparent)]
+    Sled(#[from] sled::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Other(IpldError),
+}
+
+impl Error {
+    /// Converts to the error type of the `libipld` store traits. Timeouts are
+    /// reported as `BlockNotFound` like in other `libipld` stores.
+    pub(crate) fn into_ipld_error(self) -> IpldError {
+        match self {
+            Error::BlockNotFound(err) => err.into(),
+            Error::Timeout(cid) => BlockNotFound(cid).into(),
+            Error::BlockNotLocal(err) => err.into(),
+            Error::Codec(err) => err,
+            Error::InvalidCar(err) => err.into(),
+            Error::ConnectionFailed(err) => err.into(),
+            Error::PubsubDisabled(err) => err.into(),
+            Error::Publish(err) => err.into(),
+            Error::RecordNotFound(err) => err.into(),
+            Error::NetworkStopped(err) => err.into(),
+            Error::Transport(err) => err.into(),
+            Error::DatabaseLocked(err) => err.into(),
+            Error::Sled(err) => err.into(),
+            Error::Io(err) => err.into(),
+            Error::Other(err) => err,
+        }
+    }
+}
+
+impl From<WantTimeout> for Error {
+    fn from(err: WantTimeout) -> Self {
+        Error::Timeout(err.0)
+    }
+}
+
+macro_rules! downcast {
+    ($err:ident, $($ty:ty),*) => {
+        $(
+            let $err = match $err.downcast::<$ty>() {
+                Ok(err) => return err.into(),
+                Err(err) => err,
+            };
+        )*
+    };
+}
+
+impl From<IpldError> for Error {
+    fn from(err: IpldError) -> Self {
+        downcast!(
+            err,
+            Error,
+            BlockNotFound,
+            BlockNotLocal,
+            WantTimeout,
+            InvalidCar,
+            ConnectionFailed,
+            PubsubDisabled,
+            PubsubPublishError,
+            RecordNotFound,
+            NetworkStopped,
+            TransportError<std::io::Error>,
+            DatabaseLocked,
+            sled::Error,
+            std::io::Error
+        );
+        if err.is::<InvalidMultihash>()
+            || err.is::<UnsupportedCodec>()
+            || err.is::<UnsupportedMultihash>()
+            || err.is::<BlockTooLarge>()
+            || err.is::<TypeError>()
+        {
+            Error::Codec(err)
+        } else {
+            Error::Other(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_ipld_error() {
+        let err: IpldError = BlockNotLocal("cid".into()).into();
+        assert!(matches!(Error::from(err), Error::BlockNotLocal(_)));
+        let err: IpldError = UnsupportedCodec(0).into();
+        assert!(matches!(Error::from(err), Error::Codec(_)));
+        let err: IpldError = Error::Timeout("cid".into()).into();
+        assert!(matches!(Error::from(err), Error::Timeout(_)));
+        let err = IpldError::msg("other");
+        assert!(matches!(Error::from(err), Error::Other(_)));
+    }
+
+    #[test]
+    fn test_into_ipld_error() {
+        let err = Error::Timeout("cid".into()).into_ipld_error();
+        assert!(err.downcast_ref::<BlockNotFound>().is_some());
+        let err = Error::from(BlockNotLocal("cid".into())).into_ipld_error();
+        assert!(err.downcast_ref::<BlockNotLocal>().is_some());
+    }
+}
@@ -0,0 +1,121 @@
+use crate::error::{Error, Result};
+use crate::http::{self, Response};
+use crate::storage::DEFAULT_PRIORITY;
+use crate::store::Store;
+use async_std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use libipld::cid::{Cid, DAG_CBOR, DAG_JSON};
+use libipld::codec::Codec;
+use libipld::multihash::MultihashDigest;
+
+fn content_type(cid: &Cid) -> &'static str {
+    match cid.codec() {
+        DAG_CBOR => "application/cbor",
+        DAG_JSON => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+/// A read only HTTP gateway serving the blocks of a store at
+/// `GET /ipfs/<cid>`.
+///
+/// Blocks that aren't stored locally are fetched from the network. The raw
+/// block bytes are returned, paths into DAGs aren't resolved. Each
+/// connection serves a single request.
+pub struct Gateway<C: Codec, M: MultihashDigest> {
+    store: Store<C, M>,
+    listener: TcpListener,
+}
+
+impl<C: Codec, M: MultihashDigest> Gateway<C, M> {
+    /// Binds the gateway to an address.
+    pub async fn bind<A: ToSocketAddrs>(store: Store<C, M>, addr: A) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Self { store, listener })
+    }
+
+    /// Returns the address the gateway listens on.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Serves requests until accepting a connection fails.
+    pub async fn run(self) -> Result<()> {
+        let store = &self.store;
+        http::serve(&self.listener, |path| respond(store, path)).await
+    }
+}
+
+async fn respond<C: Codec, M: MultihashDigest>(store: &Store<C, M>, path: String) -> Response {
+    let cid = match path.strip_prefix("/ipfs/") {
+        Some(cid) => cid.trim_end_matches('/'),
+        None => return Response::error("404 Not Found"),
+    };
+    let cid: Cid = match cid.parse() {
+        Ok(cid) => cid,
+        Err(_) => return Response::error("400 Bad Request"),
+    };
+    match store.get_with_priority(&cid, DEFAULT_PRIORITY).await {
+        Ok(block) => Response {
+            status: "200 OK",
+            content_type: content_type(&cid),
+            body: block.data.to_vec(),
+        },
+        Err(Error::BlockNotFound(_)) => Response::error("404 Not Found"),
+        Err(Error::Timeout(_)) => Response::error("504 Gateway Timeout"),
+        Err(err) => {
+            log::warn!("gateway failed to get {}: {}", cid.to_string(), err);
+            Response::error("500 Internal Server Error")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use async_std::net::TcpStream;
+    use async_std::prelude::*;
+    use libipld::block::Block;
+    use libipld::codec_impl::Multicodec;
+    use libipld::multihash::{Multihash, SHA2_256};
+    use libipld::raw::RawCodec;
+    use libipld::store::Store as _;
+    use tempdir::TempDir;
+
+    async fn request(addr: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await.unwrap();
+        String::from_utf8(response).unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_gateway() {
+        let tmp = TempDir::new("").unwrap();
+        let mut config = Config::from_path_local(tmp.path()).unwrap();
+        config.network.enable_mdns = false;
+        let store = Store::<Multicodec, Multihash>::new(config).unwrap();
+        let block = Block::encode(RawCodec, SHA2_256, &b"hello gateway"[..]).unwrap();
+        store.insert(&block).await.unwrap();
+
+        let gateway = Gateway::bind(store, "127.0.0.1:0").await.unwrap();
+        let addr = gateway.local_addr().unwrap();
+        async_std::task::spawn(gateway.run());
+
+        let get = format!(
+            "GET /ipfs/{} HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            block.cid
+        );
+        let response = request(addr, &get).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: application/octet-stream\r\n"));
+        assert!(response.ends_with("\r\n\r\nhello gateway"));
+
+        let response = request(addr, "GET /ipfs/invalid HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        let response = request(addr, "GET /ipns/name HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
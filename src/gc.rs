@@ -1,20 +1,76 @@
 use crate::storage::{GcEvent, GcSubscriber, Storage};
 use async_std::stream::Stream;
+use async_std::task;
 use core::future::Future;
 use core::pin::Pin;
 use core::task::{Context, Poll};
+use core::time::Duration;
+use libipld::error::Result;
+
+/// Number of blocks removed at a time by the background collection, so it
+/// doesn't block the executor for long.
+const GC_BATCH_SIZE: usize = 256;
 
 pub struct GarbageCollector {
     storage: Storage,
     subscriber: GcSubscriber,
+    /// Size of the dead blocks above which they are collected.
+    threshold: Option<u64>,
+    interval: Duration,
+    timer: Pin<Box<dyn Future<Output = ()> + Send>>,
+    /// Set while dead blocks are collected.
+    collecting: bool,
 }
 
 impl GarbageCollector {
-    pub fn new(storage: Storage) -> Self {
-        let subscriber = storage.watch_gc();
-        Self {
+    pub fn new(storage: Storage, threshold: Option<u64>, interval: Duration) -> Result<Self> {
+        let subscriber = storage.watch_gc()?;
+        Ok(Self {
             storage,
             subscriber,
+            threshold,
+            interval,
+            timer: Box::pin(task::sleep(interval)),
+            collecting: false,
+        })
+    }
+
+    /// Checks the size of the dead blocks when the timer fires and starts
+    /// collecting while it exceeds the threshold.
+    fn poll_threshold(&mut self, ctx: &mut Context, threshold: u64) {
+        while self.timer.as_mut().poll(ctx).is_ready() {
+            self.timer = Box::pin(task::sleep(self.interval));
+            match self.storage.dead_bytes() {
+                Ok(bytes) if bytes > threshold => {
+                    log::debug!("collecting {} bytes of dead blocks", bytes);
+                    self.collecting = true;
+                }
+                Ok(_) => {}
+                Err(e) => log::error!("gc error: {}", e),
+            }
+        }
+    }
+
+    /// Removes a batch of dead blocks while collecting.
+    fn poll_collect(&mut self, ctx: &mut Context) {
+        if !self.collecting {
+            return;
+        }
+        if let Ok(0) = self.storage.dead_bytes() {
+            self.collecting = false;
+            return;
+        }
+        match self.storage.collect_dead(GC_BATCH_SIZE) {
+            Ok(collected) if collected.blocks.is_empty() => self.collecting = false,
+            Ok(collected) => {
+                log::trace!("gc removed {} blocks", collected.blocks.len());
+                // the next batch is removed on the next poll
+                ctx.waker().wake_by_ref();
+            }
+            Err(e) => {
+                log::error!("gc error: {}", e);
+                self.collecting = false;
+            }
         }
     }
 }
@@ -30,10 +86,16 @@ impl Future for GarbageCollector {
                         log::error!("gc error: {}", e);
                     }
                 }
+                // the unpinned blocks aren't known, so all dead blocks are removed
+                Poll::Ready(Some(GcEvent::Lagged)) => self.collecting = true,
                 Poll::Ready(None) => return Poll::Ready(()),
                 Poll::Pending => break,
             }
         }
+        if let Some(threshold) = self.threshold {
+            self.poll_threshold(ctx, threshold);
+        }
+        self.poll_collect(ctx);
         Poll::Pending
     }
 }
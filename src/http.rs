@@ -0,0 +1,103 @@
+use crate::error::Result;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use core::future::Future;
+use futures::stream::StreamExt;
+
+/// Maximum size of the request line and headers.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Maximum number of request headers.
+const MAX_HEADERS: usize = 32;
+
+/// A response to a `GET` request.
+pub struct Response {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn error(status: &'static str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            body: format!("{}\n", status).into_bytes(),
+        }
+    }
+}
+
+/// Answers `GET` and `HEAD` requests with the response `respond` returns for
+/// the path, until accepting a connection fails. Each connection serves a
+/// single request.
+pub async fn serve<F, R>(listener: &TcpListener, respond: F) -> Result<()>
+where
+    F: Fn(String) -> R,
+    R: Future<Output = Response>,
+{
+    let respond = &respond;
+    listener
+        .incoming()
+        .for_each_concurrent(None, |stream| async move {
+            match stream {
+                Ok(stream) => {
+                    if let Err(err) = serve_one(stream, respond).await {
+                        log::debug!("http connection failed: {}", err);
+                    }
+                }
+                Err(err) => log::warn!("http listener failed to accept: {}", err),
+            }
+        })
+        .await;
+    Ok(())
+}
+
+/// Reads a request and writes the response.
+async fn serve_one<F, R>(mut stream: TcpStream, respond: &F) -> Result<()>
+where
+    F: Fn(String) -> R,
+    R: Future<Output = Response>,
+{
+    let mut buf = vec![0; MAX_REQUEST_SIZE];
+    let mut len = 0;
+    let request = loop {
+        let n = stream.read(&mut buf[len..]).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        len += n;
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+        match request.parse(&buf[..len]) {
+            Ok(httparse::Status::Complete(_)) => {
+                let method = request.method.unwrap_or_default().to_string();
+                let path = request.path.unwrap_or_default().to_string();
+                break Ok((method, path));
+            }
+            Ok(httparse::Status::Partial) if len < buf.len() => continue,
+            Ok(httparse::Status::Partial) => break Err("431 Request Header Fields Too Large"),
+            Err(_) => break Err("400 Bad Request"),
+        }
+    };
+    let (response, head) = match request {
+        Ok((method, path)) => match method.as_str() {
+            "GET" => (respond(path).await, false),
+            "HEAD" => (respond(path).await, true),
+            _ => (Response::error("405 Method Not Allowed"), false),
+        },
+        Err(status) => (Response::error(status), false),
+    };
+    log::debug!("http response {}", response.status);
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    if !head {
+        stream.write_all(&response.body).await?;
+    }
+    stream.flush().await?;
+    Ok(())
+}
@@ -7,20 +7,70 @@
 //! let store = Store::<Multicodec, Multihash>::new(config)?;
 //! # Ok(()) }
 //! ```
+//!
+//! The store runs the network in a background task, so blocks can be used
+//! right away. `Ipfs` is a store with the default codecs and hashes.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! use ipfs_embed::{Config, Ipfs, WritableStore};
+//! use libipld::cbor::DagCborCodec;
+//! use libipld::ipld;
+//! let store = Ipfs::new(Config::from_path("/tmp/db")?)?;
+//! let block = store.put(DagCborCodec, &ipld!({ "hello": "world" })).await?;
+//! store.pin(&block.cid).await?;
+//! store.provide(&block.cid).await?;
+//! let block = store.get_with_priority(&block.cid, ipfs_embed::DEFAULT_PRIORITY).await?;
+//! store.unpin(&block.cid).await?;
+//! store.shutdown().await?;
+//! # Ok(()) }
+//! ```
+//!
+//! Blocks are decoded with the codec `C` of the store, which is how the links
+//! between blocks are found when pinning, exporting and fetching graphs. To
+//! follow links in other formats, implement `libipld::codec::Codec` for an
+//! enum of the default and custom codecs and use it instead of `Multicodec`.
+mod car;
 mod config;
+mod error;
+#[cfg(feature = "gateway")]
+mod gateway;
 mod gc;
+#[cfg(any(feature = "gateway", feature = "metrics"))]
+mod http;
+#[cfg(feature = "metrics")]
+mod metrics;
 mod network;
 mod storage;
 mod store;
 
-pub use config::{Config, TREE};
+pub use car::{CarReader, CarWriter, InvalidCar};
+pub use config::{open_db, Config, DatabaseLocked, KEY_FILE, TREE};
+pub use error::{Error, Result};
+#[cfg(feature = "gateway")]
+pub use gateway::Gateway;
 pub use libipld::store::{AliasStore, ReadonlyStore, Store as WritableStore};
 pub use libipld::{Cid, Multicodec, Multihash};
 pub use libp2p::core::{Multiaddr, PeerId};
-pub use network::NetworkConfig;
+pub use libp2p::pnet::PreSharedKey;
+pub use libp2p_bitswap::Priority;
+#[cfg(feature = "metrics")]
+pub use metrics::{prometheus_metrics, MetricsServer};
+pub use network::{
+    ipfs_boot_nodes, load_or_create_keypair, load_psk, BandwidthStats, BitswapStats,
+    ConnectionEvent, ConnectionFailed, DhtRecord, InvalidRecord, Multiplexer, NetworkConfig,
+    PeerInfo, PubsubDisabled, PubsubMessage, PubsubPublishError, RecordNotFound, Security,
+    WantInfo, WantPolicy, LIBP2P_VERSION, PROTOCOLS, PROTOCOL_VERSION,
+};
 pub use sled::IVec;
-pub use storage::Metadata;
-pub use store::Store;
+pub use storage::{
+    BlockNotLocal, Collected, Metadata, NetworkEvent as StorageEvent, Page, Repaired, Stats,
+    UnsupportedFormat, DEFAULT_PRIORITY,
+};
+pub use store::{Ipfs, NetworkStopped, Store};
+
+/// Version of this crate.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 /// The maximum block size is 1MiB.
 pub const MAX_BLOCK_SIZE: usize = 1_048_576;
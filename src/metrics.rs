@@ -0,0 +1,161 @@
+use crate::error::Result;
+use crate::http::{self, Response};
+use crate::store::Store;
+use async_std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use core::fmt::Write;
+use libipld::codec::Codec;
+use libipld::multihash::MultihashDigest;
+
+/// Appends a metric with its help and type lines.
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    writeln!(out, "# HELP ipfs_embed_{} {}", name, help).ok();
+    writeln!(out, "# TYPE ipfs_embed_{} {}", name, kind).ok();
+    writeln!(out, "ipfs_embed_{} {}", name, value).ok();
+}
+
+/// Renders the bitswap and bandwidth counters, the number of connected
+/// peers and the size of the store in the Prometheus text format. The store
+/// size is counted by iterating over all blocks.
+pub async fn prometheus_metrics<C: Codec, M: MultihashDigest>(
+    store: &Store<C, M>,
+) -> Result<String> {
+    let bitswap = store.bitswap_stats().await?;
+    let bandwidth = store.bandwidth_stats();
+    let peers = store.peers().await?.len() as u64;
+    let stats = store.stats()?;
+    let mut out = String::new();
+    let counters = [
+        (
+            "bitswap_blocks_sent_total",
+            "Blocks sent.",
+            bitswap.blocks_sent,
+        ),
+        (
+            "bitswap_blocks_received_total",
+            "Blocks received.",
+            bitswap.blocks_received,
+        ),
+        (
+            "bitswap_wants_sent_total",
+            "Wants sent.",
+            bitswap.wants_sent,
+        ),
+        (
+            "bitswap_wants_received_total",
+            "Wants received.",
+            bitswap.wants_received,
+        ),
+        (
+            "bitswap_duplicate_blocks_received_total",
+            "Received blocks that were already stored.",
+            bitswap.duplicate_blocks_received,
+        ),
+        (
+            "bitswap_sent_bytes_total",
+            "Block bytes sent.",
+            bitswap.bytes_sent,
+        ),
+        (
+            "bitswap_received_bytes_total",
+            "Block bytes received.",
+            bitswap.bytes_received,
+        ),
+        (
+            "network_inbound_bytes_total",
+            "Bytes read from all connections.",
+            bandwidth.total_inbound,
+        ),
+        (
+            "network_outbound_bytes_total",
+            "Bytes written to all connections.",
+            bandwidth.total_outbound,
+        ),
+    ];
+    for (name, help, value) in &counters {
+        metric(&mut out, name, "counter", help, *value);
+    }
+    let gauges = [
+        ("connected_peers", "Connected peers.", peers),
+        ("store_blocks", "Stored blocks.", stats.blocks),
+        ("store_bytes", "Bytes of the stored blocks.", stats.bytes),
+        ("store_pinned_blocks", "Pinned blocks.", stats.pinned),
+        (
+            "store_dead_blocks",
+            "Blocks the next garbage collection removes.",
+            stats.dead,
+        ),
+    ];
+    for (name, help, value) in &gauges {
+        metric(&mut out, name, "gauge", help, *value);
+    }
+    Ok(out)
+}
+
+/// Serves `prometheus_metrics` over HTTP at `GET /metrics`.
+pub struct MetricsServer<C: Codec, M: MultihashDigest> {
+    store: Store<C, M>,
+    listener: TcpListener,
+}
+
+impl<C: Codec, M: MultihashDigest> MetricsServer<C, M> {
+    /// Binds the server to an address.
+    pub async fn bind<A: ToSocketAddrs>(store: Store<C, M>, addr: A) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Self { store, listener })
+    }
+
+    /// Returns the address the server listens on.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Serves requests until accepting a connection fails.
+    pub async fn run(self) -> Result<()> {
+        let store = &self.store;
+        http::serve(&self.listener, |path| async move {
+            if path != "/metrics" {
+                return Response::error("404 Not Found");
+            }
+            match prometheus_metrics(store).await {
+                Ok(metrics) => Response {
+                    status: "200 OK",
+                    content_type: "text/plain; version=0.0.4",
+                    body: metrics.into_bytes(),
+                },
+                Err(err) => {
+                    log::warn!("failed to collect metrics: {}", err);
+                    Response::error("500 Internal Server Error")
+                }
+            }
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use libipld::block::Block;
+    use libipld::codec_impl::Multicodec;
+    use libipld::multihash::{Multihash, SHA2_256};
+    use libipld::raw::RawCodec;
+    use libipld::store::Store as _;
+    use tempdir::TempDir;
+
+    #[async_std::test]
+    async fn test_prometheus_metrics() {
+        let tmp = TempDir::new("").unwrap();
+        let mut config = Config::from_path_local(tmp.path()).unwrap();
+        config.network.enable_mdns = false;
+        let store = Store::<Multicodec, Multihash>::new(config).unwrap();
+        let block = Block::encode(RawCodec, SHA2_256, &b"metrics"[..]).unwrap();
+        store.insert(&block).await.unwrap();
+
+        let metrics = prometheus_metrics(&store).await.unwrap();
+        assert!(metrics.contains("# TYPE ipfs_embed_bitswap_blocks_sent_total counter\n"));
+        assert!(metrics.contains("\nipfs_embed_connected_peers 0\n"));
+        assert!(metrics.contains("\nipfs_embed_store_blocks 1\n"));
+        assert!(metrics.contains("\nipfs_embed_store_bytes 7\n"));
+    }
+}
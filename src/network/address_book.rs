@@ -0,0 +1,247 @@
+use core::task::{Context, Poll};
+use futures::channel::oneshot;
+use libipld::error::Result;
+use libp2p::core::connection::{ConnectedPoint, ConnectionId};
+use libp2p::core::{Multiaddr, PeerId};
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::protocols_handler::{DummyProtocolsHandler, ProtocolsHandler};
+use libp2p::swarm::{DialPeerCondition, NetworkBehaviour, NetworkBehaviourAction, PollParameters};
+use std::collections::{HashMap, VecDeque};
+use thiserror::Error;
+
+type InEvent = <DummyProtocolsHandler as ProtocolsHandler>::InEvent;
+type OutEvent = <DummyProtocolsHandler as ProtocolsHandler>::OutEvent;
+type Reply = oneshot::Sender<Result<PeerId>>;
+
+/// Dialing a peer failed. The supplied string is the dialed address.
+#[derive(Debug, Error)]
+#[error("Failed to connect to {0}.")]
+pub struct ConnectionFailed(pub String);
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AddressBookEvent {}
+
+/// Keeps track of the established connections and of addresses that were
+/// dialed explicitly.
+#[derive(Default)]
+pub struct AddressBook {
+    connections: HashMap<PeerId, HashMap<ConnectionId, Multiaddr>>,
+    addresses: HashMap<PeerId, Vec<Multiaddr>>,
+    /// Pending dials of a peer.
+    dialing_peers: HashMap<PeerId, Vec<(Multiaddr, Reply)>>,
+    /// Pending dials of an address without a peer id.
+    dialing_addresses: HashMap<Multiaddr, Vec<Reply>>,
+    events: VecDeque<NetworkBehaviourAction<InEvent, AddressBookEvent>>,
+}
+
+impl AddressBook {
+    /// Returns the connected peers with the remote addresses of their connections.
+    pub fn connected_peers(&self) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        self.connections
+            .iter()
+            .map(|(peer_id, connections)| {
+                (peer_id.clone(), connections.values().cloned().collect())
+            })
+            .collect()
+    }
+
+    /// Dials an address. If the address ends with a `/p2p/` component the
+    /// address is added to the address book and the peer is dialed. `tx`
+    /// receives the peer id once the connection is established.
+    pub fn dial(&mut self, mut address: Multiaddr, tx: Reply) {
+        let peer_id = match address.iter().last() {
+            Some(Protocol::P2p(multihash)) => PeerId::from_multihash(multihash).ok(),
+            _ => None,
+        };
+        if let Some(peer_id) = peer_id {
+            address.pop();
+            if self.connections.contains_key(&peer_id) {
+                tx.send(Ok(peer_id)).ok();
+                return;
+            }
+            let addresses = self.addresses.entry(peer_id.clone()).or_default();
+            if !addresses.contains(&address) {
+                addresses.push(address.clone());
+            }
+            self.dialing_peers
+                .entry(peer_id.clone())
+                .or_default()
+                .push((address, tx));
+            self.events.push_back(NetworkBehaviourAction::DialPeer {
+                peer_id,
+                condition: DialPeerCondition::Disconnected,
+            });
+        } else {
+            self.dialing_addresses
+                .entry(address.clone())
+                .or_default()
+                .push(tx);
+            self.events
+                .push_back(NetworkBehaviourAction::DialAddress { address });
+        }
+    }
+}
+
+impl NetworkBehaviour for AddressBook {
+    type ProtocolsHandler = DummyProtocolsHandler;
+    type OutEvent = AddressBookEvent;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        Default::default()
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.addresses.get(peer_id).cloned().unwrap_or_default()
+    }
+
+    fn inject_connected(&mut self, peer_id: &PeerId) {
+        for (_, tx) in self.dialing_peers.remove(peer_id).unwrap_or_default() {
+            tx.send(Ok(peer_id.clone())).ok();
+        }
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        self.connections.remove(peer_id);
+    }
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        let address = endpoint.get_remote_address().clone();
+        if let ConnectedPoint::Dialer { address } = endpoint {
+            for tx in self.dialing_addresses.remove(address).unwrap_or_default() {
+                tx.send(Ok(peer_id.clone())).ok();
+            }
+        }
+        self.connections
+            .entry(peer_id.clone())
+            .or_default()
+            .insert(*id, address);
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        _endpoint: &ConnectedPoint,
+    ) {
+        if let Some(connections) = self.connections.get_mut(peer_id) {
+            connections.remove(id);
+        }
+    }
+
+    fn inject_address_change(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        _old: &ConnectedPoint,
+        new: &ConnectedPoint,
+    ) {
+        self.inject_connection_established(peer_id, id, new);
+    }
+
+    fn inject_event(&mut self, _peer_id: PeerId, _connection: ConnectionId, event: OutEvent) {
+        match event {}
+    }
+
+    fn inject_addr_reach_failure(
+        &mut self,
+        peer_id: Option<&PeerId>,
+        address: &Multiaddr,
+        error: &dyn std::error::Error,
+    ) {
+        if peer_id.is_none() {
+            log::debug!("failed to reach {}: {}", address, error);
+            for tx in self.dialing_addresses.remove(address).unwrap_or_default() {
+                tx.send(Err(ConnectionFailed(address.to_string()).into()))
+                    .ok();
+            }
+        }
+    }
+
+    fn inject_dial_failure(&mut self, peer_id: &PeerId) {
+        for (address, tx) in self.dialing_peers.remove(peer_id).unwrap_or_default() {
+            let address = address.with(Protocol::P2p(peer_id.clone().into()));
+            tx.send(Err(ConnectionFailed(address.to_string()).into()))
+                .ok();
+        }
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context,
+        _: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<InEvent, AddressBookEvent>> {
+        if let Some(event) = self.events.pop_front() {
+            Poll::Ready(event)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::FutureExt;
+
+    #[test]
+    fn test_connected_peers() {
+        let mut book = AddressBook::default();
+        let peer_id = PeerId::random();
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        let endpoint = ConnectedPoint::Dialer {
+            address: address.clone(),
+        };
+        let id = ConnectionId::new(0);
+        book.inject_connection_established(&peer_id, &id, &endpoint);
+        book.inject_connected(&peer_id);
+        assert_eq!(
+            book.connected_peers(),
+            vec![(peer_id.clone(), vec![address])]
+        );
+
+        book.inject_connection_closed(&peer_id, &id, &endpoint);
+        book.inject_disconnected(&peer_id);
+        assert!(book.connected_peers().is_empty());
+    }
+
+    #[test]
+    fn test_dial_peer() {
+        let mut book = AddressBook::default();
+        let peer_id = PeerId::random();
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        let (tx, mut rx) = oneshot::channel();
+        book.dial(
+            address.clone().with(Protocol::P2p(peer_id.clone().into())),
+            tx,
+        );
+        assert_eq!(book.addresses_of_peer(&peer_id), vec![address]);
+        match book.events.pop_front() {
+            Some(NetworkBehaviourAction::DialPeer {
+                peer_id: dialed, ..
+            }) => {
+                assert_eq!(dialed, peer_id)
+            }
+            _ => panic!("expected dial"),
+        }
+        assert!((&mut rx).now_or_never().is_none());
+        book.inject_connected(&peer_id);
+        assert_eq!(rx.now_or_never().unwrap().unwrap().unwrap(), peer_id);
+    }
+
+    #[test]
+    fn test_dial_address_failure() {
+        let mut book = AddressBook::default();
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        let (tx, rx) = oneshot::channel();
+        book.dial(address.clone(), tx);
+        let error = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+        book.inject_addr_reach_failure(None, &address, &error);
+        let err = rx.now_or_never().unwrap().unwrap().unwrap_err();
+        assert!(err.downcast_ref::<ConnectionFailed>().is_some());
+    }
+}
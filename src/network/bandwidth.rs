@@ -0,0 +1,92 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use libp2p::bandwidth::BandwidthSinks;
+use std::fmt;
+use std::sync::Arc;
+
+/// Interval at which the transfer rates are updated.
+pub const BANDWIDTH_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bytes transferred over all connections since the node was started.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BandwidthStats {
+    pub total_inbound: u64,
+    pub total_outbound: u64,
+    /// Bytes per second received during the last sample interval.
+    pub inbound_per_sec: u64,
+    /// Bytes per second sent during the last sample interval.
+    pub outbound_per_sec: u64,
+}
+
+/// Counts the bytes read from and written to the transport. The totals are
+/// counted by the transport, the rates are updated by `sample`.
+pub struct Bandwidth {
+    sinks: Arc<BandwidthSinks>,
+    last_inbound: AtomicU64,
+    last_outbound: AtomicU64,
+    inbound_per_sec: AtomicU64,
+    outbound_per_sec: AtomicU64,
+}
+
+fn per_sec(bytes: u64, elapsed: Duration) -> u64 {
+    match elapsed.as_millis() as u64 {
+        0 => 0,
+        millis => bytes * 1000 / millis,
+    }
+}
+
+impl Bandwidth {
+    pub fn new(sinks: Arc<BandwidthSinks>) -> Self {
+        Self {
+            sinks,
+            last_inbound: Default::default(),
+            last_outbound: Default::default(),
+            inbound_per_sec: Default::default(),
+            outbound_per_sec: Default::default(),
+        }
+    }
+
+    /// Updates the rates from the bytes transferred since the previous
+    /// sample, which was taken `elapsed` ago.
+    pub fn sample(&self, elapsed: Duration) {
+        let inbound = self.sinks.total_inbound();
+        let outbound = self.sinks.total_outbound();
+        let last_inbound = self.last_inbound.swap(inbound, Ordering::Relaxed);
+        let last_outbound = self.last_outbound.swap(outbound, Ordering::Relaxed);
+        self.inbound_per_sec.store(
+            per_sec(inbound.saturating_sub(last_inbound), elapsed),
+            Ordering::Relaxed,
+        );
+        self.outbound_per_sec.store(
+            per_sec(outbound.saturating_sub(last_outbound), elapsed),
+            Ordering::Relaxed,
+        );
+    }
+
+    pub fn stats(&self) -> BandwidthStats {
+        BandwidthStats {
+            total_inbound: self.sinks.total_inbound(),
+            total_outbound: self.sinks.total_outbound(),
+            inbound_per_sec: self.inbound_per_sec.load(Ordering::Relaxed),
+            outbound_per_sec: self.outbound_per_sec.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl fmt::Debug for Bandwidth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.stats().fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_per_sec() {
+        assert_eq!(per_sec(1000, Duration::from_secs(1)), 1000);
+        assert_eq!(per_sec(1000, Duration::from_millis(500)), 2000);
+        assert_eq!(per_sec(1000, Duration::from_secs(0)), 0);
+    }
+}
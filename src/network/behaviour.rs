@@ -1,26 +1,63 @@
+use crate::network::address_book::{AddressBook, AddressBookEvent};
+use crate::network::idle::{IdleConnections, IdleEvent};
+use crate::network::listeners::{Listeners, ListenersEvent};
+use crate::network::peer_filter::{PeerFilter, PeerFilterEvent};
+use crate::network::providers::{Providers, ProvidersEvent};
+use crate::network::record::{self, DhtRecord, RecordNotFound};
+use crate::network::redial::{Redial, RedialEvent};
 use crate::network::NetworkConfig;
 use core::task::{Context, Poll};
+use futures::channel::oneshot;
 use ip_network::IpNetwork;
 use libipld::cid::Cid;
 use libipld::error::Result;
 use libipld::multihash::MultihashDigest;
-use libp2p::core::PeerId;
+use libp2p::core::connection::ListenerId;
+use libp2p::core::{Multiaddr, PeerId};
+use libp2p::gossipsub::error::PublishError;
+use libp2p::gossipsub::{
+    Gossipsub, GossipsubConfigBuilder, GossipsubEvent, MessageAuthenticity, Topic,
+};
 use libp2p::identify::{Identify, IdentifyEvent};
+use libp2p::identity::Keypair;
 use libp2p::kad::record::store::MemoryStore;
-use libp2p::kad::record::Key;
+use libp2p::kad::record::{Key, Record};
 use libp2p::kad::{
-    BootstrapError, BootstrapOk, GetProvidersOk, Kademlia, KademliaEvent, QueryId, QueryResult,
+    BootstrapError, BootstrapOk, GetProvidersError, GetProvidersOk, GetRecordError, GetRecordOk,
+    Kademlia, KademliaConfig, KademliaEvent, PutRecordError, PutRecordOk, QueryId, QueryResult,
+    Quorum,
 };
 use libp2p::mdns::{Mdns, MdnsEvent};
 use libp2p::multiaddr::Protocol;
-use libp2p::ping::{Ping, PingEvent};
+use libp2p::ping::{Ping, PingConfig, PingEvent, PingSuccess};
 use libp2p::swarm::toggle::Toggle;
 use libp2p::swarm::{NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters};
 use libp2p::NetworkBehaviour;
 use libp2p_bitswap::{Bitswap, BitswapEvent, Priority};
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
+/// Protocol version sent by identify.
+pub const PROTOCOL_VERSION: &str = "/ipfs-embed/1.0";
+
+/// Version of libp2p the network is built with.
+pub const LIBP2P_VERSION: &str = "0.24.0";
+
+/// Protocol ids the node supports. Which security and multiplexing protocols
+/// are offered and whether ping and gossipsub are used depends on the config.
+pub const PROTOCOLS: &[&str] = &[
+    "/ipfs/bitswap/1.1.0",
+    "/ipfs/kad/1.0.0",
+    "/ipfs/id/1.0.0",
+    "/ipfs/ping/1.0.0",
+    "/meshsub/1.0.0",
+    "/noise",
+    "/secio/1.0.0",
+    "/yamux/1.0.0",
+    "/mplex/6.7.0",
+];
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum NetworkEvent {
     ReceivedBlock(PeerId, Cid, Box<[u8]>),
@@ -28,6 +65,94 @@ pub enum NetworkEvent {
     BootstrapComplete,
     Providers(Cid, HashSet<PeerId>),
     NoProviders(Cid),
+    /// A peer was discovered on the local network by mdns.
+    PeerDiscovered(PeerId, Multiaddr),
+    /// A peer sent its agent version through identify.
+    Identified(PeerId, String),
+    /// A ping to a peer succeeded with the round-trip time.
+    PingResult(PeerId, Duration),
+    /// A pubsub message was received on a subscribed topic.
+    Message(PubsubMessage),
+    /// A peer that isn't on the allowlist connected.
+    PeerRejected(PeerId),
+    /// A peer had no protocol activity for the idle timeout.
+    IdlePeer(PeerId),
+    /// A connection to a peer was established, with the remote address.
+    ConnectionEstablished(PeerId, Multiaddr),
+    /// A connection to a peer was closed, with the error that closed it if
+    /// it wasn't closed gracefully.
+    ConnectionClosed(PeerId, Option<String>),
+    /// A record was stored on a quorum of peers.
+    RecordPut(Key),
+    /// Valid records were found for a key.
+    RecordFound(Key),
+    /// A listener closed, with the address it was created with and the
+    /// error that closed it.
+    ListenerClosed(Multiaddr, Option<String>),
+}
+
+/// A change of the connections to a peer.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ConnectionEvent {
+    /// A connection was established, with the remote address.
+    Established(PeerId, Multiaddr),
+    /// A connection was closed, with the error that closed it if it wasn't
+    /// closed gracefully.
+    Closed(PeerId, Option<String>),
+}
+
+/// A message received on a pubsub topic.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PubsubMessage {
+    pub topic: String,
+    /// Peer that published the message.
+    pub source: Option<PeerId>,
+    pub data: Vec<u8>,
+}
+
+/// Pubsub isn't enabled in the network config.
+#[derive(Debug, Error)]
+#[error("Pubsub is disabled.")]
+pub struct PubsubDisabled;
+
+#[derive(Debug, Error)]
+#[error("{0:?}")]
+pub struct PubsubPublishError(pub PublishError);
+
+/// Bitswap counters since the node was started.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BitswapStats {
+    pub blocks_sent: u64,
+    pub blocks_received: u64,
+    pub wants_sent: u64,
+    pub wants_received: u64,
+    /// Received blocks that were already stored locally, they aren't
+    /// inserted again. Blocks that aren't wanted anymore are dropped by
+    /// bitswap and aren't counted.
+    pub duplicate_blocks_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// A connected peer.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PeerInfo {
+    pub peer_id: PeerId,
+    /// Remote addresses of the connections to the peer.
+    pub addresses: Vec<Multiaddr>,
+    /// Agent version, if the peer identified itself.
+    pub agent_version: Option<String>,
+}
+
+/// An outstanding want.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WantInfo {
+    pub cid: Cid,
+    pub priority: Priority,
+    /// Connected peers that were sent the want.
+    pub peers: Vec<PeerId>,
+    /// Providers that are being tried for the block.
+    pub providers: Vec<PeerId>,
 }
 
 /// Behaviour type.
@@ -38,6 +163,9 @@ pub struct NetworkBackendBehaviour<M: MultihashDigest> {
     node_name: String,
     #[behaviour(ignore)]
     peer_id: PeerId,
+    /// Signs the DHT records we put.
+    #[behaviour(ignore)]
+    keypair: Keypair,
     #[behaviour(ignore)]
     peers: HashMap<PeerId, String>,
 
@@ -46,12 +174,30 @@ pub struct NetworkBackendBehaviour<M: MultihashDigest> {
     allow_non_globals_in_dht: bool,
     #[behaviour(ignore)]
     queries: HashMap<QueryId, Cid>,
+    /// Provider lookups requested by the store.
+    #[behaviour(ignore)]
+    lookups: HashMap<QueryId, oneshot::Sender<Vec<PeerId>>>,
+    /// Record puts requested by the store.
+    #[behaviour(ignore)]
+    record_puts: HashMap<QueryId, oneshot::Sender<Result<()>>>,
+    /// Record gets requested by the store.
+    #[behaviour(ignore)]
+    record_gets: HashMap<QueryId, oneshot::Sender<Result<Vec<DhtRecord>>>>,
 
     mdns: Toggle<Mdns>,
     ping: Toggle<Ping>,
+    pubsub: Toggle<Gossipsub>,
     identify: Identify,
     bitswap: Bitswap<M>,
+    providers: Providers,
+    address_book: AddressBook,
+    peer_filter: PeerFilter,
+    idle: IdleConnections,
+    redial: Redial,
+    listeners: Listeners,
 
+    #[behaviour(ignore)]
+    stats: BitswapStats,
     #[behaviour(ignore)]
     events: VecDeque<NetworkEvent>,
 }
@@ -60,7 +206,16 @@ impl<M: MultihashDigest> NetworkBehaviourEventProcess<MdnsEvent> for NetworkBack
     fn inject_event(&mut self, event: MdnsEvent) {
         match event {
             MdnsEvent::Discovered(list) => {
-                for (peer, _) in list {
+                let mut discovered = HashSet::new();
+                for (peer, addr) in list {
+                    // mdns peers are on the local network, so their addresses
+                    // are reachable regardless of `allow_non_globals_in_dht`.
+                    self.kad.add_address(&peer, addr.clone());
+                    self.events
+                        .push_back(NetworkEvent::PeerDiscovered(peer.clone(), addr));
+                    discovered.insert(peer);
+                }
+                for peer in discovered {
                     self.connect(peer);
                 }
             }
@@ -75,8 +230,18 @@ impl<M: MultihashDigest> NetworkBehaviourEventProcess<KademliaEvent>
     fn inject_event(&mut self, event: KademliaEvent) {
         match event {
             KademliaEvent::QueryResult { id, result, .. } => match result {
-                QueryResult::GetProviders(Ok(GetProvidersOk { providers, .. })) => {
-                    if let Some(cid) = self.queries.remove(&id) {
+                QueryResult::GetProviders(Ok(GetProvidersOk {
+                    providers,
+                    closest_peers,
+                    ..
+                })) => {
+                    // the peers in the result answered the query
+                    for peer in providers.iter().chain(&closest_peers) {
+                        self.idle.record_activity(peer);
+                    }
+                    if let Some(tx) = self.lookups.remove(&id) {
+                        tx.send(providers.into_iter().collect()).ok();
+                    } else if let Some(cid) = self.queries.remove(&id) {
                         if providers.is_empty() {
                             self.events.push_back(NetworkEvent::NoProviders(cid));
                         } else {
@@ -85,7 +250,66 @@ impl<M: MultihashDigest> NetworkBehaviourEventProcess<KademliaEvent>
                         }
                     }
                 }
-                QueryResult::Bootstrap(Ok(BootstrapOk { num_remaining, .. })) => {
+                // a lookup that timed out returns the providers found so far
+                QueryResult::GetProviders(Err(GetProvidersError::Timeout {
+                    providers, ..
+                })) => {
+                    if let Some(tx) = self.lookups.remove(&id) {
+                        tx.send(providers.into_iter().collect()).ok();
+                    }
+                }
+                QueryResult::PutRecord(res) => {
+                    let res = match res {
+                        Ok(PutRecordOk { key }) => {
+                            self.events.push_back(NetworkEvent::RecordPut(key));
+                            Ok(())
+                        }
+                        Err(err) => Err(KadPutRecordError(err).into()),
+                    };
+                    if let Some(tx) = self.record_puts.remove(&id) {
+                        tx.send(res).ok();
+                    }
+                }
+                QueryResult::GetRecord(res) => {
+                    let (key, records) = match res {
+                        Ok(GetRecordOk { records }) => match records.first() {
+                            Some(first) => (first.record.key.clone(), records),
+                            None => return,
+                        },
+                        // the records found so far are used
+                        Err(GetRecordError::QuorumFailed { key, records, .. })
+                        | Err(GetRecordError::Timeout { key, records, .. }) => (key, records),
+                        Err(GetRecordError::NotFound { key, .. }) => (key, vec![]),
+                    };
+                    let tx = match self.record_gets.remove(&id) {
+                        Some(tx) => tx,
+                        None => return,
+                    };
+                    for peer in records.iter().filter_map(|record| record.peer.as_ref()) {
+                        self.idle.record_activity(peer);
+                    }
+                    let mut valid: Vec<DhtRecord> = vec![];
+                    for peer_record in records {
+                        match record::verify(key.as_ref(), &peer_record.record.value) {
+                            Ok(record) if !valid.contains(&record) => valid.push(record),
+                            Ok(_) => {}
+                            Err(err) => {
+                                log::warn!("dropping record from {:?}: {}", peer_record.peer, err)
+                            }
+                        }
+                    }
+                    if valid.is_empty() {
+                        tx.send(Err(RecordNotFound.into())).ok();
+                    } else {
+                        self.events.push_back(NetworkEvent::RecordFound(key));
+                        tx.send(Ok(valid)).ok();
+                    }
+                }
+                QueryResult::Bootstrap(Ok(BootstrapOk {
+                    peer,
+                    num_remaining,
+                })) => {
+                    self.idle.record_activity(&peer);
                     if num_remaining == 0 {
                         self.events.push_back(NetworkEvent::BootstrapComplete);
                     }
@@ -124,6 +348,7 @@ impl<M: MultihashDigest> NetworkBehaviourEventProcess<KademliaEvent>
                 );
             }
             KademliaEvent::RoutingUpdated { peer, .. } => {
+                self.idle.record_activity(&peer);
                 log::info!(
                     "{}: routing updated peer {}",
                     self.node_name,
@@ -136,9 +361,14 @@ impl<M: MultihashDigest> NetworkBehaviourEventProcess<KademliaEvent>
 
 impl<M: MultihashDigest> NetworkBehaviourEventProcess<PingEvent> for NetworkBackendBehaviour<M> {
     fn inject_event(&mut self, event: PingEvent) {
-        // Don't really need to do anything here as ping handles disconnecting automatically.
-        if let Err(err) = &event.result {
-            log::debug!("ping: {} {:?}", event.peer.to_base58(), err);
+        // ping closes the connection after `ping_max_failures` failed pings.
+        match event.result {
+            Ok(PingSuccess::Ping { rtt }) => {
+                self.events
+                    .push_back(NetworkEvent::PingResult(event.peer, rtt));
+            }
+            Ok(PingSuccess::Pong) => {}
+            Err(err) => log::debug!("ping: {} {:?}", event.peer.to_base58(), err),
         }
     }
 }
@@ -155,10 +385,15 @@ impl<M: MultihashDigest> NetworkBehaviourEventProcess<IdentifyEvent>
             observed_addr,
         } = event
         {
+            // identify reports the observed address to the swarm, which adds
+            // it to the external addresses.
             log::info!("{}: has external address {}", self.node_name, observed_addr);
             self.peers
                 .insert(peer_id.clone(), info.agent_version.clone());
-            self.kad.add_address(&self.peer_id, observed_addr);
+            self.events.push_back(NetworkEvent::Identified(
+                peer_id.clone(),
+                info.agent_version.clone(),
+            ));
             for addr in info.listen_addrs {
                 let global = match addr.iter().next() {
                     Some(Protocol::Ip4(ip)) => IpNetwork::from(ip).is_global(),
@@ -189,28 +424,143 @@ impl<M: MultihashDigest> NetworkBehaviourEventProcess<IdentifyEvent>
     }
 }
 
+impl<M: MultihashDigest> NetworkBehaviourEventProcess<GossipsubEvent>
+    for NetworkBackendBehaviour<M>
+{
+    fn inject_event(&mut self, event: GossipsubEvent) {
+        if let GossipsubEvent::Message(peer_id, _, message) = event {
+            self.idle.record_activity(&peer_id);
+            for topic in message.topics {
+                self.events.push_back(NetworkEvent::Message(PubsubMessage {
+                    topic: topic.as_str().to_string(),
+                    source: message.source.clone(),
+                    data: message.data.clone(),
+                }));
+            }
+        }
+    }
+}
+
 impl<M: MultihashDigest> NetworkBehaviourEventProcess<BitswapEvent> for NetworkBackendBehaviour<M> {
     fn inject_event(&mut self, event: BitswapEvent) {
         // Propagate bitswap events to the swarm.
         let event = match event {
             BitswapEvent::ReceivedBlock(peer_id, cid, data) => {
+                self.idle.record_activity(&peer_id);
                 log::debug!("received block {}", cid.to_string());
+                self.stats.blocks_received += 1;
+                self.stats.bytes_received += data.len() as u64;
                 NetworkEvent::ReceivedBlock(peer_id, cid, data)
             }
             BitswapEvent::ReceivedWant(peer_id, cid, _) => {
+                self.idle.record_activity(&peer_id);
                 log::debug!("received want {}", cid.to_string());
+                self.stats.wants_received += 1;
                 NetworkEvent::ReceivedWant(peer_id, cid)
             }
-            BitswapEvent::ReceivedCancel(_, _) => return,
+            BitswapEvent::ReceivedCancel(peer_id, _) => {
+                self.idle.record_activity(&peer_id);
+                return;
+            }
         };
         self.events.push_back(event);
     }
 }
 
+impl<M: MultihashDigest> NetworkBehaviourEventProcess<ProvidersEvent>
+    for NetworkBackendBehaviour<M>
+{
+    fn inject_event(&mut self, event: ProvidersEvent) {
+        match event {
+            ProvidersEvent::Exhausted(cid) => {
+                self.events.push_back(NetworkEvent::NoProviders(cid));
+            }
+        }
+    }
+}
+
+impl<M: MultihashDigest> NetworkBehaviourEventProcess<IdleEvent> for NetworkBackendBehaviour<M> {
+    fn inject_event(&mut self, event: IdleEvent) {
+        match event {
+            IdleEvent::Idle(peer_id) => {
+                if !self.is_busy(&peer_id) {
+                    self.events.push_back(NetworkEvent::IdlePeer(peer_id));
+                }
+            }
+        }
+    }
+}
+
+impl<M: MultihashDigest> NetworkBehaviourEventProcess<RedialEvent> for NetworkBackendBehaviour<M> {
+    fn inject_event(&mut self, event: RedialEvent) {
+        match event {
+            RedialEvent::Reconnected(peer_id) => {
+                log::info!("boot node {} reconnected, bootstrapping", peer_id);
+                self.kad.bootstrap().ok();
+            }
+        }
+    }
+}
+
+impl<M: MultihashDigest> NetworkBehaviourEventProcess<ListenersEvent>
+    for NetworkBackendBehaviour<M>
+{
+    fn inject_event(&mut self, event: ListenersEvent) {
+        match event {
+            ListenersEvent::Closed(address, reason) => {
+                self.events
+                    .push_back(NetworkEvent::ListenerClosed(address, reason));
+            }
+        }
+    }
+}
+
+impl<M: MultihashDigest> NetworkBehaviourEventProcess<PeerFilterEvent>
+    for NetworkBackendBehaviour<M>
+{
+    fn inject_event(&mut self, event: PeerFilterEvent) {
+        match event {
+            PeerFilterEvent::Rejected(peer_id) => {
+                self.events.push_back(NetworkEvent::PeerRejected(peer_id));
+            }
+        }
+    }
+}
+
+impl<M: MultihashDigest> NetworkBehaviourEventProcess<AddressBookEvent>
+    for NetworkBackendBehaviour<M>
+{
+    fn inject_event(&mut self, event: AddressBookEvent) {
+        match event {}
+    }
+}
+
 #[derive(Debug, Error)]
 #[error("{0:?}")]
 pub struct KadRecordError(pub libp2p::kad::record::store::Error);
 
+#[derive(Debug, Error)]
+#[error("{0:?}")]
+pub struct KadPutRecordError(pub PutRecordError);
+
+fn kad_config(config: &NetworkConfig) -> KademliaConfig {
+    let mut kad_config = KademliaConfig::default();
+    // public blocks are reprovided by the network
+    kad_config.set_provider_publication_interval(None);
+    kad_config.set_provider_record_ttl(config.provider_record_ttl);
+    kad_config.set_replication_factor(config.dht_replication_factor);
+    kad_config.set_parallelism(config.dht_parallelism);
+    kad_config.set_query_timeout(config.dht_query_timeout);
+    if !config.protocol_prefix.is_empty() {
+        kad_config.set_protocol_name(prefixed(config, "/ipfs/kad/1.0.0"));
+    }
+    kad_config
+}
+
+fn prefixed(config: &NetworkConfig, protocol: &str) -> Vec<u8> {
+    format!("{}{}", config.protocol_prefix, protocol).into_bytes()
+}
+
 impl<M: MultihashDigest> NetworkBackendBehaviour<M> {
     /// Create a Kademlia behaviour with the IPFS bootstrap nodes.
     pub fn new(config: NetworkConfig) -> Result<Self> {
@@ -223,38 +573,86 @@ impl<M: MultihashDigest> NetworkBackendBehaviour<M> {
         }
         .into();
 
+        if let Some(ttl) = config.provider_record_ttl {
+            if ttl <= config.reprovide_interval {
+                log::warn!("provider records expire before they are published again");
+            }
+        }
         let store = MemoryStore::new(peer_id.clone());
-        let mut kad = Kademlia::new(peer_id.clone(), store);
+        let mut kad = Kademlia::with_config(peer_id.clone(), store, kad_config(&config));
         for (addr, peer_id) in &config.boot_nodes {
             kad.add_address(peer_id, addr.to_owned());
         }
         if !config.boot_nodes.is_empty() {
             kad.bootstrap().expect("bootstrap nodes not empty");
         }
+        let redial = Redial::new(
+            &config.boot_nodes,
+            config.redial_backoff,
+            config.max_redial_backoff,
+            Instant::now(),
+        );
 
         let ping = if config.enable_ping {
-            Some(Ping::default())
+            let ping_config = PingConfig::new()
+                .with_interval(config.ping_interval)
+                .with_timeout(config.ping_timeout)
+                .with_max_failures(config.ping_max_failures);
+            Some(Ping::new(ping_config))
+        } else {
+            None
+        }
+        .into();
+
+        // the default config doesn't hash topics, as go-ipfs expects.
+        let pubsub = if config.enable_pubsub {
+            let authenticity = MessageAuthenticity::Signed(config.node_key.clone());
+            let mut builder = GossipsubConfigBuilder::default();
+            if !config.protocol_prefix.is_empty() {
+                builder.protocol_id(prefixed(&config, "/meshsub/1.0.0"));
+            }
+            Some(Gossipsub::new(authenticity, builder.build()))
         } else {
             None
         }
         .into();
 
         let public = config.public();
-        let identify = Identify::new("/ipfs-embed/1.0".into(), config.node_name.clone(), public);
+        let agent_version = config
+            .agent_version
+            .clone()
+            .unwrap_or_else(|| config.node_name.clone());
+        let identify = Identify::new(PROTOCOL_VERSION.into(), agent_version, public);
 
         let bitswap = Bitswap::new();
 
         Ok(Self {
             node_name: config.node_name,
             peer_id,
+            keypair: config.node_key,
             allow_non_globals_in_dht: config.allow_non_globals_in_dht,
             mdns,
             kad,
             ping,
+            pubsub,
             identify,
             bitswap,
+            providers: Providers::new(
+                config.max_connections,
+                config.max_pending_outgoing,
+                config.want_policy.parallel_providers,
+            ),
+            address_book: Default::default(),
+            peer_filter: PeerFilter::new(config.allowed_peers, config.denied_peers),
+            idle: IdleConnections::new(config.idle_connection_timeout),
+            redial,
+            listeners: Default::default(),
+            stats: Default::default(),
             events: Default::default(),
             queries: Default::default(),
+            lookups: Default::default(),
+            record_puts: Default::default(),
+            record_gets: Default::default(),
             peers: Default::default(),
         })
     }
@@ -266,25 +664,198 @@ impl<M: MultihashDigest> NetworkBackendBehaviour<M> {
             .unwrap_or_else(|| peer_id.to_string())
     }
 
+    /// Returns true if there are peers that were sent our wants.
+    pub fn has_peers(&self) -> bool {
+        self.bitswap.peers().next().is_some()
+    }
+
+    /// Returns true if an idle peer is still waiting for blocks from us.
+    /// Wants and DHT queries of our own keep a peer from being idle only
+    /// while it exchanges messages with us.
+    fn is_busy(&self, peer_id: &PeerId) -> bool {
+        !self.bitswap.wantlist(Some(peer_id)).is_empty()
+    }
+
+    /// Returns the connected peers with the addresses of their connections.
+    pub fn connected_peers(&self) -> Vec<PeerInfo> {
+        self.address_book
+            .connected_peers()
+            .into_iter()
+            .map(|(peer_id, addresses)| PeerInfo {
+                agent_version: self.peers.get(&peer_id).cloned(),
+                peer_id,
+                addresses,
+            })
+            .collect()
+    }
+
+    /// Dials an address, which may end with the peer id of the remote.
+    pub fn dial(&mut self, address: Multiaddr, tx: oneshot::Sender<Result<PeerId>>) {
+        log::debug!("dial {}", address);
+        self.address_book.dial(address, tx);
+    }
+
     pub fn connect(&mut self, peer_id: PeerId) {
         self.bitswap.connect(peer_id);
     }
 
+    /// Dials the providers of a block until one of them can be reached.
+    /// Providers that aren't allowed are ignored.
+    pub fn add_providers(&mut self, cid: Cid, providers: HashSet<PeerId>) {
+        let local_peer_id = &self.peer_id;
+        let peer_filter = &self.peer_filter;
+        let providers = providers
+            .into_iter()
+            .filter(|peer_id| peer_id != local_peer_id && peer_filter.is_allowed(peer_id));
+        self.providers.add_providers(cid, providers);
+    }
+
+    /// Returns the peers on the denylist.
+    pub fn denied_peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.peer_filter.denied()
+    }
+
+    /// Adds a peer to the denylist. The swarm needs to ban it too.
+    pub fn deny_peer(&mut self, peer_id: PeerId) {
+        self.peer_filter.deny(peer_id);
+    }
+
+    /// Records the address a listener was created with.
+    pub fn add_listener(&mut self, id: ListenerId, address: Multiaddr) {
+        self.listeners.add(id, address);
+    }
+
+    /// Stops dialing a boot node and removes it from the DHT.
+    pub fn remove_boot_node(&mut self, peer_id: &PeerId) {
+        self.redial.remove(peer_id);
+        self.kad.remove_peer(peer_id);
+    }
+
+    /// Removes a peer from the denylist.
+    pub fn undeny_peer(&mut self, peer_id: &PeerId) {
+        self.peer_filter.undeny(peer_id);
+    }
+
+    /// Subscribes to a pubsub topic.
+    pub fn subscribe(&mut self, topic: &str) -> Result<()> {
+        let pubsub = self.pubsub.as_mut().ok_or(PubsubDisabled)?;
+        pubsub.subscribe(Topic::new(topic.to_string()));
+        Ok(())
+    }
+
+    /// Unsubscribes from a pubsub topic.
+    pub fn unsubscribe(&mut self, topic: &str) {
+        if let Some(pubsub) = self.pubsub.as_mut() {
+            pubsub.unsubscribe(Topic::new(topic.to_string()));
+        }
+    }
+
+    /// Publishes a message on a pubsub topic.
+    pub fn publish(&mut self, topic: &str, data: Vec<u8>) -> Result<()> {
+        let pubsub = self.pubsub.as_mut().ok_or(PubsubDisabled)?;
+        pubsub
+            .publish(&Topic::new(topic.to_string()), data)
+            .map_err(PubsubPublishError)?;
+        Ok(())
+    }
+
+    pub fn bitswap_stats(&self) -> BitswapStats {
+        self.stats
+    }
+
+    /// Returns the outstanding wants. Wants are added by `want_block` and
+    /// removed by `cancel_block`, which is called when the block is received,
+    /// the get is dropped or the want timed out.
+    pub fn wantlist(&self) -> Vec<WantInfo> {
+        let peers: Vec<_> = self.bitswap.peers().cloned().collect();
+        self.bitswap
+            .wantlist(None)
+            .into_iter()
+            .map(|(cid, priority)| WantInfo {
+                providers: self.providers.dialing(&cid).to_vec(),
+                peers: peers.clone(),
+                cid,
+                priority,
+            })
+            .collect()
+    }
+
+    /// Counts a received block that was already stored locally.
+    pub fn duplicate_block_received(&mut self) {
+        self.stats.duplicate_blocks_received += 1;
+    }
+
     pub fn send_block(&mut self, peer_id: &PeerId, cid: Cid, data: Box<[u8]>) {
         log::debug!("send {}", cid.to_string());
+        self.stats.blocks_sent += 1;
+        self.stats.bytes_sent += data.len() as u64;
+        self.idle.record_activity(peer_id);
         self.bitswap.send_block(peer_id, cid, data);
     }
 
     pub fn want_block(&mut self, cid: Cid, priority: Priority) {
         log::debug!("want {}", cid.to_string());
-        let key = Key::new(&cid.hash().to_bytes());
-        self.kad.get_providers(key);
+        self.find_providers(cid.clone());
+        self.send_want(cid, priority);
+    }
+
+    /// Sends a want to the connected peers without searching the DHT.
+    pub fn send_want(&mut self, cid: Cid, priority: Priority) {
+        self.stats.wants_sent += 1;
+        let peers: Vec<_> = self.bitswap.peers().cloned().collect();
+        for peer_id in &peers {
+            self.idle.record_activity(peer_id);
+        }
         self.bitswap.want_block(cid, priority);
     }
 
+    /// Searches the DHT for providers of a block.
+    pub fn find_providers(&mut self, cid: Cid) {
+        let key = Key::new(&cid.hash().to_bytes());
+        let id = self.kad.get_providers(key);
+        self.queries.insert(id, cid);
+    }
+
+    /// Looks up the providers of a block in the DHT without dialing them.
+    pub fn get_providers(&mut self, cid: &Cid, tx: oneshot::Sender<Vec<PeerId>>) {
+        let key = Key::new(&cid.hash().to_bytes());
+        let id = self.kad.get_providers(key);
+        self.lookups.insert(id, tx);
+    }
+
+    /// Signs a value with the node key and stores it in the DHT under `key`.
+    /// Replies once a peer stored it.
+    pub fn put_record(&mut self, key: Vec<u8>, value: Vec<u8>, tx: oneshot::Sender<Result<()>>) {
+        log::debug!("put record {:?}", key);
+        let value = match record::sign(&self.keypair, &key, &value) {
+            Ok(value) => value,
+            Err(err) => {
+                tx.send(Err(err)).ok();
+                return;
+            }
+        };
+        match self.kad.put_record(Record::new(key, value), Quorum::One) {
+            Ok(id) => {
+                self.record_puts.insert(id, tx);
+            }
+            Err(err) => {
+                tx.send(Err(KadRecordError(err).into())).ok();
+            }
+        }
+    }
+
+    /// Looks up the records stored in the DHT under `key`. Records without a
+    /// valid signature are dropped.
+    pub fn get_record(&mut self, key: &[u8], tx: oneshot::Sender<Result<Vec<DhtRecord>>>) {
+        log::debug!("get record {:?}", key);
+        let id = self.kad.get_record(&Key::new(&key), Quorum::One);
+        self.record_gets.insert(id, tx);
+    }
+
     pub fn cancel_block(&mut self, cid: &Cid) {
         log::debug!("cancel {}", cid.to_string());
         self.bitswap.cancel_block(cid);
+        self.providers.remove(cid);
     }
 
     pub fn provide_block(&mut self, cid: &Cid) -> Result<()> {
@@ -296,10 +867,22 @@ impl<M: MultihashDigest> NetworkBackendBehaviour<M> {
 
     pub fn provide_and_send_block(&mut self, cid: &Cid, data: &[u8]) -> Result<()> {
         self.provide_block(&cid)?;
-        self.bitswap.send_block_all(&cid, &data);
+        self.send_block_all(cid, data);
         Ok(())
     }
 
+    /// Sends a block to all peers that want it.
+    pub fn send_block_all(&mut self, cid: &Cid, data: &[u8]) {
+        let peers: Vec<_> = self.bitswap.peers_want(cid).cloned().collect();
+        for peer_id in &peers {
+            self.idle.record_activity(peer_id);
+        }
+        let peers = peers.len() as u64;
+        self.stats.blocks_sent += peers;
+        self.stats.bytes_sent += peers * data.len() as u64;
+        self.bitswap.send_block_all(&cid, &data);
+    }
+
     pub fn unprovide_block(&mut self, cid: &Cid) {
         log::debug!("unprovide {}", cid.to_string());
         let key = Key::new(&cid.hash().to_bytes());
@@ -318,3 +901,22 @@ impl<M: MultihashDigest> NetworkBackendBehaviour<M> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::num::NonZeroUsize;
+
+    #[test]
+    fn test_kad_config() {
+        let mut config = NetworkConfig::new_local();
+        config.dht_replication_factor = NonZeroUsize::new(2).unwrap();
+        config.dht_parallelism = NonZeroUsize::new(1).unwrap();
+        config.dht_query_timeout = Duration::from_secs(5);
+        // the query config has no getters
+        let kad_config = format!("{:?}", kad_config(&config));
+        assert!(kad_config.contains("timeout: 5s"));
+        assert!(kad_config.contains("replication_factor: 2"));
+        assert!(kad_config.contains("parallelism: 1"));
+    }
+}
@@ -1,25 +1,237 @@
+use core::num::{NonZeroU32, NonZeroU64, NonZeroUsize};
+use core::time::Duration;
 use libp2p::core::{Multiaddr, PeerId};
-use libp2p::identity::{Keypair, PublicKey};
+use libp2p::identity::{ed25519, Keypair, PublicKey};
+use libp2p::pnet::PreSharedKey;
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Security protocol used to authenticate and encrypt connections.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Security {
+    /// Noise XX handshake. Supported by current go-ipfs and js-ipfs.
+    Noise,
+    /// Deprecated secio handshake, for reaching legacy peers.
+    Secio,
+    /// Negotiate noise, falling back to secio for peers that don't support
+    /// it. Lets a network of secio nodes move to noise one node at a time.
+    Both,
+}
+
+/// Stream multiplexer offered first during negotiation. Both are always supported.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Multiplexer {
+    /// Prefer yamux.
+    Yamux,
+    /// Prefer mplex.
+    Mplex,
+}
+
+/// Limits how long the network looks for a wanted block.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct WantPolicy {
+    /// Time after which a want is cancelled and pending gets fail with a
+    /// timeout. Unlimited if `None`.
+    pub timeout: Option<Duration>,
+    /// Number of times the DHT is searched again when no provider of the
+    /// block could be found.
+    pub retries: u32,
+    /// Time to wait before searching again.
+    pub backoff: Duration,
+    /// Number of providers of a block that are dialed at the same time. The
+    /// block is taken from whichever responds first.
+    pub parallel_providers: usize,
+    /// When no provider of the block could be found in the DHT, the want is
+    /// sent to the connected peers again and cancelled if none of them sent
+    /// the block within this time. Peers often have blocks they don't
+    /// provide. If `None` or without connected peers the want is cancelled
+    /// right away.
+    pub connected_peers_fallback: Option<Duration>,
+    /// Maximum number of outstanding wants of the node. Further wants are
+    /// queued until earlier ones are received, cancelled or time out. Wants
+    /// are sent to all connected peers, so no peer is sent more than this
+    /// many at a time. Unlimited if `None`.
+    pub max_wants_in_flight: Option<NonZeroUsize>,
+}
+
+impl Default for WantPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            retries: 0,
+            backoff: Duration::from_secs(1),
+            parallel_providers: 3,
+            connected_peers_fallback: Some(Duration::from_secs(1)),
+            max_wants_in_flight: None,
+        }
+    }
+}
+
+/// Loads an ed25519 keypair from a file. If the file doesn't exist a new
+/// keypair is generated and written to it, so that the peer id stays the
+/// same across restarts.
+pub fn load_or_create_keypair<P: AsRef<Path>>(path: P) -> io::Result<Keypair> {
+    let path = path.as_ref();
+    match std::fs::read(path) {
+        Ok(mut bytes) => {
+            let keypair = ed25519::Keypair::decode(&mut bytes)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            Ok(Keypair::Ed25519(keypair))
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let keypair = ed25519::Keypair::generate();
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            options.open(path)?.write_all(&keypair.encode())?;
+            Ok(Keypair::Ed25519(keypair))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Loads a pre-shared key from a `swarm.key` file in the format used by
+/// go-ipfs.
+pub fn load_psk<P: AsRef<Path>>(path: P) -> io::Result<PreSharedKey> {
+    let key = std::fs::read_to_string(path)?;
+    key.parse()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Bootstrap nodes of the public IPFS DHT.
+///
+/// The `/dnsaddr/` bootstrap nodes of go-ipfs can't be resolved by the dns
+/// transport, so only the nodes with a fixed address are included.
+pub fn ipfs_boot_nodes() -> Vec<(Multiaddr, PeerId)> {
+    vec![(
+        "/ip4/104.131.131.82/tcp/4001".parse().unwrap(),
+        "QmaCpDMGvV2BGHeYERUEnRQAwe3N8SzbUtfsmvsqQLuvuJ"
+            .parse()
+            .unwrap(),
+    )]
+}
 
 /// Network configuration.
 #[derive(Clone)]
 pub struct NetworkConfig {
     /// Multiaddresses to listen for incoming connections.
     pub listen_addresses: Vec<Multiaddr>,
+    /// Listen again on an address when its listener closes after startup,
+    /// for example because the network interface went down.
+    pub relisten: bool,
     /// Multiaddresses to advertise. Detected automatically if empty.
     pub public_addresses: Vec<Multiaddr>,
-    /// List of initial node addresses.
+    /// List of initial node addresses. They are added to the DHT and dialed
+    /// on startup to bootstrap the DHT. Defaults to `ipfs_boot_nodes`.
     pub boot_nodes: Vec<(Multiaddr, PeerId)>,
+    /// Delay before the boot nodes are dialed again while the node isn't
+    /// connected to any peer. It doubles after each dial of a boot node
+    /// until it connects.
+    pub redial_backoff: Duration,
+    /// Maximum delay between dials of a boot node.
+    pub max_redial_backoff: Duration,
     /// Node identity keypair.
     pub node_key: Keypair,
     /// Name of the node. Sent over the wire for debugging purposes.
     pub node_name: String,
-    /// Enable mdns.
+    /// Agent version sent by identify. The node name is sent if `None`.
+    pub agent_version: Option<String>,
+    /// Security protocol.
+    pub security: Security,
+    /// Only peers with the same pre-shared key can connect if set. The key
+    /// encrypts connections before the security protocol is negotiated, so
+    /// nodes of a private network can't connect to public IPFS nodes.
+    pub psk: Option<PreSharedKey>,
+    /// Preferred stream multiplexer.
+    pub multiplexer: Multiplexer,
+    /// Time to negotiate the security protocol and the multiplexer of a new
+    /// connection, after which it is dropped. Slow links may need more.
+    pub transport_timeout: Duration,
+    /// Enable mdns discovery of peers on the local network.
     pub enable_mdns: bool,
     /// Enable ping.
     pub enable_ping: bool,
+    /// Enable gossipsub pubsub.
+    pub enable_pubsub: bool,
+    /// Time between pings on an idle connection.
+    pub ping_interval: Duration,
+    /// Time to wait for a pong before the ping fails.
+    pub ping_timeout: Duration,
+    /// Number of consecutive failed pings before the connection is closed.
+    pub ping_max_failures: NonZeroU32,
+    /// Enable the websocket transport for `/ws` addresses.
+    pub enable_websocket: bool,
+    /// Use an in-process transport instead of TCP, which only listens on and
+    /// dials `/memory/<port>` addresses. Nodes in the same process connect
+    /// without sockets, which makes tests deterministic.
+    pub memory_transport: bool,
     /// Should we insert non-global addresses into the DHT?
     pub allow_non_globals_in_dht: bool,
+    /// Maximum number of connected peers. While it's reached providers of
+    /// wanted blocks aren't dialed. Unlimited if `None`.
+    pub max_connections: Option<usize>,
+    /// Maximum number of simultaneous outgoing connection attempts.
+    pub max_pending_outgoing: Option<usize>,
+    /// Maximum number of established connections to a single peer.
+    pub max_established_per_peer: Option<usize>,
+    /// Connections to peers without bitswap, DHT or pubsub activity for this
+    /// long are closed, pings don't count as activity. Activity is tracked
+    /// per peer: the wants and blocks exchanged with it and its answers to
+    /// our DHT queries. Peers that want blocks from us are exempt. Never if
+    /// `None`.
+    pub idle_connection_timeout: Option<Duration>,
+    /// Interval at which provider records of public blocks are published
+    /// again, as they expire in the DHT. This replaces the republishing of
+    /// kademlia, so that blocks stored before a restart are provided too.
+    pub reprovide_interval: Duration,
+    /// Only provide the blocks marked with `set_root` in the DHT. The other
+    /// public blocks are still sent to connected peers that want them, as
+    /// peers usually find the root of a DAG and fetch the rest over bitswap.
+    pub provide_roots_only: bool,
+    /// Time after which the provider records stored by this node expire,
+    /// both our own and the ones other peers publish to us. Never if `None`.
+    ///
+    /// Other peers expire our records according to their own config, 24
+    /// hours for go-ipfs, so `reprovide_interval` should be well below that.
+    /// The ttl should be well above `reprovide_interval`, otherwise our
+    /// records expire before they are published again.
+    pub provider_record_ttl: Option<Duration>,
+    /// Number of peers closest to a key that DHT queries look for and records
+    /// are stored on. Small networks may want fewer than the default of 20.
+    pub dht_replication_factor: NonZeroUsize,
+    /// Number of peers a DHT query contacts at the same time.
+    pub dht_parallelism: NonZeroUsize,
+    /// Time after which a DHT query, like a provider lookup, fails.
+    pub dht_query_timeout: Duration,
+    /// Prepended to the DHT and gossipsub protocol names, for example
+    /// `/my-app`, to run a private network whose nodes only route to each
+    /// other. All nodes of the network need to use the same prefix. Bitswap
+    /// can't be namespaced, use `allowed_peers` to keep other peers out.
+    /// Empty for the public IPFS network.
+    pub protocol_prefix: String,
+    /// Timeout and retries of wants.
+    pub want_policy: WantPolicy,
+    /// Only these peers are accepted if set. Other peers are disconnected
+    /// when they connect and aren't dialed as providers.
+    pub allowed_peers: Option<HashSet<PeerId>>,
+    /// Peers we never connect to.
+    pub denied_peers: HashSet<PeerId>,
+    /// Maximum number of block bytes per second sent in response to wants.
+    /// Blocks over the budget are queued and peers take turns. Unlimited if
+    /// `None`.
+    pub max_send_rate: Option<NonZeroU64>,
+    /// Maximum number of blocks queued for a peer by `max_send_rate`. Wants
+    /// of a peer with this many queued blocks are ignored, so a peer can't
+    /// fill the queue. Unlimited if `None`.
+    pub max_queued_sends: Option<NonZeroUsize>,
+    /// Number of wants, provides and roots buffered between the storage and
+    /// the network. When the network falls further behind, the events are
+    /// dropped and it reads the wants and public blocks from the storage
+    /// again. Missed unprovides stay in the DHT until the records expire.
+    pub storage_events_capacity: usize,
 }
 
 impl NetworkConfig {
@@ -27,12 +239,43 @@ impl NetworkConfig {
     pub fn new() -> Self {
         Self {
             listen_addresses: vec!["/ip4/0.0.0.0/tcp/0".parse().unwrap()],
+            relisten: true,
             public_addresses: vec![],
-            boot_nodes: vec![],
+            boot_nodes: ipfs_boot_nodes(),
+            redial_backoff: Duration::from_secs(1),
+            max_redial_backoff: Duration::from_secs(5 * 60),
             enable_mdns: true,
             enable_ping: true,
+            enable_pubsub: false,
+            ping_interval: Duration::from_secs(15),
+            ping_timeout: Duration::from_secs(20),
+            ping_max_failures: NonZeroU32::new(3).unwrap(),
+            enable_websocket: false,
+            memory_transport: false,
             allow_non_globals_in_dht: false,
+            max_connections: None,
+            max_pending_outgoing: None,
+            max_established_per_peer: None,
+            idle_connection_timeout: None,
+            reprovide_interval: Duration::from_secs(12 * 60 * 60),
+            provide_roots_only: false,
+            provider_record_ttl: Some(Duration::from_secs(24 * 60 * 60)),
+            dht_replication_factor: NonZeroUsize::new(20).unwrap(),
+            dht_parallelism: NonZeroUsize::new(3).unwrap(),
+            dht_query_timeout: Duration::from_secs(60),
+            protocol_prefix: String::new(),
+            want_policy: Default::default(),
+            allowed_peers: None,
+            denied_peers: Default::default(),
+            max_send_rate: None,
+            max_queued_sends: None,
+            storage_events_capacity: 1024,
             node_key: Keypair::generate_ed25519(),
+            security: Security::Noise,
+            psk: None,
+            multiplexer: Multiplexer::Yamux,
+            transport_timeout: Duration::from_secs(20),
+            agent_version: None,
             node_name: names::Generator::with_naming(names::Name::Numbered)
                 .next()
                 .unwrap(),
@@ -43,10 +286,35 @@ impl NetworkConfig {
     pub fn new_local() -> Self {
         let mut config = Self::new();
         config.listen_addresses = vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()];
+        config.boot_nodes = vec![];
         config.allow_non_globals_in_dht = true;
         config
     }
 
+    /// Creates a local network configuration with the memory transport,
+    /// listening on a random `/memory` port. Mdns is disabled, so nodes only
+    /// find each other through `boot_nodes` or `Store::connect`.
+    ///
+    /// ```no_run
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    /// use ipfs_embed::{Config, Ipfs, NetworkConfig};
+    /// let mut config = Config::from_path_local("/tmp/a")?;
+    /// config.network = NetworkConfig::new_memory();
+    /// let a = Ipfs::new(config)?;
+    /// let mut config = Config::from_path_local("/tmp/b")?;
+    /// config.network = NetworkConfig::new_memory();
+    /// config.network.boot_nodes = vec![(a.address().clone(), a.peer_id().clone())];
+    /// let b = Ipfs::new(config)?;
+    /// # Ok(()) }
+    /// ```
+    pub fn new_memory() -> Self {
+        let mut config = Self::new_local();
+        config.listen_addresses = vec!["/memory/0".parse().unwrap()];
+        config.enable_mdns = false;
+        config.memory_transport = true;
+        config
+    }
+
     /// The public node key.
     pub fn public(&self) -> PublicKey {
         self.node_key.public()
@@ -63,3 +331,51 @@ impl Default for NetworkConfig {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_provider_record_ttl() {
+        let config = NetworkConfig::new();
+        assert!(config.provider_record_ttl.unwrap() > config.reprovide_interval);
+    }
+
+    #[test]
+    fn test_load_or_create_keypair() {
+        let tmp = TempDir::new("").unwrap();
+        let path = tmp.path().join("key");
+        let key1 = load_or_create_keypair(&path).unwrap();
+        let key2 = load_or_create_keypair(&path).unwrap();
+        assert_eq!(key1.public(), key2.public());
+
+        std::fs::write(&path, b"invalid").unwrap();
+        match load_or_create_keypair(&path) {
+            Err(err) => assert_eq!(err.kind(), io::ErrorKind::InvalidData),
+            Ok(_) => panic!("expected invalid key"),
+        }
+    }
+
+    #[test]
+    fn test_load_psk() {
+        let tmp = TempDir::new("").unwrap();
+        let path = tmp.path().join("swarm.key");
+        let psk = PreSharedKey::new([7; 32]);
+        std::fs::write(&path, psk.to_string()).unwrap();
+        assert_eq!(load_psk(&path).unwrap(), psk);
+
+        std::fs::write(&path, b"invalid").unwrap();
+        assert_eq!(
+            load_psk(&path).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_ipfs_boot_nodes() {
+        assert!(!ipfs_boot_nodes().is_empty());
+        assert!(NetworkConfig::new_local().boot_nodes.is_empty());
+    }
+}
@@ -0,0 +1,154 @@
+use async_std::task;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use core::time::Duration;
+use libp2p::core::connection::ConnectionId;
+use libp2p::core::{Multiaddr, PeerId};
+use libp2p::swarm::protocols_handler::{DummyProtocolsHandler, ProtocolsHandler};
+use libp2p::swarm::{NetworkBehaviour, NetworkBehaviourAction, PollParameters};
+use std::collections::{HashMap, VecDeque};
+use std::time::Instant;
+
+type InEvent = <DummyProtocolsHandler as ProtocolsHandler>::InEvent;
+type OutEvent = <DummyProtocolsHandler as ProtocolsHandler>::OutEvent;
+
+/// Interval at which connected peers are checked for activity.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum IdleEvent {
+    /// A peer had no protocol activity for the idle timeout.
+    Idle(PeerId),
+}
+
+/// Reports connected peers without protocol activity.
+///
+/// Whether a connection is kept alive is decided by the protocol handlers,
+/// and a single handler that wants to keep it open, like the one of
+/// gossipsub, keeps it open. So idle peers are reported instead and the
+/// network closes their connections.
+pub struct IdleConnections {
+    /// Disabled if `None`.
+    timeout: Option<Duration>,
+    /// Last activity of the connected peers.
+    activity: HashMap<PeerId, Instant>,
+    timer: Pin<Box<dyn Future<Output = ()> + Send>>,
+    events: VecDeque<NetworkBehaviourAction<InEvent, IdleEvent>>,
+}
+
+impl IdleConnections {
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            timeout,
+            activity: Default::default(),
+            timer: Box::pin(task::sleep(IDLE_CHECK_INTERVAL)),
+            events: Default::default(),
+        }
+    }
+
+    /// Marks a connected peer as active.
+    pub fn record_activity(&mut self, peer_id: &PeerId) {
+        if let Some(last) = self.activity.get_mut(peer_id) {
+            *last = Instant::now();
+        }
+    }
+
+    /// Returns the peers that were idle for the timeout at `now`. Their
+    /// activity is reset, so that they are reported once per timeout.
+    fn idle_peers(&mut self, now: Instant) -> Vec<PeerId> {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return vec![],
+        };
+        let mut idle = vec![];
+        for (peer_id, last) in &mut self.activity {
+            if now.saturating_duration_since(*last) >= timeout {
+                idle.push(peer_id.clone());
+                *last = now;
+            }
+        }
+        idle
+    }
+}
+
+impl NetworkBehaviour for IdleConnections {
+    type ProtocolsHandler = DummyProtocolsHandler;
+    type OutEvent = IdleEvent;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        Default::default()
+    }
+
+    fn addresses_of_peer(&mut self, _peer_id: &PeerId) -> Vec<Multiaddr> {
+        Default::default()
+    }
+
+    fn inject_connected(&mut self, peer_id: &PeerId) {
+        self.activity.insert(peer_id.clone(), Instant::now());
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        self.activity.remove(peer_id);
+    }
+
+    fn inject_event(&mut self, _peer_id: PeerId, _connection: ConnectionId, event: OutEvent) {
+        match event {}
+    }
+
+    fn poll(
+        &mut self,
+        ctx: &mut Context,
+        _: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<InEvent, IdleEvent>> {
+        if self.timeout.is_some() {
+            while self.timer.as_mut().poll(ctx).is_ready() {
+                self.timer = Box::pin(task::sleep(IDLE_CHECK_INTERVAL));
+                for peer_id in self.idle_peers(Instant::now()) {
+                    self.events
+                        .push_back(NetworkBehaviourAction::GenerateEvent(IdleEvent::Idle(
+                            peer_id,
+                        )));
+                }
+            }
+        }
+        if let Some(event) = self.events.pop_front() {
+            Poll::Ready(event)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idle_peers() {
+        let timeout = Duration::from_secs(10);
+        let mut idle = IdleConnections::new(Some(timeout));
+        let a = PeerId::random();
+        let b = PeerId::random();
+        idle.inject_connected(&a);
+        let start = Instant::now();
+        assert!(idle.idle_peers(start).is_empty());
+        assert_eq!(idle.idle_peers(start + timeout), vec![a.clone()]);
+        // reported once per timeout
+        assert!(idle.idle_peers(start + timeout).is_empty());
+
+        idle.inject_connected(&b);
+        idle.record_activity(&a);
+        idle.inject_disconnected(&b);
+        assert!(idle.idle_peers(start + timeout).is_empty());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut idle = IdleConnections::new(None);
+        idle.inject_connected(&PeerId::random());
+        assert!(idle
+            .idle_peers(Instant::now() + Duration::from_secs(3600))
+            .is_empty());
+    }
+}
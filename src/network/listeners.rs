@@ -0,0 +1,82 @@
+use core::task::{Context, Poll};
+use libp2p::core::connection::{ConnectionId, ListenerId};
+use libp2p::core::{Multiaddr, PeerId};
+use libp2p::swarm::protocols_handler::{DummyProtocolsHandler, ProtocolsHandler};
+use libp2p::swarm::{NetworkBehaviour, NetworkBehaviourAction, PollParameters};
+use std::collections::{HashMap, VecDeque};
+
+type InEvent = <DummyProtocolsHandler as ProtocolsHandler>::InEvent;
+type OutEvent = <DummyProtocolsHandler as ProtocolsHandler>::OutEvent;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ListenersEvent {
+    /// A listener closed, with the address it was created with and the error
+    /// that closed it.
+    Closed(Multiaddr, Option<String>),
+}
+
+/// Keeps track of the addresses the listeners were created with.
+///
+/// The swarm only reports the addresses a listener was bound to when it
+/// closes, which for an unspecified ip or port aren't the ones it was
+/// created with.
+#[derive(Default)]
+pub struct Listeners {
+    listeners: HashMap<ListenerId, Multiaddr>,
+    events: VecDeque<NetworkBehaviourAction<InEvent, ListenersEvent>>,
+}
+
+impl Listeners {
+    /// Records the address a listener was created with.
+    pub fn add(&mut self, id: ListenerId, address: Multiaddr) {
+        self.listeners.insert(id, address);
+    }
+}
+
+impl NetworkBehaviour for Listeners {
+    type ProtocolsHandler = DummyProtocolsHandler;
+    type OutEvent = ListenersEvent;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        Default::default()
+    }
+
+    fn addresses_of_peer(&mut self, _peer_id: &PeerId) -> Vec<Multiaddr> {
+        Default::default()
+    }
+
+    fn inject_connected(&mut self, _peer_id: &PeerId) {}
+
+    fn inject_disconnected(&mut self, _peer_id: &PeerId) {}
+
+    fn inject_event(&mut self, _peer_id: PeerId, _connection: ConnectionId, event: OutEvent) {
+        match event {}
+    }
+
+    fn inject_listener_error(&mut self, id: ListenerId, err: &(dyn std::error::Error + 'static)) {
+        if let Some(address) = self.listeners.get(&id) {
+            log::warn!("listener on {} failed: {}", address, err);
+        }
+    }
+
+    fn inject_listener_closed(&mut self, id: ListenerId, reason: Result<(), &std::io::Error>) {
+        if let Some(address) = self.listeners.remove(&id) {
+            let reason = reason.err().map(|err| err.to_string());
+            self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                ListenersEvent::Closed(address, reason),
+            ));
+        }
+    }
+
+    fn poll(
+        &mut self,
+        _ctx: &mut Context,
+        _: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<InEvent, ListenersEvent>> {
+        if let Some(event) = self.events.pop_front() {
+            Poll::Ready(event)
+        } else {
+            Poll::Pending
+        }
+    }
+}
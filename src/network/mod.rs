@@ -1,82 +1,567 @@
 use async_std::prelude::*;
-use async_std::task::{Context, Poll};
+use async_std::task::{self, Context, Poll};
 use core::marker::PhantomData;
 use core::pin::Pin;
+use futures::channel::{mpsc, oneshot};
+use futures::future::FutureExt;
+use futures::stream::{Fuse, FuturesUnordered, StreamExt};
 use libipld::block::Block;
+use libipld::cid::Cid;
 use libipld::codec::Codec;
 use libipld::error::Result;
 use libipld::multihash::MultihashDigest;
-use libp2p::core::transport::upgrade::Version;
-use libp2p::core::transport::Transport;
-use libp2p::core::Multiaddr;
-use libp2p::mplex::MplexConfig;
-use libp2p::secio::SecioConfig;
-use libp2p::swarm::{Swarm, SwarmEvent};
-use libp2p::tcp::TcpConfig;
-//use libp2p::yamux::Config as YamuxConfig;
-use std::time::Duration;
+use libp2p::core::multiaddr::Protocol;
+use libp2p::core::{Multiaddr, PeerId};
+use libp2p::swarm::{Swarm, SwarmBuilder, SwarmEvent};
+use libp2p_bitswap::Priority;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+mod address_book;
+mod bandwidth;
 mod behaviour;
 mod config;
+mod idle;
+mod listeners;
+mod peer_filter;
+mod providers;
+mod record;
+mod redial;
+mod send_queue;
+mod transport;
 
 use crate::storage::{
     NetworkEvent as StorageEvent, NetworkSubscriber as StorageSubscriber, Storage,
 };
+pub use address_book::ConnectionFailed;
+use bandwidth::BANDWIDTH_SAMPLE_INTERVAL;
+pub use bandwidth::{Bandwidth, BandwidthStats};
 use behaviour::NetworkBackendBehaviour;
-pub use behaviour::NetworkEvent;
-pub use config::NetworkConfig;
+pub use behaviour::{
+    BitswapStats, ConnectionEvent, NetworkEvent, PeerInfo, PubsubDisabled, PubsubMessage,
+    PubsubPublishError, WantInfo, LIBP2P_VERSION, PROTOCOLS, PROTOCOL_VERSION,
+};
+pub use config::{
+    ipfs_boot_nodes, load_or_create_keypair, load_psk, Multiplexer, NetworkConfig, Security,
+    WantPolicy,
+};
+pub use record::{DhtRecord, InvalidRecord, RecordNotFound};
+use send_queue::SendQueue;
+
+/// Requests from the store to the network.
+#[derive(Debug)]
+pub enum NetworkCommand {
+    /// Lists the connected peers with the addresses of their connections.
+    Peers(oneshot::Sender<Vec<PeerInfo>>),
+    /// Lists the addresses we listen on and the external addresses.
+    Addresses(oneshot::Sender<Vec<Multiaddr>>),
+    /// Dials an address and returns the peer id of the remote.
+    Connect(Multiaddr, oneshot::Sender<Result<PeerId>>),
+    /// Returns the bitswap counters.
+    BitswapStats(oneshot::Sender<BitswapStats>),
+    /// Lists the outstanding wants.
+    Wantlist(oneshot::Sender<Vec<WantInfo>>),
+    /// Looks up the providers of a block in the DHT.
+    Providers(Cid, oneshot::Sender<Vec<PeerId>>),
+    /// Publishes a provider record for a block.
+    Provide(Cid, oneshot::Sender<Result<()>>),
+    /// Stores a signed record in the DHT.
+    DhtPut(Vec<u8>, Vec<u8>, oneshot::Sender<Result<()>>),
+    /// Looks up the signed records of a key in the DHT.
+    DhtGet(Vec<u8>, oneshot::Sender<Result<Vec<DhtRecord>>>),
+    /// Subscribes to a pubsub topic. Messages are sent to the channel.
+    Subscribe(
+        String,
+        mpsc::UnboundedSender<PubsubMessage>,
+        oneshot::Sender<Result<()>>,
+    ),
+    /// Publishes a message on a pubsub topic.
+    Publish(String, Vec<u8>, oneshot::Sender<Result<()>>),
+    /// Sends connection events to the channel.
+    WatchConnections(mpsc::UnboundedSender<ConnectionEvent>),
+    /// Adds a peer to the denylist and disconnects it.
+    Ban(PeerId),
+    /// Removes a peer from the denylist.
+    Unban(PeerId),
+    /// Stops dialing a boot node and removes it from the DHT.
+    RemoveBootNode(PeerId),
+    /// Replies once the DHT was bootstrapped.
+    Bootstrapped(oneshot::Sender<()>),
+    /// Stops the network. The reply is sent once the storage was flushed.
+    Shutdown(oneshot::Sender<()>),
+}
+
+/// Timers of an outstanding want, identified by the id of the want.
+enum WantTimer {
+    Timeout(Cid, u64),
+    Retry(Cid, u64),
+    Fallback(Cid, u64),
+}
+
+/// State of an outstanding want.
+struct Want {
+    id: u64,
+    retries: u32,
+    priority: Priority,
+    /// Set once the want was sent, it may be queued by `max_wants_in_flight`.
+    sent: bool,
+    /// Set once the want was sent to the connected peers again, after which
+    /// it's cancelled by the `Fallback` timer.
+    asked_connected: bool,
+}
 
 pub struct Network<C: Codec, M: MultihashDigest> {
     _marker: PhantomData<C>,
     swarm: Swarm<NetworkBackendBehaviour<M>>,
     storage: Storage,
     subscriber: StorageSubscriber,
+    commands: Fuse<mpsc::UnboundedReceiver<NetworkCommand>>,
+    reprovide_interval: Duration,
+    reprovide: Pin<Box<dyn Future<Output = ()> + Send>>,
+    provide_roots_only: bool,
+    bandwidth: Arc<Bandwidth>,
+    sample: Pin<Box<dyn Future<Output = ()> + Send>>,
+    last_sample: Instant,
+    /// Blocks waiting for the send rate limit.
+    send_queue: Option<SendQueue>,
+    send_timer: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+    /// Subscribers of pubsub topics.
+    subscriptions: HashMap<String, Vec<mpsc::UnboundedSender<PubsubMessage>>>,
+    connection_watchers: Vec<mpsc::UnboundedSender<ConnectionEvent>>,
+    /// Set once the DHT was bootstrapped, or right away without boot nodes.
+    bootstrapped: bool,
+    bootstrap_replies: Vec<oneshot::Sender<()>>,
+    want_policy: WantPolicy,
+    /// Listen again when a listener closes.
+    relisten: bool,
+    wants: HashMap<Cid, Want>,
+    /// Wants that weren't sent yet, in the order they are sent.
+    queued_wants: VecDeque<Cid>,
+    /// Number of wants that were sent.
+    sent_wants: usize,
+    next_want_id: u64,
+    timers: FuturesUnordered<Pin<Box<dyn Future<Output = WantTimer> + Send>>>,
+    /// Set once a shutdown was requested.
+    shutdown: bool,
+    shutdown_replies: Vec<oneshot::Sender<()>>,
+    flush: Option<Pin<Box<dyn Future<Output = Result<()>> + Send>>>,
+}
+
+/// Returns true if a listener on `listen` can report `addr`, for example
+/// `/ip4/0.0.0.0/tcp/0` reports `/ip4/127.0.0.1/tcp/4001`.
+fn listens_on(listen: &Multiaddr, addr: &Multiaddr) -> bool {
+    let mut addr = addr.iter();
+    for protocol in listen.iter() {
+        let matches = match (protocol, addr.next()) {
+            (_, None) => false,
+            (Protocol::Ip4(ip), Some(Protocol::Ip4(_))) if ip.is_unspecified() => true,
+            (Protocol::Ip6(ip), Some(Protocol::Ip6(_))) if ip.is_unspecified() => true,
+            (Protocol::Tcp(0), Some(Protocol::Tcp(_))) => true,
+            (Protocol::Memory(0), Some(Protocol::Memory(_))) => true,
+            (protocol, Some(other)) => protocol == other,
+        };
+        if !matches {
+            return false;
+        }
+    }
+    addr.next().is_none()
 }
 
 impl<C: Codec, M: MultihashDigest> Network<C, M> {
-    pub async fn new(config: NetworkConfig, storage: Storage) -> Result<(Self, Multiaddr)> {
-        let transport = TcpConfig::new()
-            .nodelay(true)
-            .upgrade(Version::V1)
-            .authenticate(SecioConfig::new(config.node_key.clone()))
-            .multiplex(MplexConfig::new())
-            .timeout(Duration::from_secs(20));
+    /// Creates the network and waits until each listen address reported at
+    /// least one address, which are returned. Fails if a listener fails or
+    /// closes before it reported an address, or if no listen address is
+    /// configured.
+    pub async fn new(
+        config: NetworkConfig,
+        storage: Storage,
+        commands: mpsc::UnboundedReceiver<NetworkCommand>,
+    ) -> Result<(Self, Vec<Multiaddr>)> {
+        if config.listen_addresses.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "no listen addresses configured",
+            )
+            .into());
+        }
+        let (transport, sinks) = transport::build_transport(&config)?;
 
         let peer_id = config.peer_id();
         let behaviour = NetworkBackendBehaviour::new(config.clone())?;
-        let mut swarm = Swarm::new(transport, behaviour, peer_id);
+        let mut builder = SwarmBuilder::new(transport, behaviour, peer_id);
+        if let Some(n) = config.max_pending_outgoing {
+            builder = builder.outgoing_connection_limit(n);
+        }
+        if let Some(n) = config.max_established_per_peer {
+            builder = builder.peer_connection_limit(n);
+        }
+        let mut swarm = builder.build();
+        let max_queued_sends = config.max_queued_sends.map(|max| max.get());
+        let bootstrapped = config.boot_nodes.is_empty();
+        let mut pending = config.listen_addresses.clone();
         for addr in config.listen_addresses {
-            Swarm::listen_on(&mut swarm, addr)?;
+            let id = Swarm::listen_on(&mut swarm, addr.clone())?;
+            swarm.add_listener(id, addr);
         }
         for addr in config.public_addresses {
             Swarm::add_external_address(&mut swarm, addr);
         }
+        let denied: Vec<_> = swarm.denied_peers().cloned().collect();
+        for peer_id in denied {
+            Swarm::ban_peer_id(&mut swarm, peer_id);
+        }
 
-        let addr = loop {
+        // listeners on unspecified addresses report an address per interface
+        let mut addrs = vec![];
+        while !pending.is_empty() {
             match swarm.next_event().await {
-                SwarmEvent::NewListenAddr(addr) => break addr,
+                SwarmEvent::NewListenAddr(addr) => {
+                    if let Some(i) = pending.iter().position(|listen| listens_on(listen, &addr)) {
+                        pending.remove(i);
+                    }
+                    addrs.push(addr);
+                }
                 SwarmEvent::ListenerClosed { reason, .. } => reason?,
+                // a listener that closed before reporting an address never
+                // becomes ready
+                SwarmEvent::Behaviour(NetworkEvent::ListenerClosed(address, _)) => {
+                    if pending.contains(&address) {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::AddrNotAvailable,
+                            format!("listener on {} closed before it was ready", address),
+                        )
+                        .into());
+                    }
+                    log::warn!("listener on {} closed", address);
+                }
                 _ => {}
             }
-        };
+        }
 
-        let subscriber = storage.watch_network();
+        let subscriber = storage.watch_network()?;
         Ok((
             Self {
                 _marker: PhantomData,
                 swarm,
                 storage,
                 subscriber,
+                commands: commands.fuse(),
+                reprovide_interval: config.reprovide_interval,
+                reprovide: Box::pin(task::sleep(config.reprovide_interval)),
+                provide_roots_only: config.provide_roots_only,
+                bandwidth: Arc::new(Bandwidth::new(sinks)),
+                sample: Box::pin(task::sleep(BANDWIDTH_SAMPLE_INTERVAL)),
+                last_sample: Instant::now(),
+                send_queue: config
+                    .max_send_rate
+                    .map(|rate| SendQueue::new(rate.get(), max_queued_sends, Instant::now())),
+                send_timer: None,
+                subscriptions: Default::default(),
+                connection_watchers: Default::default(),
+                bootstrapped,
+                bootstrap_replies: Default::default(),
+                want_policy: config.want_policy,
+                relisten: config.relisten,
+                wants: Default::default(),
+                queued_wants: Default::default(),
+                sent_wants: 0,
+                next_want_id: 0,
+                timers: Default::default(),
+                shutdown: false,
+                shutdown_replies: Default::default(),
+                flush: None,
             },
-            addr,
+            addrs,
         ))
     }
+
+    /// Sends a connection event to the watchers, dropping the watchers whose
+    /// stream was dropped.
+    fn notify_connection(&mut self, event: ConnectionEvent) {
+        self.connection_watchers
+            .retain(|watcher| watcher.unbounded_send(event.clone()).is_ok());
+    }
+
+    /// Makes the future resolve. Outstanding wants are cancelled, so that
+    /// pending gets fail, and the storage is flushed first.
+    pub fn shutdown(&mut self) {
+        self.shutdown = true;
+    }
+
+    fn poll_shutdown(&mut self, ctx: &mut Context) -> Poll<()> {
+        if self.flush.is_none() {
+            log::info!("shutting down");
+            self.queued_wants.clear();
+            self.sent_wants = 0;
+            let wants: Vec<_> = self.wants.drain().map(|(cid, _)| cid).collect();
+            for cid in wants {
+                self.swarm.cancel_block(&cid);
+                if let Err(err) = self.storage.cancel(&cid) {
+                    log::error!("failed to cancel get {:?}", err);
+                }
+            }
+            let storage = self.storage.clone();
+            self.flush = Some(async move { storage.flush().await }.boxed());
+        }
+        if let Some(flush) = self.flush.as_mut() {
+            match flush.as_mut().poll(ctx) {
+                Poll::Ready(Err(err)) => log::error!("failed to flush storage {:?}", err),
+                Poll::Ready(Ok(())) => {}
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        for tx in self.shutdown_replies.drain(..) {
+            tx.send(()).ok();
+        }
+        Poll::Ready(())
+    }
+
+    /// Returns the bandwidth counters of the transport.
+    pub fn bandwidth(&self) -> Arc<Bandwidth> {
+        self.bandwidth.clone()
+    }
+
+    /// Sends a block, or queues it if the send rate limit is reached.
+    fn send_block(&mut self, peer_id: PeerId, cid: Cid, data: Box<[u8]>) {
+        if data.len() > self.storage.max_block_size() {
+            log::warn!(
+                "not sending block {} of {} bytes to {}, it exceeds the block size limit",
+                cid.to_string(),
+                data.len(),
+                peer_id
+            );
+            return;
+        }
+        match self.send_queue.as_mut() {
+            Some(queue) => {
+                if !queue.push(peer_id.clone(), cid.clone(), data) {
+                    log::debug!(
+                        "not sending block {} to {}, too many blocks are queued for it",
+                        cid.to_string(),
+                        peer_id
+                    );
+                }
+            }
+            None => self.swarm.send_block(&peer_id, cid, data),
+        }
+    }
+
+    /// Sends the queued blocks the budget allows and schedules a wakeup for
+    /// the rest.
+    fn send_queued(&mut self, ctx: &mut Context) {
+        if let Some(timer) = self.send_timer.as_mut() {
+            if timer.as_mut().poll(ctx).is_pending() {
+                return;
+            }
+            self.send_timer = None;
+        }
+        let queue = match self.send_queue.as_mut() {
+            Some(queue) => queue,
+            None => return,
+        };
+        while let Some((peer_id, cid, data)) = queue.pop(Instant::now()) {
+            self.swarm.send_block(&peer_id, cid, data);
+        }
+        if let Some(delay) = queue.delay() {
+            let mut timer = task::sleep(delay).boxed();
+            if timer.as_mut().poll(ctx).is_pending() {
+                self.send_timer = Some(timer);
+            } else {
+                ctx.waker().wake_by_ref();
+            }
+        }
+    }
+
+    fn want_block(&mut self, cid: Cid, priority: Priority) {
+        match self.wants.get_mut(&cid) {
+            Some(want) => {
+                want.priority = priority;
+                // the priority of queued wants is sent with them
+                if want.sent {
+                    self.swarm.want_block(cid, priority);
+                }
+            }
+            None => {
+                let id = self.next_want_id;
+                self.next_want_id += 1;
+                if let Some(timeout) = self.want_policy.timeout {
+                    let timer = WantTimer::Timeout(cid.clone(), id);
+                    self.timers
+                        .push(task::sleep(timeout).map(move |_| timer).boxed());
+                }
+                let want = Want {
+                    id,
+                    retries: 0,
+                    priority,
+                    sent: false,
+                    asked_connected: false,
+                };
+                self.wants.insert(cid.clone(), want);
+                self.queued_wants.push_back(cid);
+                self.send_wants();
+            }
+        }
+    }
+
+    /// Sends queued wants while fewer than `max_wants_in_flight` wants are
+    /// outstanding.
+    fn send_wants(&mut self) {
+        let max = self.want_policy.max_wants_in_flight.map(|max| max.get());
+        while max.is_none_or(|max| self.sent_wants < max) {
+            let cid = match self.queued_wants.pop_front() {
+                Some(cid) => cid,
+                None => break,
+            };
+            // the want may have been removed while queued
+            if let Some(want) = self.wants.get_mut(&cid) {
+                want.sent = true;
+                self.sent_wants += 1;
+                let priority = want.priority;
+                self.swarm.want_block(cid, priority);
+            }
+        }
+    }
+
+    /// Removes a want and sends the next queued one in its place. Returns
+    /// false if there was no want for the block.
+    fn remove_want(&mut self, cid: &Cid) -> bool {
+        match self.wants.remove(cid) {
+            Some(want) => {
+                if want.sent {
+                    self.sent_wants -= 1;
+                    self.send_wants();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sends a want to the connected peers again and schedules its
+    /// cancellation. Returns false without a `connected_peers_fallback` or
+    /// if the connected peers were already asked.
+    fn ask_connected_peers(&mut self, cid: &Cid) -> bool {
+        let wait = match self.want_policy.connected_peers_fallback {
+            Some(wait) => wait,
+            None => return false,
+        };
+        let want = match self.wants.get_mut(cid) {
+            Some(want) if want.sent && !want.asked_connected => want,
+            _ => return false,
+        };
+        want.asked_connected = true;
+        log::debug!("asking connected peers for {}", cid.to_string());
+        let timer = WantTimer::Fallback(cid.clone(), want.id);
+        let priority = want.priority;
+        self.timers
+            .push(task::sleep(wait).map(move |_| timer).boxed());
+        // the DHT isn't searched again, so the timer is the deadline
+        self.swarm.send_want(cid.clone(), priority);
+        true
+    }
+
+    /// Fails the gets of a block that no peer sent.
+    fn no_providers(&mut self, cid: &Cid) {
+        log::info!("no providers for {}", cid.to_string());
+        self.swarm.cancel_block(cid);
+        if let Err(err) = self.storage.cancel(cid) {
+            log::error!("failed to cancel get {:?}", err);
+        }
+    }
+
+    /// Schedules another provider search. Returns false when the retries
+    /// of the want are used up.
+    fn retry_want(&mut self, cid: &Cid) -> bool {
+        let want = match self.wants.get_mut(cid) {
+            Some(want) if want.retries < self.want_policy.retries => want,
+            _ => return false,
+        };
+        want.retries += 1;
+        log::debug!("searching providers of {} again", cid.to_string());
+        let timer = WantTimer::Retry(cid.clone(), want.id);
+        self.timers.push(
+            task::sleep(self.want_policy.backoff)
+                .map(move |_| timer)
+                .boxed(),
+        );
+        true
+    }
+
+    fn is_current(&self, cid: &Cid, id: u64) -> bool {
+        self.wants
+            .get(cid)
+            .map(|want| want.id == id)
+            .unwrap_or(false)
+    }
+
+    /// Checks if a public block gets a provider record, which with
+    /// `provide_roots_only` only roots do.
+    fn is_provided(&self, cid: &Cid) -> Result<bool> {
+        Ok(!self.provide_roots_only || self.storage.is_root(cid)?)
+    }
+
+    /// Catches up with the storage after storage events were dropped. Wants
+    /// are added to `wants` and cancelled to match the storage, and the
+    /// public blocks are provided again.
+    fn resync(&mut self, wants: &mut Vec<(Cid, Priority)>) {
+        log::warn!("storage events were dropped, reading the storage again");
+        let mut wanted = HashSet::new();
+        for res in self.storage.wanted() {
+            match res {
+                Ok((cid, priority)) => {
+                    let pending = wants.iter().any(|(want, _)| *want == cid);
+                    if !pending && !self.wants.contains_key(&cid) {
+                        wants.push((cid.clone(), priority));
+                    }
+                    wanted.insert(cid);
+                }
+                Err(err) => log::error!("failed to read wants {:?}", err),
+            }
+        }
+        wants.retain(|(cid, _)| wanted.contains(cid));
+        let cancelled: Vec<_> = self
+            .wants
+            .keys()
+            .filter(|cid| !wanted.contains(*cid))
+            .cloned()
+            .collect();
+        for cid in cancelled {
+            self.remove_want(&cid);
+            self.swarm.cancel_block(&cid);
+        }
+        self.provide_public();
+    }
+
+    /// Publishes provider records for the public blocks that won't be
+    /// garbage collected.
+    fn provide_public(&mut self) {
+        if self.storage.is_offline() {
+            return;
+        }
+        for public in self.storage.public() {
+            let res = public.and_then(|cid| {
+                if self.is_provided(&cid)? && self.storage.is_live(&cid)? {
+                    self.swarm.provide_block(&cid)?;
+                }
+                Ok(())
+            });
+            if let Err(err) = res {
+                log::error!("error providing block {:?}", err);
+            }
+        }
+    }
 }
 
 impl<C: Codec, M: MultihashDigest> Future for Network<C, M> {
     type Output = ();
 
     fn poll(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        if self.shutdown {
+            return self.poll_shutdown(ctx);
+        }
+        // wants are collected so that higher priority wants are sent first.
+        let mut wants = Vec::new();
         loop {
             let event = match Pin::new(&mut self.subscriber).poll_next(ctx) {
                 Poll::Ready(Some(event)) => event,
@@ -85,30 +570,209 @@ impl<C: Codec, M: MultihashDigest> Future for Network<C, M> {
             };
             log::trace!("{:?}", event);
             match event {
-                StorageEvent::Want(cid) => self.swarm.want_block(cid, 1000),
-                StorageEvent::Cancel(cid) => self.swarm.cancel_block(&cid),
+                StorageEvent::Want(cid, priority) => wants.push((cid, priority)),
+                StorageEvent::Cancel(cid) => {
+                    wants.retain(|(want, _)| *want != cid);
+                    self.remove_want(&cid);
+                    self.swarm.cancel_block(&cid);
+                }
+                // blocks aren't announced offline
+                StorageEvent::Provide(_)
+                | StorageEvent::Unprovide(_)
+                | StorageEvent::Root(_)
+                | StorageEvent::Unroot(_)
+                    if self.storage.is_offline() => {}
                 StorageEvent::Provide(cid) => {
-                    if let Err(err) = match self.storage.get_local(&cid) {
-                        Ok(Some(block)) => self.swarm.provide_and_send_block(&cid, &block),
-                        _ => self.swarm.provide_block(&cid),
-                    } {
+                    let res = self.is_provided(&cid).and_then(|provided| {
+                        match self.storage.get_local(&cid) {
+                            Ok(Some(block)) if provided => {
+                                self.swarm.provide_and_send_block(&cid, &block)
+                            }
+                            _ if provided => self.swarm.provide_block(&cid),
+                            // other public blocks are only sent to peers that want them
+                            Ok(Some(block)) => {
+                                self.swarm.send_block_all(&cid, &block);
+                                Ok(())
+                            }
+                            _ => Ok(()),
+                        }
+                    });
+                    if let Err(err) = res {
                         log::error!("error providing block {:?}", err);
                     }
                 }
                 StorageEvent::Unprovide(cid) => self.swarm.unprovide_block(&cid),
+                // roots are already provided unless only roots are
+                StorageEvent::Root(cid) if self.provide_roots_only => {
+                    if let Err(err) = self.swarm.provide_block(&cid) {
+                        log::error!("error providing block {:?}", err);
+                    }
+                }
+                StorageEvent::Unroot(cid) if self.provide_roots_only => {
+                    self.swarm.unprovide_block(&cid)
+                }
+                StorageEvent::Root(_) | StorageEvent::Unroot(_) => {}
+                StorageEvent::Lagged => self.resync(&mut wants),
+            }
+        }
+        wants.sort_by(|(_, a), (_, b)| b.cmp(a));
+        for (cid, priority) in wants {
+            self.want_block(cid, priority);
+        }
+        loop {
+            let command = match Pin::new(&mut self.commands).poll_next(ctx) {
+                Poll::Ready(Some(command)) => command,
+                // the store was dropped, but gets may still be in progress
+                Poll::Ready(None) | Poll::Pending => break,
+            };
+            log::trace!("{:?}", command);
+            match command {
+                NetworkCommand::Peers(tx) => {
+                    tx.send(self.swarm.connected_peers()).ok();
+                }
+                NetworkCommand::Addresses(tx) => {
+                    let mut addresses: Vec<_> = Swarm::listeners(&self.swarm).cloned().collect();
+                    for address in Swarm::external_addresses(&self.swarm) {
+                        if !addresses.contains(address) {
+                            addresses.push(address.clone());
+                        }
+                    }
+                    tx.send(addresses).ok();
+                }
+                NetworkCommand::Connect(address, tx) => self.swarm.dial(address, tx),
+                NetworkCommand::BitswapStats(tx) => {
+                    tx.send(self.swarm.bitswap_stats()).ok();
+                }
+                NetworkCommand::Wantlist(tx) => {
+                    tx.send(self.swarm.wantlist()).ok();
+                }
+                NetworkCommand::Providers(cid, tx) => self.swarm.get_providers(&cid, tx),
+                NetworkCommand::Provide(_, tx) if self.storage.is_offline() => {
+                    tx.send(Ok(())).ok();
+                }
+                NetworkCommand::Provide(cid, tx) => {
+                    tx.send(self.swarm.provide_block(&cid)).ok();
+                }
+                NetworkCommand::DhtPut(key, value, tx) => self.swarm.put_record(key, value, tx),
+                NetworkCommand::DhtGet(key, tx) => self.swarm.get_record(&key, tx),
+                NetworkCommand::Subscribe(topic, subscriber, tx) => {
+                    let res = self.swarm.subscribe(&topic);
+                    if res.is_ok() {
+                        self.subscriptions
+                            .entry(topic)
+                            .or_default()
+                            .push(subscriber);
+                    }
+                    tx.send(res).ok();
+                }
+                NetworkCommand::Publish(topic, data, tx) => {
+                    tx.send(self.swarm.publish(&topic, data)).ok();
+                }
+                NetworkCommand::WatchConnections(watcher) => {
+                    self.connection_watchers.push(watcher);
+                }
+                NetworkCommand::Ban(peer_id) => {
+                    self.swarm.deny_peer(peer_id.clone());
+                    Swarm::ban_peer_id(&mut self.swarm, peer_id);
+                }
+                NetworkCommand::Unban(peer_id) => {
+                    self.swarm.undeny_peer(&peer_id);
+                    Swarm::unban_peer_id(&mut self.swarm, peer_id);
+                }
+                NetworkCommand::RemoveBootNode(peer_id) => {
+                    self.swarm.remove_boot_node(&peer_id);
+                }
+                NetworkCommand::Bootstrapped(tx) if self.bootstrapped => {
+                    tx.send(()).ok();
+                }
+                NetworkCommand::Bootstrapped(tx) => self.bootstrap_replies.push(tx),
+                NetworkCommand::Shutdown(tx) => {
+                    self.shutdown_replies.push(tx);
+                    self.shutdown();
+                }
             }
         }
+        if self.shutdown {
+            return self.poll_shutdown(ctx);
+        }
+        while let Poll::Ready(Some(timer)) = Pin::new(&mut self.timers).poll_next(ctx) {
+            match timer {
+                WantTimer::Timeout(cid, id) if self.is_current(&cid, id) => {
+                    log::info!("want {} timed out", cid.to_string());
+                    self.remove_want(&cid);
+                    self.swarm.cancel_block(&cid);
+                    if let Err(err) = self.storage.timeout(&cid) {
+                        log::error!("failed to cancel get {:?}", err);
+                    }
+                }
+                WantTimer::Retry(cid, id) if self.is_current(&cid, id) => {
+                    self.swarm.find_providers(cid);
+                }
+                WantTimer::Fallback(cid, id) if self.is_current(&cid, id) => {
+                    self.no_providers(&cid);
+                }
+                _ => {}
+            }
+        }
+        // provider records expire, so they are published again periodically.
+        while self.reprovide.as_mut().poll(ctx).is_ready() {
+            log::debug!("reproviding public blocks");
+            self.reprovide = Box::pin(task::sleep(self.reprovide_interval));
+            self.provide_public();
+        }
+        self.send_queued(ctx);
+        while self.sample.as_mut().poll(ctx).is_ready() {
+            self.sample = Box::pin(task::sleep(BANDWIDTH_SAMPLE_INTERVAL));
+            self.bandwidth.sample(self.last_sample.elapsed());
+            self.last_sample = Instant::now();
+        }
         // polling the swarm needs to happen last as calling methods on swarm can
         // make the swarm ready, but won't register a waker.
         loop {
-            let event = match Pin::new(&mut self.swarm).poll_next(ctx) {
-                Poll::Ready(Some(event)) => event,
-                Poll::Ready(None) => return Poll::Ready(()),
+            let poll = {
+                let next_event = self.swarm.next_event();
+                futures::pin_mut!(next_event);
+                next_event.poll(ctx)
+            };
+            let event = match poll {
+                Poll::Ready(SwarmEvent::Behaviour(event)) => event,
+                Poll::Ready(SwarmEvent::ConnectionEstablished {
+                    peer_id, endpoint, ..
+                }) => NetworkEvent::ConnectionEstablished(
+                    peer_id,
+                    endpoint.get_remote_address().clone(),
+                ),
+                Poll::Ready(SwarmEvent::ConnectionClosed { peer_id, cause, .. }) => {
+                    NetworkEvent::ConnectionClosed(peer_id, cause.map(|err| err.to_string()))
+                }
+                Poll::Ready(event) => {
+                    log::trace!("{:?}", event);
+                    continue;
+                }
                 Poll::Pending => break,
             };
             log::trace!("{:?}", event);
             match event {
-                NetworkEvent::ReceivedBlock(_, cid, data) => {
+                NetworkEvent::ReceivedBlock(peer_id, cid, data) => {
+                    if data.len() > self.storage.max_block_size() {
+                        log::warn!(
+                            "dropping block {} of {} bytes from {}, it exceeds the block size limit",
+                            cid.to_string(),
+                            data.len(),
+                            peer_id
+                        );
+                        continue;
+                    }
+                    // another peer was faster, so the insert and its metadata
+                    // updates are skipped.
+                    if let Ok(true) = self.storage.contains(&cid) {
+                        log::debug!("duplicate block {} from {}", cid.to_string(), peer_id);
+                        self.swarm.duplicate_block_received();
+                        if self.remove_want(&cid) {
+                            self.swarm.cancel_block(&cid);
+                        }
+                        continue;
+                    }
                     let block = Block::<C, M>::new(cid, data);
                     if let Err(err) = self.storage.insert(&block) {
                         log::error!("failed to insert received block {:?}", err);
@@ -117,30 +781,126 @@ impl<C: Codec, M: MultihashDigest> Future for Network<C, M> {
                 NetworkEvent::ReceivedWant(peer_id, cid) => match self.storage.get_local(&cid) {
                     Ok(Some(block)) => {
                         let data = block.to_vec().into_boxed_slice();
-                        self.swarm.send_block(&peer_id, cid, data)
+                        self.send_block(peer_id, cid, data);
+                        // queued blocks are sent on the next poll
+                        if self.send_queue.is_some() {
+                            ctx.waker().wake_by_ref();
+                        }
                     }
                     Ok(None) => log::trace!("don't have local block {}", cid.to_string()),
                     Err(err) => log::error!("failed to get local block {:?}", err),
                 },
-                NetworkEvent::Providers(_cid, providers) => {
-                    let peer_id = providers.into_iter().next().unwrap();
-                    self.swarm.connect(peer_id);
+                NetworkEvent::Providers(cid, providers) => {
+                    self.swarm.add_providers(cid, providers);
                 }
-                NetworkEvent::NoProviders(_cid) => {
-                    log::info!("TODO no providers");
-                    // abort get
+                NetworkEvent::NoProviders(cid) => {
+                    // the `Fallback` timer is the deadline of wants that were
+                    // sent to the connected peers again, other searches of
+                    // the DHT for the block may still end
+                    let asked = self.wants.get(&cid).map(|want| want.asked_connected);
+                    if asked == Some(true) {
+                        continue;
+                    }
+                    if self.retry_want(&cid) {
+                        // the timer needs to be polled to register the waker
+                        ctx.waker().wake_by_ref();
+                        continue;
+                    }
+                    if self.swarm.has_peers() && self.ask_connected_peers(&cid) {
+                        ctx.waker().wake_by_ref();
+                    } else {
+                        self.no_providers(&cid);
+                    }
                 }
-                NetworkEvent::BootstrapComplete => {
-                    for public in self.storage.public() {
-                        match public.map(|cid| self.swarm.provide_block(&cid)) {
-                            Ok(Ok(())) => {}
-                            Ok(Err(err)) => log::error!("error providing block {:?}", err),
-                            Err(err) => log::error!("error reading public blocks {:?}", err),
+                NetworkEvent::PeerDiscovered(peer_id, address) => {
+                    log::info!("discovered peer {} at {}", peer_id, address);
+                }
+                NetworkEvent::Identified(peer_id, agent_version) => {
+                    log::info!("identified peer {} as {}", peer_id, agent_version);
+                }
+                NetworkEvent::PingResult(peer_id, rtt) => {
+                    log::debug!("ping {} {}ms", peer_id, rtt.as_millis());
+                }
+                NetworkEvent::RecordPut(key) => log::debug!("put record {:?}", key.as_ref()),
+                NetworkEvent::RecordFound(key) => log::debug!("found record {:?}", key.as_ref()),
+                NetworkEvent::Message(message) => {
+                    let topic = message.topic.clone();
+                    if let Some(subscribers) = self.subscriptions.get_mut(&topic) {
+                        subscribers.retain(|subscriber| {
+                            subscriber.unbounded_send(message.clone()).is_ok()
+                        });
+                        // unsubscribe once all subscribers were dropped
+                        if subscribers.is_empty() {
+                            self.subscriptions.remove(&topic);
+                            self.swarm.unsubscribe(&topic);
+                        }
+                    }
+                }
+                NetworkEvent::PeerRejected(peer_id) => {
+                    Swarm::ban_peer_id(&mut self.swarm, peer_id);
+                }
+                NetworkEvent::IdlePeer(peer_id) => {
+                    // the swarm can only close connections by banning a peer
+                    log::info!("closing idle connections to {}", peer_id);
+                    Swarm::ban_peer_id(&mut self.swarm, peer_id.clone());
+                    Swarm::unban_peer_id(&mut self.swarm, peer_id);
+                }
+                NetworkEvent::ListenerClosed(address, reason) => {
+                    match reason {
+                        Some(err) => log::error!("listener on {} closed: {}", address, err),
+                        None => log::warn!("listener on {} closed", address),
+                    }
+                    if self.relisten {
+                        match Swarm::listen_on(&mut self.swarm, address.clone()) {
+                            Ok(id) => self.swarm.add_listener(id, address),
+                            Err(err) => log::error!("failed to listen on {}: {}", address, err),
                         }
                     }
                 }
+                NetworkEvent::ConnectionEstablished(peer_id, address) => {
+                    log::debug!("connected to {} at {}", peer_id, address);
+                    self.notify_connection(ConnectionEvent::Established(peer_id, address));
+                }
+                NetworkEvent::ConnectionClosed(peer_id, cause) => {
+                    log::debug!("connection to {} closed: {:?}", peer_id, cause);
+                    self.notify_connection(ConnectionEvent::Closed(peer_id, cause));
+                }
+                NetworkEvent::BootstrapComplete => {
+                    self.bootstrapped = true;
+                    for tx in self.bootstrap_replies.drain(..) {
+                        tx.send(()).ok();
+                    }
+                    self.provide_public();
+                }
             }
         }
         Poll::Pending
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listens_on() {
+        let listens_on =
+            |listen: &str, addr: &str| listens_on(&listen.parse().unwrap(), &addr.parse().unwrap());
+        assert!(listens_on("/ip4/0.0.0.0/tcp/0", "/ip4/127.0.0.1/tcp/4001"));
+        assert!(listens_on(
+            "/ip4/127.0.0.1/tcp/4001",
+            "/ip4/127.0.0.1/tcp/4001"
+        ));
+        assert!(listens_on("/ip6/::/tcp/0/ws", "/ip6/::1/tcp/4001/ws"));
+        assert!(listens_on("/memory/0", "/memory/1234"));
+        assert!(!listens_on("/ip4/0.0.0.0/tcp/0", "/ip6/::1/tcp/4001"));
+        assert!(!listens_on(
+            "/ip4/0.0.0.0/tcp/0",
+            "/ip4/127.0.0.1/tcp/4001/ws"
+        ));
+        assert!(!listens_on(
+            "/ip4/127.0.0.1/tcp/4001",
+            "/ip4/127.0.0.1/tcp/4002"
+        ));
+    }
+}
@@ -0,0 +1,146 @@
+use core::task::{Context, Poll};
+use libp2p::core::connection::ConnectionId;
+use libp2p::core::{Multiaddr, PeerId};
+use libp2p::swarm::protocols_handler::{DummyProtocolsHandler, ProtocolsHandler};
+use libp2p::swarm::{NetworkBehaviour, NetworkBehaviourAction, PollParameters};
+use std::collections::{HashSet, VecDeque};
+
+type InEvent = <DummyProtocolsHandler as ProtocolsHandler>::InEvent;
+type OutEvent = <DummyProtocolsHandler as ProtocolsHandler>::OutEvent;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PeerFilterEvent {
+    /// A peer that isn't allowed connected and needs to be banned.
+    Rejected(PeerId),
+}
+
+/// Decides which peers we talk to.
+///
+/// Denied peers are banned in the swarm, which refuses connections from and
+/// dials to them. With an allowlist, peers that aren't listed are rejected
+/// when they connect.
+#[derive(Default)]
+pub struct PeerFilter {
+    /// Only these peers are accepted if set.
+    allowed: Option<HashSet<PeerId>>,
+    denied: HashSet<PeerId>,
+    events: VecDeque<NetworkBehaviourAction<InEvent, PeerFilterEvent>>,
+}
+
+impl PeerFilter {
+    pub fn new(allowed: Option<HashSet<PeerId>>, denied: HashSet<PeerId>) -> Self {
+        Self {
+            allowed,
+            denied,
+            ..Default::default()
+        }
+    }
+
+    /// Returns true if the peer is allowed and not denied.
+    pub fn is_allowed(&self, peer_id: &PeerId) -> bool {
+        if self.denied.contains(peer_id) {
+            return false;
+        }
+        match &self.allowed {
+            Some(allowed) => allowed.contains(peer_id),
+            None => true,
+        }
+    }
+
+    /// Returns the denied peers.
+    pub fn denied(&self) -> impl Iterator<Item = &PeerId> {
+        self.denied.iter()
+    }
+
+    /// Adds a peer to the denylist.
+    pub fn deny(&mut self, peer_id: PeerId) {
+        self.denied.insert(peer_id);
+    }
+
+    /// Removes a peer from the denylist.
+    pub fn undeny(&mut self, peer_id: &PeerId) {
+        self.denied.remove(peer_id);
+    }
+}
+
+impl NetworkBehaviour for PeerFilter {
+    type ProtocolsHandler = DummyProtocolsHandler;
+    type OutEvent = PeerFilterEvent;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        Default::default()
+    }
+
+    fn addresses_of_peer(&mut self, _peer_id: &PeerId) -> Vec<Multiaddr> {
+        Default::default()
+    }
+
+    fn inject_connected(&mut self, peer_id: &PeerId) {
+        if !self.is_allowed(peer_id) {
+            log::info!("rejecting peer {}", peer_id);
+            self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                PeerFilterEvent::Rejected(peer_id.clone()),
+            ));
+        }
+    }
+
+    fn inject_disconnected(&mut self, _peer_id: &PeerId) {}
+
+    fn inject_event(&mut self, _peer_id: PeerId, _connection: ConnectionId, event: OutEvent) {
+        match event {}
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context,
+        _: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<InEvent, PeerFilterEvent>> {
+        if let Some(event) = self.events.pop_front() {
+            Poll::Ready(event)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(filter: &mut PeerFilter) -> Option<PeerId> {
+        match filter.events.pop_front() {
+            Some(NetworkBehaviourAction::GenerateEvent(PeerFilterEvent::Rejected(peer_id))) => {
+                Some(peer_id)
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_denylist() {
+        let a = PeerId::random();
+        let b = PeerId::random();
+        let mut filter = PeerFilter::default();
+        assert!(filter.is_allowed(&a));
+        filter.deny(a.clone());
+        assert!(!filter.is_allowed(&a));
+        assert!(filter.is_allowed(&b));
+        filter.undeny(&a);
+        assert!(filter.is_allowed(&a));
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let a = PeerId::random();
+        let b = PeerId::random();
+        let allowed = vec![a.clone()].into_iter().collect();
+        let mut filter = PeerFilter::new(Some(allowed), Default::default());
+        filter.inject_connected(&a);
+        assert_eq!(rejected(&mut filter), None);
+        filter.inject_connected(&b);
+        assert_eq!(rejected(&mut filter), Some(b));
+
+        filter.deny(a.clone());
+        assert!(!filter.is_allowed(&a));
+    }
+}
@@ -0,0 +1,343 @@
+use core::task::{Context, Poll};
+use libipld::cid::Cid;
+use libp2p::core::connection::ConnectionId;
+use libp2p::core::{Multiaddr, PeerId};
+use libp2p::swarm::protocols_handler::{DummyProtocolsHandler, ProtocolsHandler};
+use libp2p::swarm::{DialPeerCondition, NetworkBehaviour, NetworkBehaviourAction, PollParameters};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+type InEvent = <DummyProtocolsHandler as ProtocolsHandler>::InEvent;
+type OutEvent = <DummyProtocolsHandler as ProtocolsHandler>::OutEvent;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ProvidersEvent {
+    /// All providers of a block were tried without success.
+    Exhausted(Cid),
+}
+
+/// Dials the providers of wanted blocks, up to `parallel` of them at the
+/// same time.
+///
+/// Bitswap sends our wantlist to every connected peer, so connecting to a
+/// provider is enough to request the block from it. The connected providers
+/// form a session: they are asked for every block we want, the first one to
+/// respond wins and the want is cancelled on the others. When a dial fails or
+/// the connection to a provider is lost, the next provider is tried.
+///
+/// While the connection or pending dial limit is reached blocks wait for a
+/// free slot instead of failing the dial.
+#[derive(Default)]
+pub struct Providers {
+    /// Maximum number of connected peers.
+    max_connections: Option<usize>,
+    /// Maximum number of simultaneous dials.
+    max_pending: Option<usize>,
+    /// Number of providers of a block that are tried at the same time.
+    parallel: usize,
+    /// Connected peers.
+    connected: HashSet<PeerId>,
+    /// Providers that are being dialed.
+    dialing: HashSet<PeerId>,
+    /// Providers that haven't been tried yet.
+    queued: HashMap<Cid, VecDeque<PeerId>>,
+    /// Providers that are currently tried.
+    current: HashMap<Cid, Vec<PeerId>>,
+    /// Blocks waiting for a free slot.
+    waiting: VecDeque<Cid>,
+    events: VecDeque<NetworkBehaviourAction<InEvent, ProvidersEvent>>,
+}
+
+impl Providers {
+    pub fn new(
+        max_connections: Option<usize>,
+        max_pending: Option<usize>,
+        parallel: usize,
+    ) -> Self {
+        Self {
+            max_connections,
+            max_pending,
+            parallel,
+            ..Default::default()
+        }
+    }
+
+    /// Adds providers for a block and starts dialing them.
+    pub fn add_providers(&mut self, cid: Cid, providers: impl IntoIterator<Item = PeerId>) {
+        let queued = self.queued.entry(cid.clone()).or_default();
+        for peer_id in providers {
+            if !queued.contains(&peer_id) {
+                queued.push_back(peer_id);
+            }
+        }
+        if !self.waiting.contains(&cid) {
+            self.try_next(cid);
+        }
+    }
+
+    /// Returns the providers that are being tried for a block.
+    pub fn dialing(&self, cid: &Cid) -> &[PeerId] {
+        self.current.get(cid).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Stops dialing providers for a block.
+    pub fn remove(&mut self, cid: &Cid) {
+        self.queued.remove(cid);
+        self.current.remove(cid);
+        self.waiting.retain(|waiting| waiting != cid);
+    }
+
+    fn can_dial(&self) -> bool {
+        let below = |limit: Option<usize>, n: usize| match limit {
+            Some(max) => n < max,
+            None => true,
+        };
+        let connections = self.connected.len() + self.dialing.len();
+        below(self.max_connections, connections) && below(self.max_pending, self.dialing.len())
+    }
+
+    /// Dials providers of a block until `parallel` of them are tried.
+    fn try_next(&mut self, cid: Cid) {
+        let parallel = self.parallel.max(1);
+        loop {
+            let tried = self.current.get(&cid).map(Vec::len).unwrap_or_default();
+            if tried >= parallel {
+                return;
+            }
+            let queued = self.queued.entry(cid.clone()).or_default();
+            // connected peers already have our want, so dial the next
+            // provider that isn't connected yet.
+            let connected = &self.connected;
+            while queued
+                .front()
+                .map(|peer_id| connected.contains(peer_id))
+                .unwrap_or_default()
+            {
+                queued.pop_front();
+            }
+            if queued.is_empty() {
+                break;
+            }
+            if !self.can_dial() {
+                log::debug!("connection limit reached, {} waits", cid.to_string());
+                self.waiting.push_back(cid);
+                return;
+            }
+            let peer_id = match self.queued.get_mut(&cid).and_then(VecDeque::pop_front) {
+                Some(peer_id) => peer_id,
+                None => break,
+            };
+            log::debug!("dialing provider {} for {}", peer_id, cid.to_string());
+            self.current
+                .entry(cid.clone())
+                .or_default()
+                .push(peer_id.clone());
+            self.dialing.insert(peer_id.clone());
+            self.events.push_back(NetworkBehaviourAction::DialPeer {
+                peer_id,
+                condition: DialPeerCondition::Disconnected,
+            });
+        }
+        self.queued.remove(&cid);
+        if !self.current.contains_key(&cid) {
+            self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                ProvidersEvent::Exhausted(cid),
+            ));
+        }
+    }
+
+    fn provider_failed(&mut self, peer_id: &PeerId) {
+        let mut cids = vec![];
+        self.current.retain(|cid, current| {
+            if let Some(i) = current.iter().position(|current| current == peer_id) {
+                current.remove(i);
+                cids.push(cid.clone());
+            }
+            !current.is_empty()
+        });
+        for cid in cids {
+            log::debug!("provider {} failed for {}", peer_id, cid.to_string());
+            self.try_next(cid);
+        }
+    }
+
+    /// Dials providers for blocks that wait for a free slot.
+    fn resume(&mut self) {
+        while self.can_dial() {
+            match self.waiting.pop_front() {
+                Some(cid) => self.try_next(cid),
+                None => break,
+            }
+        }
+    }
+}
+
+impl NetworkBehaviour for Providers {
+    type ProtocolsHandler = DummyProtocolsHandler;
+    type OutEvent = ProvidersEvent;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        Default::default()
+    }
+
+    fn addresses_of_peer(&mut self, _peer_id: &PeerId) -> Vec<Multiaddr> {
+        Default::default()
+    }
+
+    fn inject_connected(&mut self, peer_id: &PeerId) {
+        self.connected.insert(peer_id.clone());
+        self.dialing.remove(peer_id);
+        self.resume();
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        self.connected.remove(peer_id);
+        self.provider_failed(peer_id);
+        self.resume();
+    }
+
+    fn inject_event(&mut self, _peer_id: PeerId, _connection: ConnectionId, event: OutEvent) {
+        match event {}
+    }
+
+    fn inject_dial_failure(&mut self, peer_id: &PeerId) {
+        self.dialing.remove(peer_id);
+        self.provider_failed(peer_id);
+        self.resume();
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut Context,
+        _: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<InEvent, ProvidersEvent>> {
+        if let Some(event) = self.events.pop_front() {
+            Poll::Ready(event)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::cid::{Cid, RAW};
+    use libipld::multihash::{Multihash, MultihashDigest, SHA2_256};
+
+    fn cid(bytes: &[u8]) -> Cid {
+        let digest = Multihash::new(SHA2_256, bytes).unwrap().to_raw().unwrap();
+        Cid::new_v1(RAW, digest)
+    }
+
+    fn next_event(
+        providers: &mut Providers,
+    ) -> Option<NetworkBehaviourAction<InEvent, ProvidersEvent>> {
+        providers.events.pop_front()
+    }
+
+    fn dialed(providers: &mut Providers) -> Option<PeerId> {
+        match next_event(providers) {
+            Some(NetworkBehaviourAction::DialPeer { peer_id, .. }) => Some(peer_id),
+            _ => None,
+        }
+    }
+
+    fn exhausted(providers: &mut Providers) -> Option<Cid> {
+        match next_event(providers) {
+            Some(NetworkBehaviourAction::GenerateEvent(ProvidersEvent::Exhausted(cid))) => {
+                Some(cid)
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_dial_providers_in_order() {
+        let mut providers = Providers::default();
+        let cid = cid(b"hello world");
+        let a = PeerId::random();
+        let b = PeerId::random();
+        providers.add_providers(cid.clone(), vec![a.clone(), b.clone()]);
+        assert_eq!(dialed(&mut providers), Some(a.clone()));
+        assert!(next_event(&mut providers).is_none());
+
+        providers.inject_dial_failure(&a);
+        assert_eq!(dialed(&mut providers), Some(b.clone()));
+
+        providers.inject_connected(&b);
+        providers.inject_disconnected(&b);
+        assert_eq!(exhausted(&mut providers), Some(cid));
+    }
+
+    #[test]
+    fn test_dial_providers_in_parallel() {
+        let mut providers = Providers::new(None, None, 2);
+        let cid = cid(b"hello world");
+        let a = PeerId::random();
+        let b = PeerId::random();
+        let c = PeerId::random();
+        providers.add_providers(cid.clone(), vec![a.clone(), b.clone(), c.clone()]);
+        assert_eq!(dialed(&mut providers), Some(a.clone()));
+        assert_eq!(dialed(&mut providers), Some(b.clone()));
+        assert!(next_event(&mut providers).is_none());
+        assert_eq!(providers.dialing(&cid), &[a.clone(), b.clone()][..]);
+
+        providers.inject_connected(&a);
+        providers.inject_dial_failure(&b);
+        assert_eq!(dialed(&mut providers), Some(c.clone()));
+
+        providers.inject_dial_failure(&c);
+        assert!(next_event(&mut providers).is_none());
+        providers.inject_disconnected(&a);
+        assert_eq!(exhausted(&mut providers), Some(cid));
+    }
+
+    #[test]
+    fn test_skip_connected_providers() {
+        let mut providers = Providers::default();
+        let cid = cid(b"hello world");
+        let a = PeerId::random();
+        let b = PeerId::random();
+        providers.inject_connected(&a);
+        providers.add_providers(cid, vec![a, b.clone()]);
+        assert_eq!(dialed(&mut providers), Some(b));
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut providers = Providers::default();
+        let cid = cid(b"hello world");
+        let a = PeerId::random();
+        let b = PeerId::random();
+        providers.add_providers(cid.clone(), vec![a.clone(), b]);
+        assert_eq!(dialed(&mut providers), Some(a.clone()));
+        providers.remove(&cid);
+        providers.inject_dial_failure(&a);
+        assert!(next_event(&mut providers).is_none());
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let mut providers = Providers::new(Some(1), None, 1);
+        let cid1 = cid(b"hello");
+        let cid2 = cid(b"world");
+        let a = PeerId::random();
+        let b = PeerId::random();
+        providers.add_providers(cid1.clone(), vec![a.clone()]);
+        providers.add_providers(cid2.clone(), vec![b.clone()]);
+        assert_eq!(dialed(&mut providers), Some(a.clone()));
+        assert!(next_event(&mut providers).is_none());
+
+        providers.inject_dial_failure(&a);
+        assert_eq!(exhausted(&mut providers), Some(cid1));
+        assert_eq!(dialed(&mut providers), Some(b.clone()));
+
+        providers.inject_connected(&b);
+        let c = PeerId::random();
+        providers.add_providers(cid(b"!"), vec![c.clone()]);
+        assert!(next_event(&mut providers).is_none());
+        providers.inject_disconnected(&b);
+        assert_eq!(exhausted(&mut providers), Some(cid2));
+        assert_eq!(dialed(&mut providers), Some(c));
+    }
+}
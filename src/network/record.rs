@@ -0,0 +1,89 @@
+use libipld::cbor::DagCborCodec;
+use libipld::codec::Codec;
+use libipld::error::Result;
+use libipld::ipld::Ipld;
+use libp2p::core::PeerId;
+use libp2p::identity::{Keypair, PublicKey};
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+/// The value of a DHT record isn't a valid signed record.
+#[derive(Debug, Error)]
+#[error("Invalid record: {0}.")]
+pub struct InvalidRecord(pub &'static str);
+
+/// No valid record was found in the DHT.
+#[derive(Debug, Error)]
+#[error("Record not found.")]
+pub struct RecordNotFound;
+
+/// A record read from the DHT.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DhtRecord {
+    /// Peer that signed the record.
+    pub publisher: PeerId,
+    pub value: Vec<u8>,
+}
+
+/// The signature covers the key, so that a record can't be replayed under
+/// another key.
+fn signed_bytes(key: &[u8], value: &[u8]) -> Result<Box<[u8]>> {
+    let signed = Ipld::List(vec![Ipld::Bytes(key.to_vec()), Ipld::Bytes(value.to_vec())]);
+    DagCborCodec.encode(&signed)
+}
+
+/// Signs a value with the node key. Returns the DAG-CBOR encoded record
+/// that is stored in the DHT.
+pub fn sign(keypair: &Keypair, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+    let signature = keypair.sign(&signed_bytes(key, value)?)?;
+    let mut record = BTreeMap::new();
+    let public_key = keypair.public().into_protobuf_encoding();
+    record.insert("publicKey".to_string(), Ipld::Bytes(public_key));
+    record.insert("signature".to_string(), Ipld::Bytes(signature));
+    record.insert("value".to_string(), Ipld::Bytes(value.to_vec()));
+    Ok(DagCborCodec.encode(&Ipld::Map(record))?.into_vec())
+}
+
+/// Checks the signature of a record stored under `key`.
+pub fn verify(key: &[u8], record: &[u8]) -> Result<DhtRecord> {
+    let mut record = match DagCborCodec.decode_ipld(record)? {
+        Ipld::Map(record) => record,
+        _ => return Err(InvalidRecord("not a map").into()),
+    };
+    let mut bytes = |name| match record.remove(name) {
+        Some(Ipld::Bytes(bytes)) => Ok(bytes),
+        _ => Err(InvalidRecord(name)),
+    };
+    let public_key = PublicKey::from_protobuf_encoding(&bytes("publicKey")?)?;
+    let signature = bytes("signature")?;
+    let value = bytes("value")?;
+    if !public_key.verify(&signed_bytes(key, &value)?, &signature) {
+        return Err(InvalidRecord("bad signature").into());
+    }
+    Ok(DhtRecord {
+        publisher: public_key.into_peer_id(),
+        value,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_verify() {
+        let keypair = Keypair::generate_ed25519();
+        let record = sign(&keypair, b"key", b"value").unwrap();
+        let record = verify(b"key", &record).unwrap();
+        assert_eq!(record.publisher, keypair.public().into_peer_id());
+        assert_eq!(record.value, b"value");
+    }
+
+    #[test]
+    fn test_verify_other_key() {
+        let keypair = Keypair::generate_ed25519();
+        let record = sign(&keypair, b"key", b"value").unwrap();
+        let err = verify(b"other key", &record).unwrap_err();
+        assert!(err.downcast_ref::<InvalidRecord>().is_some());
+    }
+}
@@ -0,0 +1,234 @@
+use async_std::task;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use core::time::Duration;
+use libp2p::core::connection::ConnectionId;
+use libp2p::core::{Multiaddr, PeerId};
+use libp2p::swarm::protocols_handler::{DummyProtocolsHandler, ProtocolsHandler};
+use libp2p::swarm::{DialPeerCondition, NetworkBehaviour, NetworkBehaviourAction, PollParameters};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Instant;
+
+type InEvent = <DummyProtocolsHandler as ProtocolsHandler>::InEvent;
+type OutEvent = <DummyProtocolsHandler as ProtocolsHandler>::OutEvent;
+
+/// Interval at which the boot nodes are checked.
+const REDIAL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RedialEvent {
+    /// A boot node that was dialed again connected, so the DHT needs to be
+    /// bootstrapped again.
+    Reconnected(PeerId),
+}
+
+struct BootNode {
+    addresses: Vec<Multiaddr>,
+    /// Delay before the next dial.
+    delay: Duration,
+    next: Instant,
+}
+
+/// Dials the boot nodes again while the node isn't connected to any peer.
+///
+/// The DHT is bootstrapped once on startup, so a node whose boot nodes are
+/// down at that time would stay isolated. The delay between dials of a boot
+/// node doubles after each dial up to a maximum, and is reset when it
+/// connects.
+pub struct Redial {
+    backoff: Duration,
+    max_backoff: Duration,
+    boot_nodes: HashMap<PeerId, BootNode>,
+    connected: HashSet<PeerId>,
+    /// Set while boot nodes are dialed again.
+    redialing: bool,
+    timer: Pin<Box<dyn Future<Output = ()> + Send>>,
+    events: VecDeque<NetworkBehaviourAction<InEvent, RedialEvent>>,
+}
+
+impl Redial {
+    pub fn new(
+        boot_nodes: &[(Multiaddr, PeerId)],
+        backoff: Duration,
+        max_backoff: Duration,
+        now: Instant,
+    ) -> Self {
+        let mut nodes: HashMap<PeerId, BootNode> = HashMap::new();
+        for (address, peer_id) in boot_nodes {
+            // the first dial happens when bootstrapping
+            nodes
+                .entry(peer_id.clone())
+                .or_insert_with(|| BootNode {
+                    addresses: vec![],
+                    delay: backoff,
+                    next: now + backoff,
+                })
+                .addresses
+                .push(address.clone());
+        }
+        Self {
+            backoff,
+            max_backoff,
+            boot_nodes: nodes,
+            connected: Default::default(),
+            redialing: false,
+            timer: Box::pin(task::sleep(REDIAL_CHECK_INTERVAL)),
+            events: Default::default(),
+        }
+    }
+
+    /// Stops dialing a boot node.
+    pub fn remove(&mut self, peer_id: &PeerId) {
+        self.boot_nodes.remove(peer_id);
+    }
+
+    /// Returns the boot nodes to dial at `now` and backs them off.
+    fn due(&mut self, now: Instant) -> Vec<PeerId> {
+        if !self.connected.is_empty() {
+            return vec![];
+        }
+        let mut due = vec![];
+        for (peer_id, node) in &mut self.boot_nodes {
+            if node.next <= now {
+                due.push(peer_id.clone());
+                node.next = now + node.delay;
+                node.delay = (node.delay * 2).min(self.max_backoff);
+            }
+        }
+        due
+    }
+}
+
+impl NetworkBehaviour for Redial {
+    type ProtocolsHandler = DummyProtocolsHandler;
+    type OutEvent = RedialEvent;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        Default::default()
+    }
+
+    fn addresses_of_peer(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        self.boot_nodes
+            .get(peer_id)
+            .map(|node| node.addresses.clone())
+            .unwrap_or_default()
+    }
+
+    fn inject_connected(&mut self, peer_id: &PeerId) {
+        self.connected.insert(peer_id.clone());
+        if let Some(node) = self.boot_nodes.get_mut(peer_id) {
+            node.delay = self.backoff;
+            if self.redialing {
+                self.redialing = false;
+                self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                    RedialEvent::Reconnected(peer_id.clone()),
+                ));
+            }
+        }
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        self.connected.remove(peer_id);
+        if let Some(node) = self.boot_nodes.get_mut(peer_id) {
+            node.next = Instant::now() + node.delay;
+        }
+    }
+
+    fn inject_event(&mut self, _peer_id: PeerId, _connection: ConnectionId, event: OutEvent) {
+        match event {}
+    }
+
+    fn poll(
+        &mut self,
+        ctx: &mut Context,
+        _: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<InEvent, RedialEvent>> {
+        while self.timer.as_mut().poll(ctx).is_ready() {
+            self.timer = Box::pin(task::sleep(REDIAL_CHECK_INTERVAL));
+            for peer_id in self.due(Instant::now()) {
+                log::info!("dialing boot node {} again", peer_id);
+                self.redialing = true;
+                self.events.push_back(NetworkBehaviourAction::DialPeer {
+                    peer_id,
+                    condition: DialPeerCondition::Disconnected,
+                });
+            }
+        }
+        if let Some(event) = self.events.pop_front() {
+            Poll::Ready(event)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redial(peer_id: &PeerId, now: Instant) -> Redial {
+        let address = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        let boot_nodes = vec![(address, peer_id.clone())];
+        Redial::new(
+            &boot_nodes,
+            Duration::from_secs(1),
+            Duration::from_secs(3),
+            now,
+        )
+    }
+
+    #[test]
+    fn test_backoff() {
+        let a = PeerId::random();
+        let start = Instant::now();
+        let mut redial = redial(&a, start);
+        assert!(redial.due(start).is_empty());
+        let secs = |secs| start + Duration::from_secs(secs);
+        assert_eq!(redial.due(secs(1)), vec![a.clone()]);
+        assert!(redial.due(secs(1)).is_empty());
+        assert_eq!(redial.due(secs(2)), vec![a.clone()]);
+        assert!(redial.due(secs(3)).is_empty());
+        assert_eq!(redial.due(secs(4)), vec![a.clone()]);
+        // capped at the maximum
+        assert!(redial.due(secs(6)).is_empty());
+        assert_eq!(redial.due(secs(7)), vec![a.clone()]);
+        assert_eq!(redial.due(secs(10)), vec![a.clone()]);
+    }
+
+    #[test]
+    fn test_not_isolated() {
+        let a = PeerId::random();
+        let start = Instant::now();
+        let mut redial = redial(&a, start);
+        redial.inject_connected(&PeerId::random());
+        assert!(redial.due(start + Duration::from_secs(60)).is_empty());
+    }
+
+    #[test]
+    fn test_reconnected() {
+        let a = PeerId::random();
+        let start = Instant::now();
+        let mut redial = redial(&a, start);
+        redial.due(start + Duration::from_secs(1));
+        redial.redialing = true;
+        redial.inject_connected(&a);
+        match redial.events.pop_front() {
+            Some(NetworkBehaviourAction::GenerateEvent(RedialEvent::Reconnected(peer_id))) => {
+                assert_eq!(peer_id, a)
+            }
+            _ => panic!("expected reconnected event"),
+        }
+        // the backoff is reset
+        assert_eq!(redial.boot_nodes[&a].delay, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_remove() {
+        let a = PeerId::random();
+        let start = Instant::now();
+        let mut redial = redial(&a, start);
+        redial.remove(&a);
+        assert!(redial.due(start + Duration::from_secs(60)).is_empty());
+    }
+}
@@ -0,0 +1,170 @@
+use core::time::Duration;
+use libipld::cid::Cid;
+use libp2p::core::PeerId;
+use std::collections::{HashMap, VecDeque};
+use std::time::Instant;
+
+type Block = (Cid, Box<[u8]>);
+
+/// Limits the rate at which blocks are sent with a token bucket.
+///
+/// Tokens are bytes. The bucket holds up to one second worth of tokens and a
+/// block is sent once the bucket has enough tokens for it, or is full for
+/// blocks that are larger than the bucket. Peers take turns so that a peer
+/// requesting many blocks can't starve the others, and a peer can have a
+/// limited number of queued blocks.
+pub struct SendQueue {
+    /// Bytes per second.
+    rate: u64,
+    /// Maximum number of queued blocks per peer.
+    max_queued: Option<usize>,
+    tokens: u64,
+    refilled: Instant,
+    /// Peers with queued blocks in the order they take turns.
+    peers: VecDeque<PeerId>,
+    queued: HashMap<PeerId, VecDeque<Block>>,
+}
+
+impl SendQueue {
+    pub fn new(rate: u64, max_queued: Option<usize>, now: Instant) -> Self {
+        Self {
+            rate,
+            max_queued,
+            tokens: rate,
+            refilled: now,
+            peers: Default::default(),
+            queued: Default::default(),
+        }
+    }
+
+    /// Queues a block for a peer. Returns false if the peer has the maximum
+    /// number of queued blocks, in which case the block is dropped.
+    pub fn push(&mut self, peer_id: PeerId, cid: Cid, data: Box<[u8]>) -> bool {
+        let queue = self.queued.entry(peer_id.clone()).or_default();
+        if self.max_queued.is_some_and(|max| queue.len() >= max) {
+            return false;
+        }
+        if queue.is_empty() {
+            self.peers.push_back(peer_id);
+        }
+        queue.push_back((cid, data));
+        true
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled);
+        let tokens = (elapsed.as_micros() as u64).saturating_mul(self.rate) / 1_000_000;
+        if tokens > 0 {
+            self.tokens = (self.tokens + tokens).min(self.rate);
+            self.refilled = now;
+        }
+    }
+
+    fn cost(&self, len: usize) -> u64 {
+        (len as u64).min(self.rate)
+    }
+
+    /// Returns the next block if the budget allows sending it.
+    pub fn pop(&mut self, now: Instant) -> Option<(PeerId, Cid, Box<[u8]>)> {
+        self.refill(now);
+        let peer_id = self.peers.front()?.clone();
+        let len = self.queued.get(&peer_id)?.front()?.1.len();
+        let cost = self.cost(len);
+        if cost > self.tokens {
+            return None;
+        }
+        self.tokens -= cost;
+        self.peers.pop_front();
+        let queue = self.queued.get_mut(&peer_id)?;
+        let (cid, data) = queue.pop_front()?;
+        if queue.is_empty() {
+            self.queued.remove(&peer_id);
+        } else {
+            self.peers.push_back(peer_id.clone());
+        }
+        Some((peer_id, cid, data))
+    }
+
+    /// Returns how long to wait until the next block can be sent.
+    pub fn delay(&self) -> Option<Duration> {
+        let peer_id = self.peers.front()?;
+        let len = self.queued.get(peer_id)?.front()?.1.len();
+        let missing = self.cost(len).saturating_sub(self.tokens);
+        Some(Duration::from_micros(
+            missing.saturating_mul(1_000_000).div_ceil(self.rate),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::cid::RAW;
+    use libipld::multihash::{Multihash, MultihashDigest, SHA2_256};
+
+    fn cid(bytes: &[u8]) -> Cid {
+        let digest = Multihash::new(SHA2_256, bytes).unwrap().to_raw().unwrap();
+        Cid::new_v1(RAW, digest)
+    }
+
+    fn block(len: usize) -> Box<[u8]> {
+        vec![0; len].into_boxed_slice()
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let start = Instant::now();
+        let mut queue = SendQueue::new(100, None, start);
+        let peer_id = PeerId::random();
+        queue.push(peer_id.clone(), cid(b"a"), block(60));
+        queue.push(peer_id.clone(), cid(b"b"), block(60));
+        assert_eq!(queue.pop(start).unwrap().1, cid(b"a"));
+        assert!(queue.pop(start).is_none());
+        assert_eq!(queue.delay(), Some(Duration::from_millis(200)));
+        assert!(queue.pop(start + Duration::from_millis(100)).is_none());
+        assert_eq!(
+            queue.pop(start + Duration::from_millis(200)).unwrap().1,
+            cid(b"b")
+        );
+        assert!(queue.delay().is_none());
+    }
+
+    #[test]
+    fn test_large_block() {
+        let start = Instant::now();
+        let mut queue = SendQueue::new(100, None, start);
+        queue.push(PeerId::random(), cid(b"a"), block(1000));
+        assert!(queue.pop(start).is_some());
+    }
+
+    #[test]
+    fn test_fairness() {
+        let start = Instant::now();
+        let mut queue = SendQueue::new(1000, None, start);
+        let a = PeerId::random();
+        let b = PeerId::random();
+        queue.push(a.clone(), cid(b"a1"), block(1));
+        queue.push(a.clone(), cid(b"a2"), block(1));
+        queue.push(b.clone(), cid(b"b1"), block(1));
+        let order: Vec<_> = std::iter::from_fn(|| queue.pop(start))
+            .map(|(peer_id, cid, _)| (peer_id, cid))
+            .collect();
+        assert_eq!(
+            order,
+            vec![(a.clone(), cid(b"a1")), (b, cid(b"b1")), (a, cid(b"a2"))]
+        );
+    }
+
+    #[test]
+    fn test_max_queued() {
+        let start = Instant::now();
+        let mut queue = SendQueue::new(100, Some(1), start);
+        let a = PeerId::random();
+        assert!(queue.push(a.clone(), cid(b"a1"), block(1)));
+        assert!(!queue.push(a.clone(), cid(b"a2"), block(1)));
+        // the limit is per peer
+        assert!(queue.push(PeerId::random(), cid(b"b1"), block(1)));
+        queue.pop(start).unwrap();
+        assert!(queue.push(a, cid(b"a2"), block(1)));
+    }
+}
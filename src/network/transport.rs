@@ -0,0 +1,182 @@
+use crate::network::{Multiplexer, NetworkConfig, Security};
+use futures::io::{AsyncRead, AsyncWrite};
+use libipld::error::Result;
+use libp2p::bandwidth::{BandwidthLogging, BandwidthSinks};
+use libp2p::core::either::EitherOutput;
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::boxed::Boxed;
+use libp2p::core::transport::upgrade::Version;
+use libp2p::core::transport::{MemoryTransport, OptionalTransport, Transport};
+use libp2p::core::upgrade::{EitherUpgrade, MapInboundUpgrade, MapOutboundUpgrade, SelectUpgrade};
+use libp2p::core::PeerId;
+use libp2p::dns::DnsConfig;
+use libp2p::mplex::MplexConfig;
+use libp2p::noise::{Keypair, NoiseConfig, X25519Spec};
+use libp2p::pnet::PnetConfig;
+use libp2p::secio::SecioConfig;
+use libp2p::tcp::TcpConfig;
+use libp2p::websocket::WsConfig;
+use libp2p::yamux::Config as YamuxConfig;
+use std::io::Error;
+use std::sync::Arc;
+
+pub type NetworkTransport = Boxed<(PeerId, StreamMuxerBox), Error>;
+
+type MultiplexUpgrade =
+    EitherUpgrade<SelectUpgrade<YamuxConfig, MplexConfig>, SelectUpgrade<MplexConfig, YamuxConfig>>;
+
+/// Negotiates both yamux and mplex, offering the preferred one first.
+fn multiplex_upgrade(config: &NetworkConfig) -> MultiplexUpgrade {
+    match config.multiplexer {
+        Multiplexer::Yamux => EitherUpgrade::A(SelectUpgrade::new(
+            YamuxConfig::default(),
+            MplexConfig::new(),
+        )),
+        Multiplexer::Mplex => EitherUpgrade::B(SelectUpgrade::new(
+            MplexConfig::new(),
+            YamuxConfig::default(),
+        )),
+    }
+}
+
+/// Moves the peer id out of the output of either security protocol, as
+/// `authenticate` expects.
+fn either_output<A, B>(
+    output: EitherOutput<(PeerId, A), (PeerId, B)>,
+) -> (PeerId, EitherOutput<A, B>) {
+    match output {
+        EitherOutput::First((peer_id, a)) => (peer_id, EitherOutput::First(a)),
+        EitherOutput::Second((peer_id, b)) => (peer_id, EitherOutput::Second(b)),
+    }
+}
+
+/// Builds the transport according to the configured security protocol. The
+/// returned sinks count the bytes transferred over all connections.
+///
+/// `/dns`, `/dns4` and `/dns6` addresses are resolved before dialing,
+/// `/dnsaddr` addresses aren't supported. With `memory_transport` only
+/// `/memory` addresses are supported.
+pub fn build_transport(config: &NetworkConfig) -> Result<(NetworkTransport, Arc<BandwidthSinks>)> {
+    if config.memory_transport {
+        let (transport, sinks) = BandwidthLogging::new(MemoryTransport);
+        return Ok((secure(transport, config)?, sinks));
+    }
+    let tcp = DnsConfig::new(TcpConfig::new().nodelay(true))?;
+    let ws = if config.enable_websocket {
+        OptionalTransport::some(WsConfig::new(tcp.clone()))
+    } else {
+        OptionalTransport::none()
+    };
+    let (transport, sinks) = BandwidthLogging::new(tcp.or_transport(ws));
+    Ok((secure(transport, config)?, sinks))
+}
+
+fn secure<T>(transport: T, config: &NetworkConfig) -> Result<NetworkTransport>
+where
+    T: Transport + Clone + Send + Sync + 'static,
+    T::Output: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    T::Error: std::error::Error + Send + Sync + 'static,
+    T::Listener: Send + 'static,
+    T::ListenerUpgrade: Send + 'static,
+    T::Dial: Send + 'static,
+{
+    match config.psk {
+        // the pnet handshake encrypts the connection before anything else is
+        // negotiated, so a peer without the key can't complete it.
+        Some(psk) => authenticate(
+            transport.and_then(move |socket, _| PnetConfig::new(psk).handshake(socket)),
+            config,
+        ),
+        None => authenticate(transport, config),
+    }
+}
+
+fn authenticate<T>(transport: T, config: &NetworkConfig) -> Result<NetworkTransport>
+where
+    T: Transport + Clone + Send + Sync + 'static,
+    T::Output: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    T::Error: std::error::Error + Send + Sync + 'static,
+    T::Listener: Send + 'static,
+    T::ListenerUpgrade: Send + 'static,
+    T::Dial: Send + 'static,
+{
+    let transport = match config.security {
+        Security::Noise => {
+            let keypair = Keypair::<X25519Spec>::new().into_authentic(&config.node_key)?;
+            transport
+                .upgrade(Version::V1)
+                .authenticate(NoiseConfig::xx(keypair).into_authenticated())
+                .multiplex(multiplex_upgrade(config))
+                .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+                .timeout(config.transport_timeout)
+                .map_err(Error::other)
+                .boxed()
+        }
+        Security::Secio => transport
+            .upgrade(Version::V1)
+            .authenticate(SecioConfig::new(config.node_key.clone()))
+            .multiplex(multiplex_upgrade(config))
+            .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+            .timeout(config.transport_timeout)
+            .map_err(Error::other)
+            .boxed(),
+        Security::Both => {
+            let keypair = Keypair::<X25519Spec>::new().into_authentic(&config.node_key)?;
+            let noise = NoiseConfig::xx(keypair).into_authenticated();
+            let secio = SecioConfig::new(config.node_key.clone());
+            let upgrade = MapOutboundUpgrade::new(
+                MapInboundUpgrade::new(SelectUpgrade::new(noise, secio), either_output),
+                either_output,
+            );
+            transport
+                .upgrade(Version::V1)
+                .authenticate(upgrade)
+                .multiplex(multiplex_upgrade(config))
+                .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+                .timeout(config.transport_timeout)
+                .map_err(Error::other)
+                .boxed()
+        }
+    };
+    Ok(transport)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::core::upgrade::{ProtocolName, UpgradeInfo};
+
+    fn protocols(config: &NetworkConfig) -> Vec<Vec<u8>> {
+        multiplex_upgrade(config)
+            .protocol_info()
+            .map(|name| name.protocol_name().to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn test_multiplex_protocols() {
+        let mut config = NetworkConfig::new();
+        config.multiplexer = Multiplexer::Yamux;
+        assert_eq!(
+            protocols(&config),
+            vec![b"/yamux/1.0.0".to_vec(), b"/mplex/6.7.0".to_vec()]
+        );
+        config.multiplexer = Multiplexer::Mplex;
+        assert_eq!(
+            protocols(&config),
+            vec![b"/mplex/6.7.0".to_vec(), b"/yamux/1.0.0".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_build_transport() {
+        let mut config = NetworkConfig::new();
+        build_transport(&config).unwrap();
+        config.security = Security::Secio;
+        build_transport(&config).unwrap();
+        config.security = Security::Both;
+        build_transport(&config).unwrap();
+        config.memory_transport = true;
+        build_transport(&config).unwrap();
+    }
+}
@@ -0,0 +1,69 @@
+use crate::storage::compression;
+use crate::storage::forward::{Forward, Forwarded};
+use crate::storage::key::Key;
+use crate::storage::Storage;
+use async_std::prelude::*;
+use async_std::task::{Context, Poll};
+use core::convert::TryFrom;
+use core::pin::Pin;
+use libipld::cid::Cid;
+use libipld::error::Result;
+use sled::{Event, IVec};
+
+/// Number of inserted blocks buffered before the subscriber lags.
+const BLOCKS_CAPACITY: usize = 1024;
+
+/// Stream of blocks inserted into the store.
+///
+/// Buffers up to `BLOCKS_CAPACITY` blocks, after which further blocks are
+/// dropped until the subscriber catches up, so a slow subscriber neither
+/// blocks inserts nor grows memory.
+pub struct BlockSubscriber {
+    block: Forwarded<(Cid, IVec)>,
+}
+
+impl Storage {
+    pub fn watch_blocks(&self) -> Result<BlockSubscriber> {
+        log::trace!("watching block() with prefix {:?}", Key::Block.prefix());
+        let subscriber = self.tree.watch_prefix(Key::Block.prefix());
+        Ok(BlockSubscriber {
+            block: Forwarded::spawn("block subscriber", subscriber, BLOCKS_CAPACITY, block_event)?,
+        })
+    }
+}
+
+/// Decodes the inserted blocks, removals aren't emitted.
+fn block_event(event: Event) -> Option<(Cid, IVec)> {
+    let (key, value) = match event {
+        Event::Insert { key, value } => (key, value),
+        Event::Remove { .. } => return None,
+    };
+    let cid = Cid::try_from(&key[1..]).expect("valid cid");
+    match compression::decode(&cid, value) {
+        Ok(data) => Some((cid, data)),
+        Err(err) => {
+            log::error!("failed to decode block {}: {}", cid.to_string(), err);
+            None
+        }
+    }
+}
+
+impl Stream for BlockSubscriber {
+    type Item = (Cid, IVec);
+
+    fn poll_next(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Option<Self::Item>> {
+        loop {
+            match Pin::new(&mut self.block).poll_next(ctx) {
+                Poll::Ready(Some(Forward::Event((cid, data)))) => {
+                    log::trace!("emit block event {}", cid.to_string());
+                    return Poll::Ready(Some((cid, data)));
+                }
+                Poll::Ready(Some(Forward::Lagged)) => {
+                    log::warn!("block subscriber fell behind, blocks were dropped");
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use crate::storage::key::{Key, Value};
+use libipld::cid::Cid;
+use libipld::error::Result;
+use sled::{Batch, IVec, Tree};
+use std::ops::Bound;
+use thiserror::Error;
+
+/// Version of the block value format, stored under `Key::Format`.
+const FORMAT_VERSION: u32 = 1;
+
+/// Flag of block values that are stored as is.
+const RAW: u8 = 0;
+
+/// Flag of block values compressed with deflate.
+#[cfg(feature = "compression")]
+const DEFLATE: u8 = 1;
+
+/// The block is stored in a format this build can't read, for example
+/// because it is compressed and the `compression` feature is disabled. The
+/// supplied string is a CID.
+#[derive(Debug, Error)]
+#[error("Block {0} is stored in an unsupported format.")]
+pub struct UnsupportedFormat(pub String);
+
+/// Number of block values rewritten in each batch of an upgrade.
+const UPGRADE_CHUNK: usize = 1024;
+
+/// Block values start with a flag byte telling how the rest is compressed,
+/// so compressed and uncompressed blocks can be mixed. The flag is written
+/// without the `compression` feature too, so a store can be opened by builds
+/// with and without it. Values of stores from before the flag are prefixed
+/// with `RAW` in batches of `UPGRADE_CHUNK`. The last rewritten key is stored
+/// with each batch so an interrupted upgrade resumes after it, and the format
+/// version is written once all values are rewritten.
+pub fn upgrade(tree: &Tree) -> Result<()> {
+    if tree.get(Key::format())?.is_some() {
+        return Ok(());
+    }
+    let prefix = Key::Block.prefix();
+    let mut start = match tree.get(Key::upgrade())? {
+        Some(key) => Bound::Excluded(key),
+        None => Bound::Included(prefix.clone()),
+    };
+    loop {
+        let mut batch = Batch::default();
+        let mut last = None;
+        for res in tree.range((start, Bound::Unbounded)).take(UPGRADE_CHUNK) {
+            let (key, value) = res?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            batch.insert(key.clone(), encode_raw(&value));
+            last = Some(key);
+        }
+        let last = match last {
+            Some(last) => last,
+            None => break,
+        };
+        batch.insert(Key::upgrade(), last.clone());
+        tree.apply_batch(batch)?;
+        start = Bound::Excluded(last);
+    }
+    let mut batch = Batch::default();
+    batch.remove(Key::upgrade());
+    batch.insert(Key::format(), Value::from(FORMAT_VERSION));
+    tree.apply_batch(batch)?;
+    Ok(())
+}
+
+fn encode_raw(data: &[u8]) -> Vec<u8> {
+    let mut value = Vec::with_capacity(data.len() + 1);
+    value.push(RAW);
+    value.extend_from_slice(data);
+    value
+}
+
+/// Returns the value to store for the data of a block. With a deflate
+/// `level` the data is compressed, unless it doesn't get smaller.
+#[cfg(feature = "compression")]
+pub fn encode(data: &[u8], level: Option<u32>) -> Result<Vec<u8>> {
+    use flate2::write::DeflateEncoder;
+    use std::io::Write;
+    let level = match level {
+        Some(level) => level,
+        None => return Ok(encode_raw(data)),
+    };
+    let mut encoder = DeflateEncoder::new(vec![DEFLATE], flate2::Compression::new(level));
+    encoder.write_all(data)?;
+    let value = encoder.finish()?;
+    if value.len() > data.len() {
+        return Ok(encode_raw(data));
+    }
+    Ok(value)
+}
+
+#[cfg(not(feature = "compression"))]
+pub fn encode(data: &[u8], _level: Option<u32>) -> Result<Vec<u8>> {
+    Ok(encode_raw(data))
+}
+
+/// Size of the stored data of a block, after compression and without the
+/// flag.
+pub fn stored_size(value: &[u8]) -> usize {
+    value.len().saturating_sub(1)
+}
+
+/// Returns the data of a block from its stored value.
+pub fn decode(cid: &Cid, value: IVec) -> Result<IVec> {
+    match value.first().copied() {
+        Some(RAW) => Ok(value.subslice(1, value.len() - 1)),
+        #[cfg(feature = "compression")]
+        Some(DEFLATE) => {
+            use flate2::read::DeflateDecoder;
+            use std::io::Read;
+            let mut data = Vec::with_capacity(value.len() * 2);
+            DeflateDecoder::new(&value[1..]).read_to_end(&mut data)?;
+            Ok(data.into())
+        }
+        _ => Err(UnsupportedFormat(cid.to_string()).into()),
+    }
+}
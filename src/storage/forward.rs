@@ -0,0 +1,96 @@
+use async_std::prelude::*;
+use async_std::task::{Context, Poll};
+use core::pin::Pin;
+use futures::channel::mpsc;
+use sled::{Event, Subscriber};
+use std::io;
+
+/// Item of a `Forwarded` stream.
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum Forward<T> {
+    Event(T),
+    /// Events were dropped because the channel was full.
+    Lagged,
+}
+
+/// Events of a sled subscriber, forwarded into a bounded channel by a thread.
+///
+/// Sled blocks reads and writes of the whole tree while one of its
+/// subscribers is full, so the sled subscriber is always drained. The async
+/// sled subscriber drops events that are polled while they are still being
+/// written, the thread uses the blocking iterator instead. Once the channel
+/// is full further events are dropped, and a `Lagged` is queued in their
+/// place before the next forwarded event.
+pub(crate) struct Forwarded<T> {
+    events: mpsc::Receiver<Forward<T>>,
+}
+
+impl<T: Send + 'static> Forwarded<T> {
+    /// Spawns the forwarding thread, fails if the thread couldn't be spawned.
+    pub fn spawn<F>(
+        name: &str,
+        subscriber: Subscriber,
+        capacity: usize,
+        mut map: F,
+    ) -> io::Result<Self>
+    where
+        F: FnMut(Event) -> Option<T> + Send + 'static,
+    {
+        let (mut tx, events) = mpsc::channel(capacity);
+        // every sender has a slot of its own, so the lag is queued while the
+        // buffer is full
+        let mut lag_tx = tx.clone();
+        let thread_name = name.to_string();
+        std::thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                let mut lagged = false;
+                for event in subscriber {
+                    let event = match map(event) {
+                        Some(event) => event,
+                        None => continue,
+                    };
+                    if lagged {
+                        match send_lag(&mut lag_tx, &mut tx) {
+                            Ok(()) => lagged = false,
+                            Err(err) if err.is_full() => continue,
+                            Err(_) => break,
+                        }
+                    }
+                    match tx.try_send(Forward::Event(event)) {
+                        Ok(()) => {}
+                        Err(err) if err.is_full() => match send_lag(&mut lag_tx, &mut tx) {
+                            Ok(()) => log::warn!("{} lagged, dropping events", thread_name),
+                            Err(_) => lagged = true,
+                        },
+                        // the receiver was dropped
+                        Err(_) => break,
+                    }
+                }
+            })?;
+        Ok(Self { events })
+    }
+}
+
+/// Queues a `Lagged` in the slot of `lag_tx`, or in the buffer while the
+/// previous lag still holds that slot.
+fn send_lag<T>(
+    lag_tx: &mut mpsc::Sender<Forward<T>>,
+    tx: &mut mpsc::Sender<Forward<T>>,
+) -> Result<(), mpsc::TrySendError<Forward<T>>> {
+    lag_tx.try_send(Forward::Lagged).or_else(|err| {
+        if err.is_full() {
+            tx.try_send(err.into_inner())
+        } else {
+            Err(err)
+        }
+    })
+}
+
+impl<T> Stream for Forwarded<T> {
+    type Item = Forward<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.events).poll_next(ctx)
+    }
+}
@@ -1,3 +1,4 @@
+use crate::storage::forward::{Forward, Forwarded};
 use crate::storage::key::Key;
 use crate::storage::Storage;
 use async_std::prelude::*;
@@ -5,23 +6,38 @@ use async_std::task::{Context, Poll};
 use core::convert::TryFrom;
 use core::pin::Pin;
 use libipld::cid::Cid;
-use sled::{Event, Subscriber};
+use libipld::error::Result;
+use sled::Event;
+
+/// Number of unpins buffered before the subscriber lags.
+const GC_CAPACITY: usize = 1024;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum GcEvent {
     Unpin(Cid),
+    /// The subscriber fell behind and unpins were dropped.
+    Lagged,
 }
 
 pub struct GcSubscriber {
-    pin: Subscriber,
+    pin: Forwarded<Cid>,
 }
 
 impl Storage {
-    pub fn watch_gc(&self) -> GcSubscriber {
+    pub fn watch_gc(&self) -> Result<GcSubscriber> {
         log::trace!("watching pin() with prefix {:?}", Key::Pin.prefix());
-        GcSubscriber {
-            pin: self.tree.watch_prefix(Key::Pin.prefix()),
-        }
+        let subscriber = self.tree.watch_prefix(Key::Pin.prefix());
+        Ok(GcSubscriber {
+            pin: Forwarded::spawn(
+                "gc subscriber",
+                subscriber,
+                GC_CAPACITY,
+                |event| match event {
+                    Event::Remove { key } => Some(Cid::try_from(&key[1..]).expect("valid cid")),
+                    Event::Insert { .. } => None,
+                },
+            )?,
+        })
     }
 }
 
@@ -29,18 +45,14 @@ impl Stream for GcSubscriber {
     type Item = GcEvent;
 
     fn poll_next(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Option<Self::Item>> {
-        loop {
-            match Pin::new(&mut self.pin).poll(ctx) {
-                Poll::Ready(Some(Event::Remove { key })) => {
-                    let cid = Cid::try_from(&key[1..]).expect("valid cid");
+        Pin::new(&mut self.pin)
+            .poll_next(ctx)
+            .map(|event| match event? {
+                Forward::Event(cid) => {
                     log::trace!("emit unpin event {}", cid.to_string());
-                    return Poll::Ready(Some(GcEvent::Unpin(cid)));
+                    Some(GcEvent::Unpin(cid))
                 }
-                Poll::Ready(Some(_)) => continue,
-                Poll::Ready(None) => return Poll::Ready(None),
-                Poll::Pending => break,
-            }
-        }
-        Poll::Pending
+                Forward::Lagged => Some(GcEvent::Lagged),
+            })
     }
 }
@@ -13,6 +13,13 @@ pub enum Key {
     Want,
     Refs,
     Refer,
+    Atime,
+    Label,
+    Named,
+    Format,
+    Root,
+    Upgrade,
+    Evict,
 }
 
 impl Key {
@@ -58,6 +65,57 @@ impl Key {
     pub fn refer(cid: &Cid) -> IVec {
         Self::Refer.cid_key(cid)
     }
+
+    pub fn atime(cid: &Cid) -> IVec {
+        Self::Atime.cid_key(cid)
+    }
+
+    /// Prefix of the labels of a cid. The cid is length prefixed so that the
+    /// label can be told apart from it.
+    pub fn labels(cid: &Cid) -> IVec {
+        let cid = cid.to_bytes();
+        let mut key = Vec::with_capacity(cid.len() + 2);
+        key.push(Self::Label as u8);
+        key.push(cid.len() as u8);
+        key.extend_from_slice(&cid);
+        key.into()
+    }
+
+    pub fn label(cid: &Cid, label: &str) -> IVec {
+        let mut key = Self::labels(cid).to_vec();
+        key.extend_from_slice(label.as_bytes());
+        key.into()
+    }
+
+    /// Number of labels of a cid.
+    pub fn named(cid: &Cid) -> IVec {
+        Self::Named.cid_key(cid)
+    }
+
+    /// Version of the format of the block values.
+    pub fn format() -> IVec {
+        Self::Format.prefix()
+    }
+
+    pub fn root(cid: &Cid) -> IVec {
+        Self::Root.cid_key(cid)
+    }
+
+    /// Key of the last block value rewritten by an unfinished upgrade.
+    pub fn upgrade() -> IVec {
+        Self::Upgrade.prefix()
+    }
+
+    /// Entry of a block that isn't pinned or referenced in the eviction
+    /// order. The access time is big endian so that entries sort by it.
+    pub fn evict(atime: u64, cid: &Cid) -> IVec {
+        let cid = cid.to_bytes();
+        let mut key = Vec::with_capacity(cid.len() + 9);
+        key.push(Self::Evict as u8);
+        key.extend_from_slice(&atime.to_be_bytes());
+        key.extend_from_slice(&cid);
+        key.into()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -106,6 +164,36 @@ impl From<Value> for u32 {
     }
 }
 
+impl From<i32> for Value {
+    fn from(n: i32) -> Self {
+        let bytes = n.to_le_bytes();
+        Self(IVec::from(&bytes[..]))
+    }
+}
+
+impl From<Value> for i32 {
+    fn from(value: Value) -> Self {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(&value.0);
+        i32::from_le_bytes(buf)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(n: u64) -> Self {
+        let bytes = n.to_le_bytes();
+        Self(IVec::from(&bytes[..]))
+    }
+}
+
+impl From<Value> for u64 {
+    fn from(value: Value) -> Self {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(&value.0);
+        u64::from_le_bytes(buf)
+    }
+}
+
 impl From<Cid> for Value {
     fn from(cid: Cid) -> Self {
         Self(cid.to_bytes().into())
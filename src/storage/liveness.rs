@@ -0,0 +1,81 @@
+use crate::storage::compression;
+use crate::storage::key::{Key, Value};
+use libipld::cid::Cid;
+use libipld::error::Error;
+use sled::transaction::{ConflictableTransactionResult, TransactionalTree};
+use std::collections::HashMap;
+
+/// Stored size and access time of a block that is neither pinned nor
+/// referenced, `None` if the block is live or isn't stored. The access time
+/// is only read for the eviction index.
+fn dead_block(
+    tree: &TransactionalTree,
+    cid: &Cid,
+    evict: bool,
+) -> ConflictableTransactionResult<Option<(usize, u64)>, Error> {
+    if tree.get(Key::pin(cid))?.is_some() || tree.get(Key::refer(cid))?.is_some() {
+        return Ok(None);
+    }
+    let size = match tree.get(Key::block(cid))? {
+        Some(block) => compression::stored_size(&block),
+        None => return Ok(None),
+    };
+    let atime = if evict {
+        tree.get(Key::atime(cid))?
+            .map(|b| Value::from(b).into())
+            .unwrap_or_default()
+    } else {
+        0
+    };
+    Ok(Some((size, atime)))
+}
+
+/// Blocks whose pins, referers, data or access time a transaction changes,
+/// with their dead size and access time from before the change.
+pub struct Liveness {
+    /// Keeps the eviction index of the dead blocks up to date.
+    evict: bool,
+    before: HashMap<Cid, Option<(usize, u64)>>,
+}
+
+impl Liveness {
+    pub fn new(evict: bool) -> Self {
+        Self {
+            evict,
+            before: Default::default(),
+        }
+    }
+
+    /// Records the dead size of a block before the transaction changes it.
+    pub fn track(
+        &mut self,
+        tree: &TransactionalTree,
+        cid: &Cid,
+    ) -> ConflictableTransactionResult<(), Error> {
+        if !self.before.contains_key(cid) {
+            let dead = dead_block(tree, cid, self.evict)?;
+            self.before.insert(cid.clone(), dead);
+        }
+        Ok(())
+    }
+
+    /// Moves the tracked blocks in the eviction index and returns by how much
+    /// the transaction changed the size of the dead blocks.
+    pub fn finish(self, tree: &TransactionalTree) -> ConflictableTransactionResult<i64, Error> {
+        let mut delta = 0;
+        for (cid, before) in self.before {
+            let after = dead_block(tree, &cid, self.evict)?;
+            if self.evict && before.map(|(_, atime)| atime) != after.map(|(_, atime)| atime) {
+                if let Some((_, atime)) = before {
+                    tree.remove(Key::evict(atime, &cid))?;
+                }
+                if let Some((_, atime)) = after {
+                    tree.insert(Key::evict(atime, &cid), Value::from(true))?;
+                }
+            }
+            let size = |dead: Option<(usize, u64)>| dead.map(|(size, _)| size).unwrap_or_default();
+            delta += size(after) as i64 - size(before) as i64;
+        }
+        Ok(delta)
+    }
+}
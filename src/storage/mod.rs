@@ -1,4 +1,5 @@
 use crate::storage::key::{Key, Value};
+use crate::storage::liveness::Liveness;
 use core::convert::TryFrom;
 use core::future::Future;
 use core::pin::Pin;
@@ -6,56 +7,321 @@ use core::task::{Context, Poll};
 use libipld::block::{Block, Visibility};
 use libipld::cid::Cid;
 use libipld::codec::Codec;
-use libipld::error::{BlockTooLarge, EmptyBatch, Error, Result};
+use libipld::error::{
+    BlockNotFound, BlockTooLarge, EmptyBatch, Error, InvalidMultihash, Result, UnsupportedMultihash,
+};
 use libipld::multihash::MultihashDigest;
-use sled::{transaction::TransactionError, Event, IVec, Subscriber, Tree};
-use std::collections::HashSet;
+use libp2p_bitswap::Priority;
+use sled::transaction::{abort, TransactionError};
+use sled::{Batch, Event, IVec, Subscriber, Tree};
+use std::collections::{HashMap, HashSet};
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
 
+mod blocks;
+mod compression;
+mod forward;
 mod gc;
 mod key;
+mod liveness;
 mod network;
 
+pub use compression::UnsupportedFormat;
 pub use gc::{GcEvent, GcSubscriber};
 pub use network::{NetworkEvent, NetworkSubscriber};
 
+/// Priority of wants that don't request a specific priority.
+pub const DEFAULT_PRIORITY: Priority = 1000;
+
+/// The block isn't stored locally. The supplied string is a CID.
+#[derive(Debug, Error)]
+#[error("Block {0} is not stored locally.")]
+pub struct BlockNotLocal(pub String);
+
+/// The want of a block timed out. The supplied string is a CID.
+#[derive(Debug, Error)]
+#[error("Want for block {0} timed out.")]
+pub struct WantTimeout(pub String);
+
+/// Checks a block read from the tree.
+pub type Verify = fn(&Cid, &[u8]) -> Result<()>;
+
+/// Checks that the data matches the digest of the cid.
+pub fn verify<M: MultihashDigest>(cid: &Cid, data: &[u8]) -> Result<()> {
+    let code = cid.hash().code();
+    let hash = M::new(code, data).map_err(|_| UnsupportedMultihash(code))?;
+    if hash.digest() != cid.hash().digest() {
+        return Err(InvalidMultihash(hash.to_bytes()).into());
+    }
+    Ok(())
+}
+
 #[derive(Debug, Clone)]
 pub struct Storage {
     tree: Tree,
+    /// Number of pending gets of a block. They share a single want that is
+    /// removed when the last get is dropped.
+    getters: Arc<Mutex<HashMap<Cid, usize>>>,
+    /// Blocks with pending gets whose want timed out.
+    timed_out: Arc<Mutex<HashSet<Cid>>>,
+    /// Size limit of the blocks. When it is exceeded the least recently
+    /// accessed blocks that aren't pinned or referenced are evicted.
+    max_bytes: Option<u64>,
+    /// Total size of the blocks, only tracked with a size limit.
+    size: Arc<AtomicU64>,
+    /// Total size of the blocks that aren't pinned or referenced.
+    dead: Arc<AtomicU64>,
+    /// Key of the last block scanned by `collect_dead`, the next call
+    /// resumes after it.
+    collect_cursor: Arc<Mutex<Option<IVec>>>,
+    /// Logical clock for the access times of blocks.
+    clock: Arc<AtomicU64>,
+    /// Checks blocks read from the tree against their cid.
+    verify: Option<Verify>,
+    /// Size limit of a single block.
+    max_block_size: usize,
+    /// Number of inserted blocks after which the tree is flushed.
+    flush_every: Option<u64>,
+    /// Blocks inserted since the last flush, only tracked with `flush_every`.
+    unflushed: Arc<AtomicU64>,
+    /// Gets of blocks that aren't stored locally fail instead of wanting
+    /// them from the network.
+    offline: bool,
+    /// Deflate level of inserted blocks, uncompressed if `None`.
+    compression_level: Option<u32>,
+    /// Number of events buffered for each `watch_network` subscriber.
+    network_capacity: usize,
 }
 
 impl Storage {
-    pub fn new(tree: sled::Tree) -> Result<Self> {
+    pub fn new(tree: sled::Tree, max_bytes: Option<u64>) -> Result<Self> {
+        compression::upgrade(&tree)?;
         // cleanup wanted on startup
         for key in tree.scan_prefix(Key::Want.prefix()).keys() {
             tree.remove(key?)?;
         }
-        Ok(Self { tree })
+        let mut size = 0;
+        let mut clock = 0;
+        if max_bytes.is_some() {
+            for block in tree.scan_prefix(Key::Block.prefix()).values() {
+                size += compression::stored_size(&block?) as u64;
+            }
+            for atime in tree.scan_prefix(Key::Atime.prefix()).values() {
+                clock = clock.max(u64::from(Value::from(atime?)) + 1);
+            }
+        }
+        let dead = count_dead(&tree, max_bytes.is_some())?;
+        Ok(Self {
+            tree,
+            getters: Default::default(),
+            timed_out: Default::default(),
+            max_bytes,
+            size: Arc::new(AtomicU64::new(size)),
+            dead: Arc::new(AtomicU64::new(dead)),
+            collect_cursor: Default::default(),
+            clock: Arc::new(AtomicU64::new(clock)),
+            verify: None,
+            max_block_size: crate::MAX_BLOCK_SIZE,
+            flush_every: None,
+            unflushed: Default::default(),
+            offline: false,
+            compression_level: None,
+            network_capacity: network::DEFAULT_NETWORK_CAPACITY,
+        })
+    }
+
+    /// Checks every block returned by `get_local` with `verify`, so corrupted
+    /// data isn't served.
+    pub fn set_verify(&mut self, verify: Verify) {
+        self.verify = Some(verify);
+    }
+
+    /// Rejects blocks larger than `max_block_size` with `BlockTooLarge`.
+    pub fn set_max_block_size(&mut self, max_block_size: usize) {
+        self.max_block_size = max_block_size;
+    }
+
+    pub fn max_block_size(&self) -> usize {
+        self.max_block_size
+    }
+
+    /// Flushes the tree after every `flush_every` inserted blocks.
+    pub fn set_flush_every(&mut self, flush_every: Option<u64>) {
+        self.flush_every = flush_every;
+    }
+
+    /// Makes `get` fail with `BlockNotLocal` for blocks that aren't stored
+    /// locally instead of wanting them.
+    pub fn set_offline(&mut self, offline: bool) {
+        self.offline = offline;
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    /// Compresses inserted blocks with deflate at `level`. Blocks that are
+    /// already stored aren't recompressed.
+    #[cfg(feature = "compression")]
+    pub fn set_compression(&mut self, level: Option<u32>) {
+        self.compression_level = level;
+    }
+
+    /// Counts inserted blocks and flushes once `flush_every` is reached.
+    fn count_unflushed(&self, blocks: usize) -> Result<()> {
+        let flush_every = match self.flush_every {
+            Some(flush_every) => flush_every,
+            None => return Ok(()),
+        };
+        let unflushed = self.unflushed.fetch_add(blocks as u64, Ordering::SeqCst) + blocks as u64;
+        if unflushed >= flush_every {
+            log::trace!("flushing after {} blocks", unflushed);
+            self.unflushed.store(0, Ordering::SeqCst);
+            self.tree.flush()?;
+        }
+        Ok(())
     }
 
     pub fn get_local(&self, cid: &Cid) -> Result<Option<IVec>> {
         log::trace!("get_local {}", cid.to_string());
-        Ok(self.tree.get(Key::block(cid))?)
+        let block = match self.tree.get(Key::block(cid))? {
+            Some(value) => Some(compression::decode(cid, value)?),
+            None => None,
+        };
+        if let (Some(block), Some(verify)) = (&block, self.verify) {
+            verify(cid, block)?;
+        }
+        if block.is_some() && self.max_bytes.is_some() {
+            self.touch(cid)?;
+        }
+        Ok(block)
+    }
+
+    /// Updates the access time of a block.
+    fn touch(&self, cid: &Cid) -> Result<()> {
+        let atime = self.clock.fetch_add(1, Ordering::SeqCst);
+        self.tree
+            .transaction::<_, _, Error>(|tree| {
+                let mut liveness = self.liveness();
+                liveness.track(tree, cid)?;
+                tree.insert(Key::atime(cid), Value::from(atime))?;
+                liveness.finish(tree)?;
+                Ok(())
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => Error::from(e),
+            })
+    }
+
+    /// Tracks the dead blocks of a transaction, and keeps their eviction
+    /// index with a size limit.
+    fn liveness(&self) -> Liveness {
+        Liveness::new(self.max_bytes.is_some())
+    }
+
+    /// Returns `len` bytes of a block starting at `offset`, or fewer if the
+    /// block ends before. The block is read and verified like with
+    /// `get_local`, the returned bytes share its buffer.
+    pub fn get_range(&self, cid: &Cid, offset: usize, len: usize) -> Result<Option<IVec>> {
+        log::trace!("get_range {} {}+{}", cid.to_string(), offset, len);
+        Ok(self.get_local(cid)?.map(|block| {
+            let start = offset.min(block.len());
+            let len = len.min(block.len() - start);
+            block.subslice(start, len)
+        }))
+    }
+
+    /// Returns true if the block is stored locally. Unlike `get_local` the
+    /// block isn't verified and the access isn't recorded for eviction.
+    pub fn contains(&self, cid: &Cid) -> Result<bool> {
+        Ok(self.tree.contains_key(Key::block(cid))?)
+    }
+
+    /// Total size of the blocks. Only tracked when a size limit is set.
+    pub fn size(&self) -> u64 {
+        self.size.load(Ordering::SeqCst)
     }
 
-    pub async fn get(&self, cid: &Cid) -> Result<IVec> {
-        log::trace!("get {}", cid.to_string());
+    fn add_size(&self, bytes: usize) {
+        if self.max_bytes.is_some() {
+            self.size.fetch_add(bytes as u64, Ordering::SeqCst);
+        }
+    }
+
+    fn sub_size(&self, bytes: usize) {
+        if self.max_bytes.is_some() {
+            self.size.fetch_sub(bytes as u64, Ordering::SeqCst);
+        }
+    }
+
+    /// Applies the change of the dead bytes by a transaction.
+    fn add_dead(&self, delta: i64) {
+        self.dead
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |dead| {
+                Some((dead as i64 + delta).max(0) as u64)
+            })
+            .ok();
+    }
+
+    /// Gets a block, asking the network for it with `priority` if it isn't
+    /// stored locally. Concurrent gets of the same block share a single want
+    /// with the highest requested priority.
+    pub async fn get(&self, cid: &Cid, priority: Priority) -> Result<IVec> {
+        log::trace!("get {} with priority {}", cid.to_string(), priority);
         let key = Key::block(cid);
-        if let Some(block) = self.tree.get(&key)? {
+        if let Some(block) = self.get_local(cid)? {
             return Ok(block);
         }
+        if self.offline {
+            return Err(BlockNotLocal(cid.to_string()).into());
+        }
         let subscription = self.tree.watch_prefix(&key);
-        if let Some(block) = self.tree.get(&key)? {
+        if let Some(block) = self.get_local(cid)? {
             return Ok(block);
         }
-        self.tree.insert(Key::want(cid), Value::from(true))?;
+        let want_key = Key::want(cid);
+        let want = self.tree.watch_prefix(&want_key);
         log::trace!("watching block({}) with prefix {:?}", cid.to_string(), key);
-        GetFuture {
+        let future = GetFuture {
             tree: self.tree.clone(),
+            getters: self.getters.clone(),
+            timed_out: self.timed_out.clone(),
             subscription,
+            want,
             key,
+            want_key: want_key.clone(),
             cid: cid.clone(),
+        };
+        {
+            // the future removes the want once the last getter is dropped
+            let mut getters = self.getters.lock().unwrap();
+            *getters.entry(cid.clone()).or_default() += 1;
+            let wanted = self.tree.get(&want_key)?.map(|v| i32::from(Value::from(v)));
+            if wanted.map(|wanted| wanted < priority).unwrap_or(true) {
+                self.tree.insert(&want_key, Value::from(priority))?;
+            }
+        }
+        future.await
+    }
+
+    /// Aborts pending gets of a block that can't be found on the network.
+    pub fn cancel(&self, cid: &Cid) -> Result<()> {
+        log::trace!("cancel {}", cid.to_string());
+        self.tree.remove(Key::want(cid))?;
+        Ok(())
+    }
+
+    /// Aborts pending gets of a block with a `WantTimeout` error.
+    pub fn timeout(&self, cid: &Cid) -> Result<()> {
+        log::trace!("timeout {}", cid.to_string());
+        if self.getters.lock().unwrap().contains_key(cid) {
+            self.timed_out.lock().unwrap().insert(cid.clone());
         }
-        .await
+        self.tree.remove(Key::want(cid))?;
+        Ok(())
     }
 
     pub fn insert<C: Codec, M: MultihashDigest>(&self, block: &Block<C, M>) -> Result<()> {
@@ -66,31 +332,53 @@ impl Storage {
 
     pub fn insert_batch<C: Codec, M: MultihashDigest>(&self, batch: &[Block<C, M>]) -> Result<Cid> {
         log::trace!("insert_batch");
+        self.insert_blocks(batch, true)?;
+        Ok(batch.last().unwrap().cid.clone())
+    }
+
+    /// Inserts blocks in a single transaction, pinning the last one with
+    /// `pin` in the same transaction. Returns the cids of the blocks that
+    /// weren't stored yet.
+    pub fn insert_blocks<C: Codec, M: MultihashDigest>(
+        &self,
+        batch: &[Block<C, M>],
+        pin: bool,
+    ) -> Result<Vec<Cid>> {
         let blocks: Result<Vec<_>> = batch
             .iter()
             .map(|block| {
-                if block.data.len() > crate::MAX_BLOCK_SIZE {
+                if block.data.len() > self.max_block_size {
                     return Err(BlockTooLarge(block.data.len()).into());
                 }
                 let refs = block.decode_ipld()?.references();
                 let encoded = Value::from(&refs);
-                Ok((&block.cid, &block.data, refs, encoded, block.visibility()))
+                let value = compression::encode(&block.data, self.compression_level)?;
+                Ok((&block.cid, value, refs, encoded, block.visibility()))
             })
             .collect();
         let blocks = blocks?;
         if blocks.is_empty() {
             return Err(EmptyBatch.into());
         }
-        let last_cid = self
+        let atime = self.clock.fetch_add(1, Ordering::SeqCst);
+        let (inserted, bytes, dead) = self
             .tree
             .transaction::<_, _, Error>(|tree| {
-                let mut last_cid = None;
-                for (cid, data, refs, encoded_refs, visibility) in &blocks {
-                    last_cid = Some(cid);
+                let mut inserted = vec![];
+                let mut bytes = 0;
+                let mut liveness = self.liveness();
+                for (cid, value, refs, encoded_refs, visibility) in &blocks {
                     if tree.get(Key::block(cid))?.is_some() {
                         continue;
                     }
+                    liveness.track(tree, cid)?;
+                    inserted.push((*cid).clone());
+                    bytes += compression::stored_size(value);
+                    if self.max_bytes.is_some() {
+                        tree.insert(Key::atime(cid), Value::from(atime))?;
+                    }
                     for cid in refs {
+                        liveness.track(tree, cid)?;
                         let refer_key = Key::refer(cid);
                         let refer: u32 = tree
                             .get(refer_key.clone())?
@@ -98,14 +386,18 @@ impl Storage {
                             .unwrap_or_default();
                         tree.insert(refer_key, Value::from(refer + 1))?;
                     }
-                    tree.insert(Key::block(cid), &data[..])?;
+                    tree.insert(Key::block(cid), &value[..])?;
                     tree.insert(Key::refs(cid), encoded_refs.clone())?;
                     if let Visibility::Public = visibility {
                         tree.insert(Key::public(cid), Value::from(true))?;
                     }
                     tree.remove(Key::want(cid))?;
                 }
-                let last_cid = last_cid.unwrap();
+                if !pin {
+                    return Ok((inserted, bytes, liveness.finish(tree)?));
+                }
+                let (last_cid, _, _, _, _) = blocks.last().unwrap();
+                liveness.track(tree, last_cid)?;
                 let pin_key = Key::pin(last_cid);
                 if let Some(pin) = tree.get(&pin_key)? {
                     log::trace!("duplicate incrementing pin count");
@@ -113,25 +405,202 @@ impl Storage {
                 } else {
                     tree.insert(pin_key, Value::from(1))?;
                 }
-                Ok((*last_cid).clone())
+                Ok((inserted, bytes, liveness.finish(tree)?))
             })
             .map_err(|e| match e {
                 TransactionError::Abort(e) => e,
                 TransactionError::Storage(e) => Error::from(e),
             })?;
-        Ok(last_cid)
+        self.add_size(bytes);
+        self.add_dead(dead);
+        self.evict()?;
+        self.count_unflushed(inserted.len())?;
+        Ok(inserted)
+    }
+
+    /// Evicts the least recently accessed blocks that aren't pinned or
+    /// referenced until the size limit is met. Public blocks that are evicted
+    /// stop being provided.
+    fn evict(&self) -> Result<()> {
+        let max_bytes = match self.max_bytes {
+            Some(max_bytes) => max_bytes,
+            None => return Ok(()),
+        };
+        if self.size() <= max_bytes {
+            return Ok(());
+        }
+        while self.size() > max_bytes {
+            let key = match self.tree.scan_prefix(Key::Evict.prefix()).keys().next() {
+                Some(key) => key?,
+                None => {
+                    log::debug!("pinned blocks exceed the size limit");
+                    break;
+                }
+            };
+            // the cid follows the prefix and the access time
+            let cid = Cid::try_from(&key[9..])?;
+            log::debug!("evicting {}", cid.to_string());
+            match self.remove_one(&cid)? {
+                Some((_, size)) => self.sub_size(size),
+                None => {
+                    log::warn!("removing stale eviction entry of {}", cid.to_string());
+                    self.tree.remove(key)?;
+                }
+            }
+        }
+        Ok(())
     }
 
     pub async fn flush(&self) -> Result<()> {
         log::trace!("flush");
+        self.unflushed.store(0, Ordering::SeqCst);
         self.tree.flush_async().await?;
         Ok(())
     }
 
-    pub fn unpin(&self, cid: &Cid) -> Result<()> {
-        log::trace!("unpin {}", cid.to_string());
+    pub fn pin(&self, cid: &Cid) -> Result<()> {
+        log::trace!("pin {}", cid.to_string());
+        let dead = self
+            .tree
+            .transaction::<_, _, Error>(|tree| {
+                if tree.get(Key::block(cid))?.is_none() {
+                    return abort(BlockNotLocal(cid.to_string()).into());
+                }
+                let mut liveness = self.liveness();
+                liveness.track(tree, cid)?;
+                let pin_key = Key::pin(cid);
+                let pin: u32 = tree
+                    .get(&pin_key)?
+                    .map(|b| Value::from(b).into())
+                    .unwrap_or_default();
+                tree.insert(pin_key, Value::from(pin + 1))?;
+                liveness.finish(tree)
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => Error::from(e),
+            })?;
+        self.add_dead(dead);
+        Ok(())
+    }
+
+    /// Pins a cid under a label. The label holds one pin until it is removed
+    /// with `unpin_named`, pinning a cid twice under the same label does
+    /// nothing.
+    pub fn pin_named(&self, cid: &Cid, label: &str) -> Result<()> {
+        log::trace!("pin {} as {}", cid.to_string(), label);
+        let dead = self
+            .tree
+            .transaction::<_, _, Error>(|tree| {
+                if tree.get(Key::block(cid))?.is_none() {
+                    return abort(BlockNotLocal(cid.to_string()).into());
+                }
+                if tree
+                    .insert(Key::label(cid, label), Value::from(true))?
+                    .is_some()
+                {
+                    return Ok(0);
+                }
+                let mut liveness = self.liveness();
+                liveness.track(tree, cid)?;
+                let named_key = Key::named(cid);
+                let named: u32 = tree
+                    .get(&named_key)?
+                    .map(|b| Value::from(b).into())
+                    .unwrap_or_default();
+                tree.insert(named_key, Value::from(named + 1))?;
+                let pin_key = Key::pin(cid);
+                let pin: u32 = tree
+                    .get(&pin_key)?
+                    .map(|b| Value::from(b).into())
+                    .unwrap_or_default();
+                tree.insert(pin_key, Value::from(pin + 1))?;
+                liveness.finish(tree)
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => Error::from(e),
+            })?;
+        self.add_dead(dead);
+        Ok(())
+    }
+
+    /// Makes a block public or private. Public blocks are provided, so
+    /// making a block public emits `Provide` and making it private emits
+    /// `Unprovide`. Private blocks aren't roots. Aborts with `BlockNotLocal`
+    /// if the block isn't stored, because we couldn't serve it.
+    pub fn set_public(&self, cid: &Cid, public: bool) -> Result<()> {
+        log::trace!("set public {} {}", cid.to_string(), public);
         self.tree
             .transaction::<_, _, Error>(|tree| {
+                if tree.get(Key::block(cid))?.is_none() {
+                    return abort(BlockNotLocal(cid.to_string()).into());
+                }
+                if public {
+                    tree.insert(Key::public(cid), Value::from(true))?;
+                } else if tree.get(Key::public(cid))?.is_some() {
+                    tree.remove(Key::public(cid))?;
+                    tree.remove(Key::root(cid))?;
+                }
+                Ok(())
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => Error::from(e),
+            })?;
+        Ok(())
+    }
+
+    /// Marks a block as the root of a DAG, which emits `Root` and makes it
+    /// public, or unmarks it, which emits `Unroot`. With `provide_roots_only`
+    /// roots are the only blocks that are provided in the DHT. Aborts with
+    /// `BlockNotLocal` if the block isn't stored.
+    pub fn set_root(&self, cid: &Cid, root: bool) -> Result<()> {
+        log::trace!("set root {} {}", cid.to_string(), root);
+        self.tree
+            .transaction::<_, _, Error>(|tree| {
+                if tree.get(Key::block(cid))?.is_none() {
+                    return abort(BlockNotLocal(cid.to_string()).into());
+                }
+                if root {
+                    tree.insert(Key::root(cid), Value::from(true))?;
+                    tree.insert(Key::public(cid), Value::from(true))?;
+                } else if tree.get(Key::root(cid))?.is_some() {
+                    tree.remove(Key::root(cid))?;
+                }
+                Ok(())
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => Error::from(e),
+            })?;
+        Ok(())
+    }
+
+    /// Checks if a block is marked as a root.
+    pub fn is_root(&self, cid: &Cid) -> Result<bool> {
+        Ok(self.tree.contains_key(Key::root(cid))?)
+    }
+
+    /// Removes the pin of a label. Does nothing if the cid isn't pinned
+    /// under the label.
+    pub fn unpin_named(&self, cid: &Cid, label: &str) -> Result<()> {
+        log::trace!("unpin {} as {}", cid.to_string(), label);
+        let dead = self
+            .tree
+            .transaction::<_, _, Error>(|tree| {
+                if tree.remove(Key::label(cid, label))?.is_none() {
+                    return Ok(0);
+                }
+                let mut liveness = self.liveness();
+                liveness.track(tree, cid)?;
+                let named_key = Key::named(cid);
+                if let Some(named) = tree.remove(&named_key)? {
+                    let named: u32 = Value::from(named).into();
+                    if named > 1 {
+                        tree.insert(named_key, Value::from(named - 1))?;
+                    }
+                }
                 let pin_key = Key::pin(cid);
                 if let Some(pin) = tree.remove(&pin_key)? {
                     let pin: u32 = Value::from(pin).into();
@@ -139,16 +608,161 @@ impl Storage {
                         tree.insert(pin_key, Value::from(pin - 1))?;
                     }
                 }
-                Ok(())
+                liveness.finish(tree)
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => Error::from(e),
+            })?;
+        self.add_dead(dead);
+        Ok(())
+    }
+
+    /// Returns the labels a cid is pinned under, in key order.
+    pub fn labels(&self, cid: &Cid) -> Result<Vec<String>> {
+        let prefix = Key::labels(cid);
+        let mut labels = vec![];
+        for key in self.tree.scan_prefix(&prefix).keys() {
+            let key = key?;
+            labels.push(String::from_utf8_lossy(&key[prefix.len()..]).into_owned());
+        }
+        Ok(labels)
+    }
+
+    /// Pins a cid and all blocks reachable from it in a single transaction.
+    /// Aborts with `BlockNotLocal` without pinning anything if one of the
+    /// blocks isn't stored. Returns the number of pinned blocks.
+    pub fn pin_recursive(&self, cid: &Cid) -> Result<usize> {
+        log::trace!("pin recursive {}", cid.to_string());
+        let mut visited = HashSet::new();
+        let mut stack = vec![cid.clone()];
+        while let Some(cid) = stack.pop() {
+            if visited.contains(&cid) {
+                continue;
+            }
+            if !self.tree.contains_key(Key::block(&cid))? {
+                return Err(BlockNotLocal(cid.to_string()).into());
+            }
+            let refs: HashSet<Cid> = self
+                .tree
+                .get(Key::refs(&cid))?
+                .map(|b| Value::from(b).into())
+                .unwrap_or_default();
+            stack.extend(refs);
+            visited.insert(cid);
+        }
+        let dead = self
+            .tree
+            .transaction::<_, _, Error>(|tree| {
+                let mut liveness = self.liveness();
+                for cid in &visited {
+                    // the block might have been removed since the walk
+                    if tree.get(Key::block(cid))?.is_none() {
+                        return abort(BlockNotLocal(cid.to_string()).into());
+                    }
+                    liveness.track(tree, cid)?;
+                    let pin_key = Key::pin(cid);
+                    let pin: u32 = tree
+                        .get(&pin_key)?
+                        .map(|b| Value::from(b).into())
+                        .unwrap_or_default();
+                    tree.insert(pin_key, Value::from(pin + 1))?;
+                }
+                liveness.finish(tree)
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => Error::from(e),
+            })?;
+        self.add_dead(dead);
+        Ok(visited.len())
+    }
+
+    /// Returns an iterator over the blocks reachable from `root` in depth
+    /// first order. Blocks are read as the iterator advances, so only the
+    /// cids of the visited blocks are kept in memory.
+    pub fn walk(&self, root: &Cid) -> Walk<'_> {
+        Walk {
+            storage: self,
+            visited: Default::default(),
+            stack: vec![root.clone()],
+        }
+    }
+
+    /// Returns the blocks reachable from `root` that aren't stored locally.
+    /// The references of missing blocks are unknown, so these are the
+    /// blocks that need to be fetched before the rest of the DAG is known.
+    pub fn missing(&self, root: &Cid) -> Result<Vec<Cid>> {
+        let mut missing = vec![];
+        for res in self.walk(root) {
+            if let (cid, None) = res? {
+                missing.push(cid);
+            }
+        }
+        Ok(missing)
+    }
+
+    pub fn unpin(&self, cid: &Cid) -> Result<()> {
+        log::trace!("unpin {}", cid.to_string());
+        let dead = self
+            .tree
+            .transaction::<_, _, Error>(|tree| {
+                let pin_key = Key::pin(cid);
+                let pin: u32 = match tree.get(&pin_key)? {
+                    Some(pin) => Value::from(pin).into(),
+                    None => return Ok(0),
+                };
+                // the pins of labels are only removed by `unpin_named`
+                let named: u32 = tree
+                    .get(Key::named(cid))?
+                    .map(|b| Value::from(b).into())
+                    .unwrap_or_default();
+                if pin <= named {
+                    return Ok(0);
+                }
+                let mut liveness = self.liveness();
+                liveness.track(tree, cid)?;
+                if pin > 1 {
+                    tree.insert(pin_key, Value::from(pin - 1))?;
+                } else {
+                    tree.remove(pin_key)?;
+                }
+                liveness.finish(tree)
             })
             .map_err(|e| match e {
                 TransactionError::Abort(e) => e,
                 TransactionError::Storage(e) => Error::from(e),
             })?;
+        self.add_dead(dead);
         Ok(())
     }
 
-    fn remove_one(&self, cid: &Cid) -> Result<Option<HashSet<Cid>>> {
+    /// Removes all pins, including the pins of labels, in a single batch.
+    /// An unpin event is emitted for each block. Blocks referenced by other
+    /// blocks stay live until their referers are removed. Returns the number
+    /// of unpinned blocks.
+    pub fn unpin_all(&self) -> Result<usize> {
+        log::trace!("unpin all");
+        let mut batch = Batch::default();
+        let mut unpinned = 0;
+        for key in self.tree.scan_prefix(Key::Pin.prefix()).keys() {
+            batch.remove(key?);
+            unpinned += 1;
+        }
+        for prefix in &[Key::Label.prefix(), Key::Named.prefix()] {
+            for key in self.tree.scan_prefix(prefix).keys() {
+                batch.remove(key?);
+            }
+        }
+        self.tree.apply_batch(batch)?;
+        self.dead.store(
+            count_dead(&self.tree, self.max_bytes.is_some())?,
+            Ordering::SeqCst,
+        );
+        Ok(unpinned)
+    }
+
+    fn remove_one(&self, cid: &Cid) -> Result<Option<(HashSet<Cid>, usize)>> {
         log::trace!("remove {}", cid.to_string());
         let res = self
             .tree
@@ -158,11 +772,19 @@ impl Storage {
                 if pinned || referers {
                     return Ok(None);
                 }
-                tree.remove(Key::block(cid))?;
+                let mut liveness = self.liveness();
+                liveness.track(tree, cid)?;
+                let block = match tree.remove(Key::block(cid))? {
+                    Some(block) => block,
+                    None => return Ok(None),
+                };
                 tree.remove(Key::public(cid))?;
+                tree.remove(Key::root(cid))?;
                 tree.remove(Key::want(cid))?;
+                tree.remove(Key::atime(cid))?;
                 let refs: HashSet<Cid> = Value::from(tree.remove(Key::refs(cid))?.unwrap()).into();
                 for cid in &refs {
+                    liveness.track(tree, cid)?;
                     let refer_key = Key::refer(cid);
                     if let Some(refer) = tree.remove(&refer_key)? {
                         let refer: u32 = Value::from(refer).into();
@@ -171,17 +793,22 @@ impl Storage {
                         }
                     }
                 }
-                Ok(Some(refs))
+                let size = compression::stored_size(&block);
+                Ok(Some((refs, size, liveness.finish(tree)?)))
             })
             .map_err(|e| match e {
                 TransactionError::Abort(e) => e,
                 TransactionError::Storage(e) => Error::from(e),
             })?;
-        Ok(res)
+        Ok(res.map(|(refs, size, dead)| {
+            self.add_dead(dead);
+            (refs, size)
+        }))
     }
 
     pub fn remove(&self, cid: &Cid) -> Result<()> {
-        if let Some(refs) = self.remove_one(cid)? {
+        if let Some((refs, size)) = self.remove_one(cid)? {
+            self.sub_size(size);
             for cid in refs {
                 self.remove(&cid)?;
             }
@@ -189,6 +816,198 @@ impl Storage {
         Ok(())
     }
 
+    /// Removes all blocks that aren't reachable from a pinned block. Public
+    /// blocks that are removed stop being provided. Blocks that are pinned or
+    /// referenced while it runs are kept. With `dry_run` the blocks that
+    /// would be removed are returned without removing them.
+    pub fn collect_garbage(&self, dry_run: bool) -> Result<Collected> {
+        log::trace!("collect garbage");
+        let live = self.mark_live()?;
+        if !dry_run {
+            return self.sweep(&live);
+        }
+        let mut collected = Collected::default();
+        for cid in self.blocks() {
+            let cid = cid?;
+            if live.contains(&cid) {
+                continue;
+            }
+            if let Some(block) = self.tree.get(Key::block(&cid))? {
+                collected.blocks.push(cid);
+                collected.bytes += compression::stored_size(&block);
+            }
+        }
+        Ok(collected)
+    }
+
+    /// Returns the blocks that are reachable from a pinned block.
+    fn mark_live(&self) -> Result<HashSet<Cid>> {
+        let mut live = HashSet::new();
+        let mut stack = self
+            .iter_prefix(Key::Pin.prefix())
+            .collect::<Result<Vec<_>>>()?;
+        while let Some(cid) = stack.pop() {
+            if !live.insert(cid.clone()) {
+                continue;
+            }
+            let refs: HashSet<Cid> = self
+                .tree
+                .get(Key::refs(&cid))?
+                .map(|b| Value::from(b).into())
+                .unwrap_or_default();
+            stack.extend(refs);
+        }
+        Ok(live)
+    }
+
+    /// Removes the blocks that aren't `live`. The marking isn't atomic with
+    /// the sweep, so each block is only removed if it is still neither pinned
+    /// nor referenced.
+    fn sweep(&self, live: &HashSet<Cid>) -> Result<Collected> {
+        let mut collected = Collected::default();
+        for cid in self.blocks() {
+            let cid = cid?;
+            if live.contains(&cid) {
+                continue;
+            }
+            // the referenced blocks can only be removed after their referers
+            let mut stack = vec![cid];
+            while let Some(cid) = stack.pop() {
+                if let Some((refs, size)) = self.remove_one(&cid)? {
+                    self.sub_size(size);
+                    collected.blocks.push(cid);
+                    collected.bytes += size;
+                    stack.extend(refs.into_iter().filter(|cid| !live.contains(cid)));
+                }
+            }
+        }
+        Ok(collected)
+    }
+
+    /// Total size of the blocks that aren't pinned or referenced. It is kept
+    /// up to date by the transactions that change pins and referers, so it
+    /// doesn't scan the blocks.
+    pub fn dead_bytes(&self) -> Result<u64> {
+        Ok(self.dead.load(Ordering::SeqCst))
+    }
+
+    /// Removes up to `max_blocks` blocks that aren't pinned or referenced.
+    /// The scan resumes after the last block scanned by the previous call and
+    /// wraps around once, so an empty result means no dead block was found.
+    /// The blocks they reference may become unreferenced, these are removed
+    /// by a later call. A block that is pinned or referenced by an insert
+    /// after it was selected isn't removed.
+    pub fn collect_dead(&self, max_blocks: usize) -> Result<Collected> {
+        let prefix = Key::Block.prefix();
+        let cursor = self.collect_cursor.lock().unwrap().take();
+        let keys: Box<dyn Iterator<Item = sled::Result<IVec>>> = match &cursor {
+            Some(cursor) => {
+                let tail = self
+                    .tree
+                    .range((Bound::Excluded(cursor.clone()), Bound::Unbounded))
+                    .keys()
+                    .take_while(|key| key.as_ref().map_or(true, |key| key.starts_with(&prefix)));
+                let head = self
+                    .tree
+                    .range((
+                        Bound::Included(prefix.clone()),
+                        Bound::Included(cursor.clone()),
+                    ))
+                    .keys();
+                Box::new(tail.chain(head))
+            }
+            None => Box::new(self.tree.scan_prefix(&prefix).keys()),
+        };
+        let mut dead = Vec::with_capacity(max_blocks);
+        let mut last = None;
+        for key in keys {
+            if dead.len() >= max_blocks {
+                break;
+            }
+            let key = key?;
+            let cid = Cid::try_from(&key[1..])?;
+            if !self.is_live(&cid)? {
+                dead.push(cid);
+            }
+            last = Some(key);
+        }
+        // a full cycle starts from the first block again
+        if dead.len() >= max_blocks {
+            *self.collect_cursor.lock().unwrap() = last;
+        }
+        let mut collected = Collected::default();
+        for cid in dead {
+            if let Some((_, size)) = self.remove_one(&cid)? {
+                self.sub_size(size);
+                collected.blocks.push(cid);
+                collected.bytes += size;
+            }
+        }
+        Ok(collected)
+    }
+
+    /// Rebuilds the references of the blocks by decoding them with `C`, then
+    /// recounts the referers of all blocks in a single pass. References of
+    /// blocks that aren't stored anymore are removed. The repair isn't atomic,
+    /// so the store shouldn't be used while it runs.
+    pub fn repair<C: Codec, M: MultihashDigest>(&self) -> Result<Repaired> {
+        log::trace!("repair");
+        let mut repaired = Repaired::default();
+        let mut referers: HashMap<Cid, u32> = HashMap::new();
+        for cid in self.blocks() {
+            let cid = cid?;
+            let data = match self.tree.get(Key::block(&cid))? {
+                Some(value) => compression::decode(&cid, value)?,
+                None => continue,
+            };
+            let block = Block::<C, M>::new(cid.clone(), data.to_vec().into_boxed_slice());
+            let refs = block.decode_ipld()?.references();
+            let stored: Option<HashSet<Cid>> = self
+                .tree
+                .get(Key::refs(&cid))?
+                .map(|b| Value::from(b).into());
+            if stored.as_ref() != Some(&refs) {
+                log::debug!("repairing refs of {}", cid.to_string());
+                self.tree.insert(Key::refs(&cid), Value::from(&refs))?;
+                repaired.refs.push(cid.clone());
+            }
+            for cid in refs {
+                *referers.entry(cid).or_default() += 1;
+            }
+        }
+        for cid in self.iter_prefix(Key::Refs.prefix()) {
+            let cid = cid?;
+            if !self.contains(&cid)? {
+                log::debug!("removing refs of missing block {}", cid.to_string());
+                self.tree.remove(Key::refs(&cid))?;
+                repaired.refs.push(cid);
+            }
+        }
+        for cid in self.iter_prefix(Key::Refer.prefix()) {
+            let cid = cid?;
+            if !referers.contains_key(&cid) {
+                self.tree.remove(Key::refer(&cid))?;
+                repaired.referers.push(cid);
+            }
+        }
+        for (cid, count) in referers {
+            let stored: Option<u32> = self
+                .tree
+                .get(Key::refer(&cid))?
+                .map(|b| Value::from(b).into());
+            if stored != Some(count) {
+                log::debug!("repairing referers of {}", cid.to_string());
+                self.tree.insert(Key::refer(&cid), Value::from(count))?;
+                repaired.referers.push(cid);
+            }
+        }
+        self.dead.store(
+            count_dead(&self.tree, self.max_bytes.is_some())?,
+            Ordering::SeqCst,
+        );
+        Ok(repaired)
+    }
+
     fn iter_prefix(&self, prefix: IVec) -> impl Iterator<Item = Result<Cid>> {
         self.tree
             .scan_prefix(prefix)
@@ -200,10 +1019,82 @@ impl Storage {
         self.iter_prefix(Key::Block.prefix())
     }
 
+    /// Returns up to `limit` blocks in key order, starting after the block
+    /// `start_after`.
+    pub fn blocks_page(&self, start_after: Option<&Cid>, limit: usize) -> Result<Page> {
+        let prefix = Key::Block.prefix();
+        let start = match start_after {
+            Some(cid) => Bound::Excluded(Key::block(cid)),
+            None => Bound::Included(prefix.clone()),
+        };
+        let mut blocks = Vec::with_capacity(limit);
+        let mut keys = self.tree.range((start, Bound::Unbounded)).keys();
+        // one more block is read to know if there is a next page
+        while let Some(key) = keys.next().transpose()? {
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            if blocks.len() == limit {
+                let next = blocks.last().cloned();
+                return Ok(Page { blocks, next });
+            }
+            blocks.push(Cid::try_from(&key[1..])?);
+        }
+        Ok(Page { blocks, next: None })
+    }
+
     pub fn public(&self) -> impl Iterator<Item = Result<Cid>> {
         self.iter_prefix(Key::Public.prefix())
     }
 
+    /// Returns the blocks with pending gets and the priority of their want.
+    pub fn wanted(&self) -> impl Iterator<Item = Result<(Cid, Priority)>> {
+        self.tree.scan_prefix(Key::Want.prefix()).map(|result| {
+            let (key, value) = result?;
+            Ok((Cid::try_from(&key[1..])?, Value::from(value).into()))
+        })
+    }
+
+    /// Returns the blocks marked as roots.
+    pub fn roots(&self) -> impl Iterator<Item = Result<Cid>> {
+        self.iter_prefix(Key::Root.prefix())
+    }
+
+    /// Returns the pinned blocks.
+    pub fn pinned(&self) -> impl Iterator<Item = Result<Cid>> {
+        self.iter_prefix(Key::Pin.prefix())
+    }
+
+    /// Counts the stored blocks in a single pass.
+    pub fn stats(&self) -> Result<Stats> {
+        let mut stats = Stats::default();
+        for res in self.tree.scan_prefix(Key::Block.prefix()) {
+            let (key, block) = res?;
+            let cid = Cid::try_from(&key[1..])?;
+            stats.blocks += 1;
+            stats.bytes += compression::stored_size(&block) as u64;
+            let pinned = self.tree.contains_key(Key::pin(&cid))?;
+            if pinned {
+                stats.pinned += 1;
+            }
+            if pinned || self.tree.contains_key(Key::refer(&cid))? {
+                stats.live += 1;
+            } else {
+                stats.dead += 1;
+            }
+            if self.tree.contains_key(Key::public(&cid))? {
+                stats.public += 1;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Returns true if the block is pinned or referenced, so it won't be
+    /// removed by the garbage collector.
+    pub fn is_live(&self, cid: &Cid) -> Result<bool> {
+        Ok(self.tree.contains_key(Key::pin(cid))? || self.tree.contains_key(Key::refer(cid))?)
+    }
+
     pub fn alias<C: Codec, M: MultihashDigest>(
         &self,
         alias: &[u8],
@@ -226,57 +1117,199 @@ impl Storage {
     }
 
     pub fn metadata(&self, cid: &Cid) -> Result<Metadata> {
+        let (_, metadata) = self
+            .metadata_batch(std::slice::from_ref(cid))?
+            .pop()
+            .expect("one cid");
+        Ok(metadata)
+    }
+
+    /// Reads the metadata of many blocks in a single transaction. The
+    /// results are in the order of `cids`.
+    pub fn metadata_batch(&self, cids: &[Cid]) -> Result<Vec<(Cid, Metadata)>> {
         let res = self
             .tree
             .transaction::<_, _, Error>(|tree| {
-                let pins = tree
-                    .get(Key::pin(cid))?
-                    .map(|b| Value::from(b).into())
-                    .unwrap_or_default();
-                let public = tree
-                    .get(Key::public(cid))?
-                    .map(|b| Value::from(b).into())
-                    .unwrap_or_default();
-                let want = tree
-                    .get(Key::want(cid))?
-                    .map(|b| Value::from(b).into())
-                    .unwrap_or_default();
-                let refs = tree
-                    .get(Key::refs(cid))?
-                    .map(|b| Value::from(b).into())
-                    .unwrap_or_default();
-                let referers = tree
-                    .get(Key::refer(cid))?
-                    .map(|b| Value::from(b).into())
-                    .unwrap_or_default();
-                Ok(Metadata {
-                    pins,
-                    public,
-                    want,
-                    refs,
-                    referers,
-                })
+                let mut batch = Vec::with_capacity(cids.len());
+                for cid in cids {
+                    let pins = tree
+                        .get(Key::pin(cid))?
+                        .map(|b| Value::from(b).into())
+                        .unwrap_or_default();
+                    let public = tree
+                        .get(Key::public(cid))?
+                        .map(|b| Value::from(b).into())
+                        .unwrap_or_default();
+                    let root = tree
+                        .get(Key::root(cid))?
+                        .map(|b| Value::from(b).into())
+                        .unwrap_or_default();
+                    let want = tree
+                        .get(Key::want(cid))?
+                        .map(|b| Value::from(b).into())
+                        .unwrap_or_default();
+                    let refs = tree
+                        .get(Key::refs(cid))?
+                        .map(|b| Value::from(b).into())
+                        .unwrap_or_default();
+                    let referers = tree
+                        .get(Key::refer(cid))?
+                        .map(|b| Value::from(b).into())
+                        .unwrap_or_default();
+                    batch.push(Metadata {
+                        pins,
+                        labels: vec![],
+                        public,
+                        root,
+                        want,
+                        refs,
+                        referers,
+                    });
+                }
+                Ok(batch)
             })
             .map_err(|e| match e {
                 TransactionError::Abort(e) => e,
                 TransactionError::Storage(e) => Error::from(e),
             })?;
-        Ok(res)
+        // transactions can't scan, labels are read separately
+        cids.iter()
+            .zip(res)
+            .map(|(cid, metadata)| {
+                let metadata = Metadata {
+                    labels: self.labels(cid)?,
+                    ..metadata
+                };
+                Ok((cid.clone(), metadata))
+            })
+            .collect()
     }
 }
 
 pub struct Metadata {
     pub pins: u32,
+    /// Labels of the named pins, which are included in `pins`.
+    pub labels: Vec<String>,
     pub public: bool,
+    /// Marked as the root of a DAG with `set_root`.
+    pub root: bool,
     pub want: bool,
     pub refs: HashSet<Cid>,
     pub referers: u32,
 }
 
+/// Counts the size of the blocks that aren't pinned or referenced. With
+/// `evict` their eviction index is rebuilt from their access times,
+/// otherwise it is cleared.
+fn count_dead(tree: &Tree, evict: bool) -> Result<u64> {
+    let mut batch = Batch::default();
+    for key in tree.scan_prefix(Key::Evict.prefix()).keys() {
+        batch.remove(key?);
+    }
+    let mut bytes = 0;
+    for res in tree.scan_prefix(Key::Block.prefix()) {
+        let (key, block) = res?;
+        let cid = Cid::try_from(&key[1..])?;
+        if !tree.contains_key(Key::pin(&cid))? && !tree.contains_key(Key::refer(&cid))? {
+            bytes += compression::stored_size(&block) as u64;
+            if evict {
+                let atime: u64 = tree
+                    .get(Key::atime(&cid))?
+                    .map(|b| Value::from(b).into())
+                    .unwrap_or_default();
+                batch.insert(Key::evict(atime, &cid), Value::from(true));
+            }
+        }
+    }
+    tree.apply_batch(batch)?;
+    Ok(bytes)
+}
+
+/// Summary of the stored blocks.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+    pub blocks: u64,
+    pub pinned: u64,
+    /// Blocks that are pinned or referenced.
+    pub live: u64,
+    /// Blocks that will be removed by the next garbage collection.
+    pub dead: u64,
+    /// Stored size of the blocks, after compression.
+    pub bytes: u64,
+    /// Blocks that are provided to the network.
+    pub public: u64,
+}
+
+/// A page of blocks.
+#[derive(Debug, Default)]
+pub struct Page {
+    pub blocks: Vec<Cid>,
+    /// Cursor for the next page, `None` on the last page.
+    pub next: Option<Cid>,
+}
+
+/// Blocks removed by a garbage collection.
+#[derive(Debug, Default)]
+pub struct Collected {
+    pub blocks: Vec<Cid>,
+    pub bytes: usize,
+}
+
+/// Blocks whose metadata was fixed by a repair.
+#[derive(Debug, Default)]
+pub struct Repaired {
+    /// Blocks whose references didn't match their data, or weren't stored.
+    pub refs: Vec<Cid>,
+    /// Blocks whose number of referers was wrong.
+    pub referers: Vec<Cid>,
+}
+
+/// Iterator returned by `Storage::walk`. Yields `None` as the data of
+/// blocks that aren't stored locally, their references aren't followed.
+pub struct Walk<'a> {
+    storage: &'a Storage,
+    visited: HashSet<Cid>,
+    stack: Vec<Cid>,
+}
+
+impl<'a> Walk<'a> {
+    fn visit(&mut self, cid: &Cid) -> Result<Option<IVec>> {
+        let data = match self.storage.get_local(cid)? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        let refs: HashSet<Cid> = self
+            .storage
+            .tree
+            .get(Key::refs(cid))?
+            .map(|b| Value::from(b).into())
+            .unwrap_or_default();
+        self.stack.extend(refs);
+        Ok(Some(data))
+    }
+}
+
+impl<'a> Iterator for Walk<'a> {
+    type Item = Result<(Cid, Option<IVec>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let cid = self.stack.pop()?;
+            if self.visited.insert(cid.clone()) {
+                return Some(self.visit(&cid).map(|data| (cid, data)));
+            }
+        }
+    }
+}
+
 pub struct GetFuture {
     tree: Tree,
+    getters: Arc<Mutex<HashMap<Cid, usize>>>,
+    timed_out: Arc<Mutex<HashSet<Cid>>>,
     key: IVec,
+    want_key: IVec,
     subscription: Subscriber,
+    want: Subscriber,
     cid: Cid,
 }
 
@@ -291,20 +1324,63 @@ impl Future for GetFuture {
                     if let Event::Insert { key, value } = event {
                         if self.key == key {
                             log::trace!("resolve get {}", self.cid.to_string());
-                            return Poll::Ready(Ok(value));
+                            return Poll::Ready(compression::decode(&self.cid, value));
                         }
                     }
                 }
                 Poll::Ready(None) => unreachable!(),
-                Poll::Pending => return Poll::Pending,
+                Poll::Pending => break,
+            }
+        }
+        // the want is removed when the block is inserted or the get was cancelled
+        loop {
+            match Pin::new(&mut self.want).poll(ctx) {
+                Poll::Ready(Some(Event::Remove { key })) if self.want_key == key => {
+                    return Poll::Ready(self.resolve_removed());
+                }
+                Poll::Ready(Some(_)) => continue,
+                Poll::Ready(None) => unreachable!(),
+                Poll::Pending => break,
             }
         }
+        // sled drops an event that is polled while it's being written, the
+        // subscriber is still woken up once it's written
+        match self.tree.contains_key(&self.want_key) {
+            Ok(true) => Poll::Pending,
+            Ok(false) => Poll::Ready(self.resolve_removed()),
+            Err(err) => Poll::Ready(Err(err.into())),
+        }
+    }
+}
+
+impl GetFuture {
+    fn resolve_removed(&self) -> Result<IVec> {
+        log::trace!("resolve cancelled get {}", self.cid.to_string());
+        match self.tree.get(&self.key) {
+            Ok(Some(value)) => compression::decode(&self.cid, value),
+            Ok(None) if self.timed_out.lock().unwrap().contains(&self.cid) => {
+                Err(WantTimeout(self.cid.to_string()).into())
+            }
+            Ok(None) => Err(BlockNotFound(self.cid.to_string()).into()),
+            Err(err) => Err(err.into()),
+        }
     }
 }
 
 impl Drop for GetFuture {
     fn drop(&mut self) {
-        if let Err(err) = self.tree.remove(Key::want(&self.cid)) {
+        let mut getters = self.getters.lock().unwrap();
+        match getters.get_mut(&self.cid) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                return;
+            }
+            _ => {
+                getters.remove(&self.cid);
+                self.timed_out.lock().unwrap().remove(&self.cid);
+            }
+        }
+        if let Err(err) = self.tree.remove(&self.want_key) {
             log::error!("failed to remove want {}: {:?}", self.cid.to_string(), err);
         }
     }
@@ -316,8 +1392,11 @@ mod tests {
     use async_std::prelude::*;
     use async_std::task;
     use futures::future::FutureExt;
+    use libipld::cbor::DagCborCodec;
     use libipld::cid::RAW;
     use libipld::codec_impl::Multicodec;
+    use libipld::error::UnsupportedCodec;
+    use libipld::ipld::Ipld;
     use libipld::multihash::{Multihash, MultihashDigest, SHA2_256};
     use tempdir::TempDir;
 
@@ -325,7 +1404,7 @@ mod tests {
         let tmp = TempDir::new("").unwrap();
         let db = sled::open(tmp.path()).unwrap();
         let tree = db.open_tree("ipfs_tree").unwrap();
-        let storage = Storage::new(tree).unwrap();
+        let storage = Storage::new(tree, None).unwrap();
         (storage, tmp)
     }
 
@@ -349,8 +1428,8 @@ mod tests {
             env_logger::try_init().ok();
             let (store, _tmp) = create_store();
             let (cid, data) = create_block(b"block");
-            let gc = store.watch_gc();
-            let net = store.watch_network();
+            let gc = store.watch_gc().unwrap();
+            let net = store.watch_network().unwrap();
             Self {
                 _tmp,
                 store,
@@ -379,7 +1458,7 @@ mod tests {
         }
 
         fn get(&self) -> IVec {
-            task::block_on(self.store.get(&self.cid)).unwrap()
+            task::block_on(self.store.get(&self.cid, DEFAULT_PRIORITY)).unwrap()
         }
 
         fn insert(&self, visibility: Visibility) {
@@ -388,6 +1467,10 @@ mod tests {
             self.store.insert(&block).unwrap();
         }
 
+        fn pin(&self) {
+            self.store.pin(&self.cid).unwrap();
+        }
+
         fn unpin(&self) {
             self.store.unpin(&self.cid).unwrap();
         }
@@ -422,8 +1505,18 @@ mod tests {
             assert_eq!(task::block_on((&mut self.net).next()), Some(event));
         }
 
+        /// The events of a transaction are emitted in any order.
+        fn assert_net_unordered(&mut self, mut events: Vec<NetworkEvent>) {
+            while !events.is_empty() {
+                let event = task::block_on(self.net.next()).unwrap();
+                let i = events.iter().position(|expected| *expected == event);
+                assert!(i.is_some(), "unexpected event {:?}", event);
+                events.remove(i.unwrap());
+            }
+        }
+
         fn assert_want(&mut self) {
-            let event = NetworkEvent::Want(self.cid.clone());
+            let event = NetworkEvent::Want(self.cid.clone(), DEFAULT_PRIORITY);
             self.assert_net(event);
         }
 
@@ -442,6 +1535,16 @@ mod tests {
             self.assert_net(event);
         }
 
+        fn assert_root(&mut self) {
+            let event = NetworkEvent::Root(self.cid.clone());
+            self.assert_net(event);
+        }
+
+        fn assert_unroot(&mut self) {
+            let event = NetworkEvent::Unroot(self.cid.clone());
+            self.assert_net(event);
+        }
+
         fn assert_no_events(mut self) {
             drop(self.store);
             assert_eq!(task::block_on((&mut self.gc).next()), None);
@@ -471,6 +1574,603 @@ mod tests {
         tester.assert_no_events();
     }
 
+    #[test]
+    fn test_set_public() {
+        let mut tester = Tester::setup();
+        let err = tester.store.set_public(&tester.cid, true).unwrap_err();
+        assert!(err.downcast_ref::<BlockNotLocal>().is_some());
+        tester.insert(Visibility::Private);
+        tester.store.set_public(&tester.cid, true).unwrap();
+        tester.assert_provide();
+        assert!(tester.store.metadata(&tester.cid).unwrap().public);
+        tester.store.set_public(&tester.cid, false).unwrap();
+        tester.assert_unprovide();
+        // private blocks aren't unprovided again
+        tester.store.set_public(&tester.cid, false).unwrap();
+        tester.unpin();
+        tester.assert_unpin();
+        tester.remove();
+        tester.assert_no_events();
+    }
+
+    #[test]
+    fn test_set_root() {
+        let mut tester = Tester::setup();
+        let err = tester.store.set_root(&tester.cid, true).unwrap_err();
+        assert!(err.downcast_ref::<BlockNotLocal>().is_some());
+        tester.insert(Visibility::Private);
+        tester.store.set_root(&tester.cid, true).unwrap();
+        tester.assert_net_unordered(vec![
+            NetworkEvent::Provide(tester.cid()),
+            NetworkEvent::Root(tester.cid()),
+        ]);
+        assert!(tester.store.metadata(&tester.cid).unwrap().public);
+        assert_eq!(tester.store.roots().next().unwrap().unwrap(), tester.cid);
+        tester.store.set_root(&tester.cid, false).unwrap();
+        tester.assert_unroot();
+        assert!(!tester.store.is_root(&tester.cid).unwrap());
+        // blocks that aren't roots aren't unmarked again
+        tester.store.set_root(&tester.cid, false).unwrap();
+        // public blocks aren't provided again
+        tester.store.set_root(&tester.cid, true).unwrap();
+        tester.assert_root();
+        tester.store.set_public(&tester.cid, false).unwrap();
+        tester.assert_net_unordered(vec![
+            NetworkEvent::Unprovide(tester.cid()),
+            NetworkEvent::Unroot(tester.cid()),
+        ]);
+        tester.unpin();
+        tester.assert_unpin();
+        tester.remove();
+        tester.assert_no_events();
+    }
+
+    #[test]
+    fn test_pin() {
+        let mut tester = Tester::setup();
+        tester.insert(Visibility::Private);
+        tester.pin();
+        tester.unpin();
+        tester.unpin();
+        tester.assert_unpin();
+        tester.assert_no_events();
+    }
+
+    #[test]
+    fn test_metadata_batch() {
+        let tester = Tester::setup();
+        tester.insert(Visibility::Public);
+        let store = &tester.store;
+        store.pin_named(&tester.cid, "a").unwrap();
+        let (missing, _) = create_block(b"missing");
+        let cids = vec![missing.clone(), tester.cid()];
+        let batch = store.metadata_batch(&cids).unwrap();
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch[0].0, missing);
+        assert_eq!(batch[0].1.pins, 0);
+        assert_eq!(batch[1].0, tester.cid);
+        assert_eq!(batch[1].1.pins, 2);
+        assert!(batch[1].1.public);
+        assert_eq!(batch[1].1.labels, vec!["a".to_string()]);
+    }
+
+    #[test]
+    fn test_pin_named() {
+        let tester = Tester::setup();
+        tester.insert(Visibility::Private);
+        let store = &tester.store;
+        let cid = &tester.cid;
+        // insert pins the block
+        store.pin_named(cid, "a").unwrap();
+        store.pin_named(cid, "a").unwrap();
+        store.pin_named(cid, "b").unwrap();
+        let metadata = store.metadata(cid).unwrap();
+        assert_eq!(metadata.pins, 3);
+        assert_eq!(metadata.labels, vec!["a".to_string(), "b".to_string()]);
+
+        // unpin only removes the unnamed pin
+        store.unpin(cid).unwrap();
+        store.unpin(cid).unwrap();
+        assert_eq!(store.metadata(cid).unwrap().pins, 2);
+
+        store.unpin_named(cid, "a").unwrap();
+        store.unpin_named(cid, "a").unwrap();
+        let metadata = store.metadata(cid).unwrap();
+        assert_eq!(metadata.pins, 1);
+        assert_eq!(metadata.labels, vec!["b".to_string()]);
+        assert!(store.is_live(cid).unwrap());
+        store.unpin_named(cid, "b").unwrap();
+        assert!(!store.is_live(cid).unwrap());
+        assert!(store.metadata(cid).unwrap().labels.is_empty());
+    }
+
+    #[test]
+    fn test_unpin_all() {
+        let (store, _tmp) = create_store();
+        let mut gc = store.watch_gc().unwrap();
+        let encode = |ipld: &Ipld| {
+            Block::<Multicodec, Multihash>::encode(DagCborCodec, SHA2_256, ipld).unwrap()
+        };
+        let child = encode(&Ipld::Integer(0));
+        let parent = encode(&Ipld::List(vec![Ipld::Link(child.cid.clone())]));
+        store.insert(&child).unwrap();
+        store.insert(&parent).unwrap();
+        store.pin_named(&parent.cid, "a").unwrap();
+
+        assert_eq!(store.unpin_all().unwrap(), 2);
+        assert_eq!(store.pinned().count(), 0);
+        assert!(store.labels(&parent.cid).unwrap().is_empty());
+        assert!(!store.is_live(&parent.cid).unwrap());
+        // the child is still referenced by the parent
+        assert!(store.is_live(&child.cid).unwrap());
+        let events = [
+            task::block_on(gc.next()).unwrap(),
+            task::block_on(gc.next()).unwrap(),
+        ];
+        assert!(events.contains(&GcEvent::Unpin(child.cid)));
+        assert!(events.contains(&GcEvent::Unpin(parent.cid)));
+    }
+
+    #[test]
+    fn test_pin_not_local() {
+        let tester = Tester::setup();
+        let err = tester.store.pin(&tester.cid).unwrap_err();
+        assert!(err.downcast_ref::<BlockNotLocal>().is_some());
+    }
+
+    #[test]
+    fn test_pin_recursive_missing_child() {
+        let (store, _) = create_store();
+        let encode = |ipld: &Ipld| {
+            Block::<Multicodec, Multihash>::encode(Multicodec::DagCbor, SHA2_256, ipld).unwrap()
+        };
+        let leaf = encode(&Ipld::Integer(0));
+        let missing = encode(&Ipld::Integer(1));
+        let node = encode(&Ipld::List(vec![Ipld::Link(leaf.cid.clone())]));
+        let root = encode(&Ipld::List(vec![
+            Ipld::Link(node.cid.clone()),
+            Ipld::Link(missing.cid.clone()),
+        ]));
+        store
+            .insert_blocks(&[leaf.clone(), node.clone(), root.clone()], false)
+            .unwrap();
+        let err = store.pin_recursive(&root.cid).unwrap_err();
+        assert!(err.downcast_ref::<BlockNotLocal>().is_some());
+        assert_eq!(store.pinned().count(), 0);
+
+        store.insert_blocks(&[missing], false).unwrap();
+        assert_eq!(store.pin_recursive(&root.cid).unwrap(), 4);
+        assert_eq!(store.pinned().count(), 4);
+    }
+
+    #[test]
+    fn test_collect_garbage() {
+        let (store, _) = create_store();
+        let leaf = Block::<Multicodec, Multihash>::encode(
+            Multicodec::DagCbor,
+            SHA2_256,
+            &Ipld::Integer(0),
+        )
+        .unwrap();
+        let root_ipld = Ipld::List(vec![Ipld::Link(leaf.cid.clone())]);
+        let root =
+            Block::<Multicodec, Multihash>::encode(Multicodec::DagCbor, SHA2_256, &root_ipld)
+                .unwrap();
+        let dead_ipld = Ipld::List(vec![Ipld::Link(leaf.cid.clone()), Ipld::Integer(1)]);
+        let dead =
+            Block::<Multicodec, Multihash>::encode(Multicodec::DagCbor, SHA2_256, &dead_ipld)
+                .unwrap();
+        store.insert_batch(&[leaf.clone(), root.clone()]).unwrap();
+        store.insert(&dead).unwrap();
+        store.unpin(&dead.cid).unwrap();
+
+        let collected = store.collect_garbage(true).unwrap();
+        assert_eq!(collected.blocks, vec![dead.cid.clone()]);
+        assert_eq!(collected.bytes, dead.data.len());
+        assert!(store.get_local(&dead.cid).unwrap().is_some());
+
+        let collected = store.collect_garbage(false).unwrap();
+        assert_eq!(collected.blocks, vec![dead.cid.clone()]);
+        assert!(store.get_local(&dead.cid).unwrap().is_none());
+        assert!(store.get_local(&leaf.cid).unwrap().is_some());
+        assert_eq!(store.metadata(&leaf.cid).unwrap().referers, 1);
+        assert!(store.collect_garbage(false).unwrap().blocks.is_empty());
+    }
+
+    #[test]
+    fn test_collect_garbage_insert_after_mark() {
+        let (store, _) = create_store();
+        let encode = |ipld: &Ipld| {
+            Block::<Multicodec, Multihash>::encode(Multicodec::DagCbor, SHA2_256, ipld).unwrap()
+        };
+        let leaf = encode(&Ipld::Integer(0));
+        store
+            .insert_blocks(std::slice::from_ref(&leaf), false)
+            .unwrap();
+        let live = store.mark_live().unwrap();
+        // the dead leaf is referenced by a pinned root before the sweep
+        let root = encode(&Ipld::List(vec![Ipld::Link(leaf.cid.clone())]));
+        store.insert(&root).unwrap();
+        assert!(store.sweep(&live).unwrap().blocks.is_empty());
+        assert!(store.contains(&leaf.cid).unwrap());
+        assert_eq!(store.metadata(&leaf.cid).unwrap().referers, 1);
+
+        // the leaf becomes unreferenced when the root is removed
+        store.unpin(&root.cid).unwrap();
+        let collected = store.collect_garbage(false).unwrap();
+        assert_eq!(collected.blocks.len(), 2);
+        assert!(!store.contains(&root.cid).unwrap());
+        assert!(!store.contains(&leaf.cid).unwrap());
+        assert!(store.tree.get(Key::refer(&leaf.cid)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_collect_dead() {
+        let (store, _) = create_store();
+        let encode = |ipld: &Ipld| {
+            Block::<Multicodec, Multihash>::encode(Multicodec::DagCbor, SHA2_256, ipld).unwrap()
+        };
+        let leaf = encode(&Ipld::Integer(0));
+        let root = encode(&Ipld::List(vec![Ipld::Link(leaf.cid.clone())]));
+        let pinned = encode(&Ipld::Integer(1));
+        store
+            .insert_blocks(&[leaf.clone(), root.clone()], false)
+            .unwrap();
+        store
+            .insert_blocks(std::slice::from_ref(&pinned), true)
+            .unwrap();
+        assert_eq!(store.dead_bytes().unwrap(), root.data.len() as u64);
+
+        // the leaf is referenced until the root is removed
+        let collected = store.collect_dead(10).unwrap();
+        assert_eq!(collected.blocks, vec![root.cid.clone()]);
+        assert_eq!(collected.bytes, root.data.len());
+        let collected = store.collect_dead(10).unwrap();
+        assert_eq!(collected.blocks, vec![leaf.cid.clone()]);
+        assert!(store.collect_dead(10).unwrap().blocks.is_empty());
+        assert_eq!(store.dead_bytes().unwrap(), 0);
+        assert!(store.contains(&pinned.cid).unwrap());
+    }
+
+    #[test]
+    fn test_dead_bytes() {
+        let (store, _) = create_store();
+        let encode = |ipld: &Ipld| {
+            Block::<Multicodec, Multihash>::encode(Multicodec::DagCbor, SHA2_256, ipld).unwrap()
+        };
+        let assert_dead = |bytes: usize| {
+            assert_eq!(store.dead_bytes().unwrap(), bytes as u64);
+            assert_eq!(count_dead(&store.tree, false).unwrap(), bytes as u64);
+        };
+        let leaf = encode(&Ipld::Integer(0));
+        let root = encode(&Ipld::List(vec![Ipld::Link(leaf.cid.clone())]));
+        store
+            .insert_blocks(std::slice::from_ref(&leaf), false)
+            .unwrap();
+        assert_dead(leaf.data.len());
+        store.insert(&root).unwrap();
+        assert_dead(0);
+        store.pin_named(&root.cid, "label").unwrap();
+        store.unpin(&root.cid).unwrap();
+        assert_dead(0);
+        store.unpin_named(&root.cid, "label").unwrap();
+        assert_dead(root.data.len());
+        store.pin_recursive(&root.cid).unwrap();
+        assert_dead(0);
+        store.unpin_all().unwrap();
+        assert_dead(root.data.len());
+        store.remove(&root.cid).unwrap();
+        assert_dead(0);
+        assert!(!store.contains(&leaf.cid).unwrap());
+    }
+
+    #[test]
+    fn test_collect_dead_resumes() {
+        let (store, _) = create_store();
+        let mut blocks: Vec<_> = (0..4u8)
+            .map(|i| {
+                let (cid, data) = create_block(&[i]);
+                Block::<Multicodec, Multihash>::new(cid, data.to_vec().into())
+            })
+            .collect();
+        blocks.sort_by_key(|block| Key::block(&block.cid));
+        store.insert_blocks(&blocks, false).unwrap();
+        let cids: Vec<_> = blocks.iter().map(|block| block.cid.clone()).collect();
+        store.pin(&cids[0]).unwrap();
+        assert_eq!(store.collect_dead(1).unwrap().blocks, vec![cids[1].clone()]);
+        // the first block is only scanned again once the scan wraps around
+        store.unpin(&cids[0]).unwrap();
+        assert_eq!(store.collect_dead(1).unwrap().blocks, vec![cids[2].clone()]);
+        assert_eq!(store.collect_dead(1).unwrap().blocks, vec![cids[3].clone()]);
+        assert_eq!(store.collect_dead(1).unwrap().blocks, vec![cids[0].clone()]);
+        assert!(store.collect_dead(1).unwrap().blocks.is_empty());
+        assert_eq!(store.dead_bytes().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_insert_blocks() {
+        let (store, _) = create_store();
+        let encode = |ipld: &Ipld| {
+            Block::<Multicodec, Multihash>::encode(Multicodec::DagCbor, SHA2_256, ipld).unwrap()
+        };
+        let leaf = encode(&Ipld::Integer(0));
+        store.insert(&leaf).unwrap();
+        let node = encode(&Ipld::List(vec![Ipld::Link(leaf.cid.clone())]));
+        let root = encode(&Ipld::List(vec![
+            Ipld::Link(leaf.cid.clone()),
+            Ipld::Link(node.cid.clone()),
+        ]));
+        let batch = [leaf.clone(), node.clone(), root.clone()];
+        let inserted = store.insert_blocks(&batch, true).unwrap();
+        assert_eq!(inserted, vec![node.cid.clone(), root.cid.clone()]);
+
+        let metadata = store.metadata(&leaf.cid).unwrap();
+        assert_eq!(metadata.referers, 2);
+        assert_eq!(metadata.pins, 1);
+        let metadata = store.metadata(&node.cid).unwrap();
+        assert_eq!(metadata.referers, 1);
+        assert_eq!(metadata.pins, 0);
+        let metadata = store.metadata(&root.cid).unwrap();
+        assert_eq!(metadata.refs.len(), 2);
+        assert_eq!(metadata.pins, 1);
+
+        assert!(store.insert_blocks(&batch, false).unwrap().is_empty());
+        assert_eq!(store.metadata(&root.cid).unwrap().pins, 1);
+    }
+
+    #[test]
+    fn test_refs() {
+        let (store, _) = create_store();
+        let encode = |codec, ipld: &Ipld| {
+            Block::<Multicodec, Multihash>::encode(codec, SHA2_256, ipld).unwrap()
+        };
+        let child = encode(Multicodec::DagCbor, &Ipld::Integer(0));
+        store.insert(&child).unwrap();
+        for codec in &[Multicodec::DagCbor, Multicodec::DagJson] {
+            let parent = encode(*codec, &Ipld::List(vec![Ipld::Link(child.cid.clone())]));
+            store.insert(&parent).unwrap();
+            let refs = store.metadata(&parent.cid).unwrap().refs;
+            assert_eq!(
+                refs.into_iter().collect::<Vec<_>>(),
+                vec![child.cid.clone()]
+            );
+        }
+        assert_eq!(store.metadata(&child.cid).unwrap().referers, 2);
+    }
+
+    #[test]
+    fn test_repair() {
+        let (store, _) = create_store();
+        let encode = |ipld: &Ipld| {
+            Block::<Multicodec, Multihash>::encode(DagCborCodec, SHA2_256, ipld).unwrap()
+        };
+        let child = encode(&Ipld::Integer(0));
+        let parent = encode(&Ipld::List(vec![Ipld::Link(child.cid.clone())]));
+        let other = encode(&Ipld::Integer(1));
+        store.insert(&child).unwrap();
+        store.insert(&parent).unwrap();
+        store.insert(&other).unwrap();
+        let repaired = store.repair::<Multicodec, Multihash>().unwrap();
+        assert!(repaired.refs.is_empty());
+        assert!(repaired.referers.is_empty());
+
+        store.tree.remove(Key::refs(&parent.cid)).unwrap();
+        store
+            .tree
+            .insert(Key::refer(&child.cid), Value::from(5))
+            .unwrap();
+        store
+            .tree
+            .insert(Key::refer(&other.cid), Value::from(1))
+            .unwrap();
+        let repaired = store.repair::<Multicodec, Multihash>().unwrap();
+        assert_eq!(repaired.refs, vec![parent.cid.clone()]);
+        assert_eq!(repaired.referers.len(), 2);
+        assert!(repaired.referers.contains(&child.cid));
+        assert!(repaired.referers.contains(&other.cid));
+        let metadata = store.metadata(&parent.cid).unwrap();
+        assert_eq!(
+            metadata.refs.into_iter().collect::<Vec<_>>(),
+            vec![child.cid.clone()]
+        );
+        assert_eq!(store.metadata(&child.cid).unwrap().referers, 1);
+        assert_eq!(store.metadata(&other.cid).unwrap().referers, 0);
+    }
+
+    #[test]
+    fn test_missing() {
+        let (store, _) = create_store();
+        let encode = |ipld: &Ipld| {
+            Block::<Multicodec, Multihash>::encode(Multicodec::DagCbor, SHA2_256, ipld).unwrap()
+        };
+        let leaf = encode(&Ipld::Integer(0));
+        let missing = encode(&Ipld::Integer(1));
+        let missing_node = encode(&Ipld::List(vec![Ipld::Link(leaf.cid.clone())]));
+        let root = encode(&Ipld::List(vec![
+            Ipld::Link(leaf.cid.clone()),
+            Ipld::Link(missing.cid.clone()),
+            Ipld::Link(missing_node.cid.clone()),
+        ]));
+        store
+            .insert_blocks(&[leaf.clone(), root.clone()], true)
+            .unwrap();
+        let found: HashSet<_> = store.missing(&root.cid).unwrap().into_iter().collect();
+        let expected: HashSet<_> = vec![missing.cid, missing_node.cid].into_iter().collect();
+        assert_eq!(found, expected);
+        assert!(store.missing(&leaf.cid).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_walk() {
+        let (store, _) = create_store();
+        let encode = |ipld: &Ipld| {
+            Block::<Multicodec, Multihash>::encode(Multicodec::DagCbor, SHA2_256, ipld).unwrap()
+        };
+        let leaf = encode(&Ipld::Integer(0));
+        let missing = encode(&Ipld::Integer(1));
+        let node = encode(&Ipld::List(vec![
+            Ipld::Link(leaf.cid.clone()),
+            Ipld::Link(missing.cid.clone()),
+        ]));
+        let root = encode(&Ipld::List(vec![
+            Ipld::Link(leaf.cid.clone()),
+            Ipld::Link(node.cid.clone()),
+        ]));
+        store
+            .insert_blocks(&[leaf.clone(), node.clone(), root.clone()], true)
+            .unwrap();
+
+        let mut walked: Vec<_> = store
+            .walk(&root.cid)
+            .map(|res| {
+                let (cid, data) = res.unwrap();
+                (cid, data.is_some())
+            })
+            .collect();
+        // every block is visited once, the root first
+        assert_eq!(walked.remove(0), (root.cid, true));
+        assert_eq!(walked.len(), 3);
+        let walked: HashSet<_> = walked.into_iter().collect();
+        let expected = vec![(leaf.cid, true), (node.cid, true), (missing.cid, false)];
+        assert_eq!(walked, expected.into_iter().collect());
+    }
+
+    /// Codec from the private use range that is encoded like DAG-CBOR.
+    const CUSTOM: u64 = 0x30_0000;
+
+    /// The default codecs and a custom one.
+    #[derive(Clone, Copy, Debug)]
+    enum CustomCodec {
+        Default(Multicodec),
+        Custom,
+    }
+
+    impl TryFrom<u64> for CustomCodec {
+        type Error = UnsupportedCodec;
+
+        fn try_from(code: u64) -> core::result::Result<Self, Self::Error> {
+            match code {
+                CUSTOM => Ok(Self::Custom),
+                code => Multicodec::try_from(code).map(Self::Default),
+            }
+        }
+    }
+
+    impl From<CustomCodec> for u64 {
+        fn from(codec: CustomCodec) -> Self {
+            match codec {
+                CustomCodec::Default(codec) => codec.into(),
+                CustomCodec::Custom => CUSTOM,
+            }
+        }
+    }
+
+    impl Codec for CustomCodec {
+        fn decode_ipld(&self, bytes: &[u8]) -> Result<Ipld> {
+            match self {
+                Self::Default(codec) => codec.decode_ipld(bytes),
+                Self::Custom => DagCborCodec.decode_ipld(bytes),
+            }
+        }
+    }
+
+    #[test]
+    fn test_custom_codec() {
+        let (store, _) = create_store();
+        let (leaf, data) = create_block(b"leaf");
+        let leaf = Block::<CustomCodec, Multihash>::new(leaf, data.to_vec().into());
+        let data = DagCborCodec
+            .encode(&Ipld::List(vec![Ipld::Link(leaf.cid.clone())]))
+            .unwrap();
+        let digest = Multihash::new(SHA2_256, &data).unwrap().to_raw().unwrap();
+        let root = Block::<CustomCodec, Multihash>::new(Cid::new_v1(CUSTOM, digest), data);
+
+        // the default codecs can't decode the links of the root
+        let default = Block::<Multicodec, Multihash>::new(root.cid.clone(), root.data.clone());
+        let err = store.insert(&default).unwrap_err();
+        assert!(err.downcast_ref::<UnsupportedCodec>().is_some());
+
+        store
+            .insert_blocks(&[leaf.clone(), root.clone()], true)
+            .unwrap();
+        assert_eq!(
+            store.metadata(&root.cid).unwrap().refs,
+            std::iter::once(leaf.cid.clone()).collect()
+        );
+        let walked: Vec<_> = store.walk(&root.cid).map(|res| res.unwrap().0).collect();
+        assert_eq!(walked, vec![root.cid, leaf.cid]);
+    }
+
+    #[test]
+    fn test_flush_every() {
+        let (mut store, _) = create_store();
+        store.set_flush_every(Some(2));
+        let insert = |bytes: &[u8]| {
+            let (cid, data) = create_block(bytes);
+            let block = Block::<Multicodec, Multihash>::new(cid, data.to_vec().into());
+            store.insert(&block).unwrap();
+        };
+        insert(b"a");
+        assert_eq!(store.unflushed.load(Ordering::SeqCst), 1);
+        insert(b"b");
+        assert_eq!(store.unflushed.load(Ordering::SeqCst), 0);
+        // blocks that are already stored aren't written again
+        insert(b"b");
+        assert_eq!(store.unflushed.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_blocks_page() {
+        let (store, _) = create_store();
+        for i in 0..5u8 {
+            let (cid, data) = create_block(&[i]);
+            let block = Block::<Multicodec, Multihash>::new(cid, data.to_vec().into());
+            store.insert(&block).unwrap();
+        }
+        let all = store.blocks().collect::<Result<Vec<_>>>().unwrap();
+        let mut blocks = vec![];
+        let mut cursor = None;
+        loop {
+            let page = store.blocks_page(cursor.as_ref(), 2).unwrap();
+            assert!(page.blocks.len() <= 2);
+            blocks.extend(page.blocks);
+            cursor = page.next;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(blocks, all);
+        let page = store.blocks_page(None, 5).unwrap();
+        assert_eq!(page.blocks, all);
+        assert!(page.next.is_none());
+    }
+
+    #[test]
+    fn test_stats() {
+        let (store, _) = create_store();
+        let encode = |ipld: &Ipld| {
+            Block::<Multicodec, Multihash>::encode(Multicodec::DagCbor, SHA2_256, ipld).unwrap()
+        };
+        let leaf = encode(&Ipld::Integer(0));
+        let root = encode(&Ipld::List(vec![Ipld::Link(leaf.cid.clone())]));
+        let mut dead = encode(&Ipld::Integer(1));
+        dead.set_visibility(Visibility::Private);
+        store.insert_batch(&[leaf.clone(), root.clone()]).unwrap();
+        store.insert_blocks(&[dead.clone()], false).unwrap();
+        let stats = store.stats().unwrap();
+        assert_eq!(
+            stats,
+            Stats {
+                blocks: 3,
+                pinned: 1,
+                live: 2,
+                dead: 1,
+                bytes: (leaf.data.len() + root.data.len() + dead.data.len()) as u64,
+                public: 2,
+            }
+        );
+    }
+
     #[test]
     fn test_get_local() {
         let tester = Tester::setup();
@@ -478,6 +2178,25 @@ mod tests {
         assert_eq!(tester.get_local(), Some(tester.data()));
     }
 
+    #[test]
+    fn test_get_range() {
+        let tester = Tester::setup();
+        assert_eq!(tester.store.get_range(&tester.cid, 0, 1).unwrap(), None);
+        tester.insert(Visibility::Private);
+        let range = |offset, len| tester.store.get_range(&tester.cid, offset, len).unwrap();
+        assert_eq!(range(1, 3), Some(IVec::from(b"loc")));
+        assert_eq!(range(3, 10), Some(IVec::from(b"ck")));
+        assert_eq!(range(10, 1), Some(IVec::from(b"")));
+    }
+
+    #[test]
+    fn test_contains() {
+        let tester = Tester::setup();
+        assert!(!tester.store.contains(&tester.cid).unwrap());
+        tester.insert(Visibility::Private);
+        assert!(tester.store.contains(&tester.cid).unwrap());
+    }
+
     #[test]
     fn test_remove_pinned() {
         let tester = Tester::setup();
@@ -492,11 +2211,11 @@ mod tests {
 
         let block = tester.block();
         let store = tester.store.clone();
-        let mut net = store.watch_network();
+        let mut net = store.watch_network().unwrap();
         task::spawn(async move {
             assert_eq!(
                 (&mut net).next().await.unwrap(),
-                NetworkEvent::Want(block.cid.clone())
+                NetworkEvent::Want(block.cid.clone(), DEFAULT_PRIORITY)
             );
             store.insert(&block).unwrap();
         });
@@ -510,9 +2229,119 @@ mod tests {
 
         let store = tester.store.clone();
         let cid = tester.cid.clone();
-        store.get(&cid).now_or_never();
+        store.get(&cid, DEFAULT_PRIORITY).now_or_never();
+        tester.assert_want();
+        tester.assert_cancel();
+    }
+
+    #[test]
+    fn test_get_with_priority() {
+        let mut tester = Tester::setup();
+
+        let store = tester.store.clone();
+        let cid = tester.cid.clone();
+        let mut low = store.get(&cid, 1).boxed();
+        let mut high = store.get(&cid, 10).boxed();
+        let mut lower = store.get(&cid, 5).boxed();
+        assert!((&mut low).now_or_never().is_none());
+        assert!((&mut high).now_or_never().is_none());
+        assert!((&mut lower).now_or_never().is_none());
+        tester.assert_net(NetworkEvent::Want(cid.clone(), 1));
+        tester.assert_net(NetworkEvent::Want(cid.clone(), 10));
+    }
+
+    #[test]
+    fn test_get_concurrent() {
+        let mut tester = Tester::setup();
+
+        let store = tester.store.clone();
+        let cid = tester.cid.clone();
+        let mut gets: Vec<_> = (0..5)
+            .map(|_| store.get(&cid, DEFAULT_PRIORITY).boxed())
+            .collect();
+        for get in &mut gets {
+            assert!(get.now_or_never().is_none());
+        }
         tester.assert_want();
+
+        // the want is kept until the last get is dropped
+        gets.pop();
+        tester.insert(Visibility::Private);
         tester.assert_cancel();
+        for get in gets {
+            assert_eq!(task::block_on(get).unwrap(), tester.data());
+        }
+        drop(store);
+        tester.assert_no_events();
+    }
+
+    #[async_std::test]
+    async fn test_get_not_found() {
+        let tester = Tester::setup();
+
+        let store = tester.store.clone();
+        let mut net = store.watch_network().unwrap();
+        task::spawn(async move {
+            let event = net.next().await.unwrap();
+            if let NetworkEvent::Want(cid, _) = event {
+                store.cancel(&cid).unwrap();
+            }
+        });
+
+        let err = tester
+            .store
+            .get(&tester.cid, DEFAULT_PRIORITY)
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<BlockNotFound>().is_some());
+    }
+
+    #[test]
+    fn test_watch_blocks() {
+        let tester = Tester::setup();
+        let mut blocks = tester.store.watch_blocks().unwrap();
+        tester.insert(Visibility::Private);
+        // duplicates and removals aren't emitted
+        tester.insert(Visibility::Private);
+        tester.unpin();
+        tester.unpin();
+        tester.remove();
+        tester.insert(Visibility::Private);
+        let event = Some((tester.cid(), tester.data()));
+        assert_eq!(task::block_on(blocks.next()), event);
+        assert_eq!(task::block_on(blocks.next()), event);
+        drop(tester);
+        assert_eq!(task::block_on(blocks.next()), None);
+    }
+
+    #[test]
+    fn test_watch_blocks_lagged() {
+        let (store, _tmp) = create_store();
+        let mut blocks = store.watch_blocks().unwrap();
+        let mut cids = vec![];
+        // the inserts don't wait for the subscriber
+        for i in 0..2000 {
+            let (cid, data) = create_block(format!("block {}", i).as_bytes());
+            let block = Block::<Multicodec, Multihash>::new(cid.clone(), data.to_vec().into());
+            store.insert(&block).unwrap();
+            cids.push(cid);
+        }
+        drop(store);
+        let mut received = vec![];
+        while let Some((cid, _)) = task::block_on(blocks.next()) {
+            received.push(cid);
+        }
+        assert!(received.len() < cids.len());
+        assert_eq!(received[..], cids[..received.len()]);
+    }
+
+    #[test]
+    fn test_is_live() {
+        let tester = Tester::setup();
+        tester.insert(Visibility::Public);
+        assert!(tester.store.is_live(&tester.cid).unwrap());
+        tester.unpin();
+        assert!(!tester.store.is_live(&tester.cid).unwrap());
     }
 
     #[test]
@@ -546,4 +2375,255 @@ mod tests {
         tester.assert_unpin();
         tester.assert_no_events();
     }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn test_compression() {
+        let (mut store, _tmp) = create_store();
+        // blocks stored before compression was enabled stay readable
+        let (cid1, data1) = create_block(&[b'a'; 1024]);
+        let block1 = Block::<Multicodec, Multihash>::new(cid1.clone(), data1.to_vec().into());
+        store.insert(&block1).unwrap();
+        store.set_compression(Some(6));
+        let (cid2, data2) = create_block(&[b'b'; 1024]);
+        let block2 = Block::<Multicodec, Multihash>::new(cid2.clone(), data2.to_vec().into());
+        store.insert(&block2).unwrap();
+        // blocks that don't get smaller are stored as is
+        let (cid3, data3) = create_block(b"c");
+        let block3 = Block::<Multicodec, Multihash>::new(cid3.clone(), data3.to_vec().into());
+        store.insert(&block3).unwrap();
+
+        let stored = |cid| store.tree.get(Key::block(cid)).unwrap().unwrap().len();
+        assert_eq!(stored(&cid1), 1025);
+        assert!(stored(&cid2) < 1024);
+        assert_eq!(stored(&cid3), 2);
+        assert_eq!(store.get_local(&cid1).unwrap(), Some(data1));
+        assert_eq!(store.get_local(&cid2).unwrap(), Some(data2));
+        assert_eq!(store.get_local(&cid3).unwrap(), Some(data3));
+        assert_eq!(store.stats().unwrap().bytes, 1024 + stored(&cid2) as u64);
+    }
+
+    #[test]
+    fn test_upgrade_format() {
+        let tmp = TempDir::new("").unwrap();
+        let db = sled::open(tmp.path()).unwrap();
+        let tree = db.open_tree("ipfs_tree").unwrap();
+        let (cid, data) = create_block(b"block");
+        tree.insert(Key::block(&cid), data.clone()).unwrap();
+        let store = Storage::new(tree.clone(), None).unwrap();
+        assert_eq!(store.get_local(&cid).unwrap(), Some(data.clone()));
+        // upgrading twice doesn't prefix the values again
+        let store = Storage::new(tree, None).unwrap();
+        assert_eq!(store.get_local(&cid).unwrap(), Some(data));
+    }
+
+    #[test]
+    fn test_resume_upgrade() {
+        let tmp = TempDir::new("").unwrap();
+        let db = sled::open(tmp.path()).unwrap();
+        let tree = db.open_tree("ipfs_tree").unwrap();
+        let mut blocks: Vec<_> = (0..3)
+            .map(|i| create_block(format!("block {}", i).as_bytes()))
+            .collect();
+        blocks.sort_by_key(|(cid, _)| Key::block(cid));
+        for (cid, data) in &blocks {
+            tree.insert(Key::block(cid), data.clone()).unwrap();
+        }
+        // the upgrade was interrupted after rewriting the first block
+        let (cid, data) = &blocks[0];
+        tree.insert(Key::block(cid), compression::encode(data, None).unwrap())
+            .unwrap();
+        tree.insert(Key::upgrade(), Key::block(cid)).unwrap();
+        let store = Storage::new(tree.clone(), None).unwrap();
+        for (cid, data) in &blocks {
+            assert_eq!(store.get_local(cid).unwrap(), Some(data.clone()));
+        }
+        assert!(tree.get(Key::upgrade()).unwrap().is_none());
+        assert!(tree.get(Key::format()).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_unsupported_format() {
+        let tester = Tester::setup();
+        tester
+            .store
+            .tree
+            .insert(Key::block(&tester.cid), &b"\xffblock"[..])
+            .unwrap();
+        let err = tester.store.get_local(&tester.cid).unwrap_err();
+        assert!(err.downcast_ref::<UnsupportedFormat>().is_some());
+    }
+
+    #[test]
+    fn test_verify() {
+        let (mut store, _tmp) = create_store();
+        store.set_verify(verify::<Multihash>);
+        let (cid, data) = create_block(b"block");
+        let block = Block::<Multicodec, Multihash>::new(cid.clone(), data.to_vec().into());
+        store.insert(&block).unwrap();
+        assert_eq!(store.get_local(&cid).unwrap(), Some(data));
+
+        store
+            .tree
+            .insert(Key::block(&cid), &b"\0corrupted"[..])
+            .unwrap();
+        let err = store.get_local(&cid).unwrap_err();
+        assert!(err.downcast_ref::<InvalidMultihash>().is_some());
+    }
+
+    #[test]
+    fn test_watch_network_lagged() {
+        let (mut store, _tmp) = create_store();
+        store.set_network_capacity(1);
+        let mut net = store.watch_network().unwrap();
+        // more inserts than sled buffers don't wait for the subscriber
+        let mut cids = vec![];
+        for i in 0..2000 {
+            let (cid, data) = create_block(format!("block {}", i).as_bytes());
+            let mut block = Block::<Multicodec, Multihash>::new(cid.clone(), data.to_vec().into());
+            block.set_visibility(Visibility::Public);
+            store.insert_blocks(&[block], true).unwrap();
+            cids.push(cid);
+        }
+        drop(store);
+        let mut events = vec![];
+        while let Some(event) = task::block_on(net.next()) {
+            events.push(event);
+        }
+        assert_eq!(
+            events,
+            vec![
+                NetworkEvent::Provide(cids[0].clone()),
+                NetworkEvent::Provide(cids[1].clone()),
+                NetworkEvent::Lagged,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_watch_network_lag_order() {
+        let (mut store, _tmp) = create_store();
+        store.set_network_capacity(1);
+        let mut net = store.watch_network().unwrap();
+        let insert = |i: usize| {
+            let (cid, data) = create_block(format!("block {}", i).as_bytes());
+            let mut block = Block::<Multicodec, Multihash>::new(cid.clone(), data.to_vec().into());
+            block.set_visibility(Visibility::Public);
+            store.insert_blocks(&[block], true).unwrap();
+            cid
+        };
+        let cids: Vec<_> = (0..5).map(insert).collect();
+        // let the thread forward the events before some are received
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let mut next = || task::block_on(net.next()).unwrap();
+        assert_eq!(next(), NetworkEvent::Provide(cids[0].clone()));
+        assert_eq!(next(), NetworkEvent::Provide(cids[1].clone()));
+        assert_eq!(next(), NetworkEvent::Lagged);
+
+        // the events dropped after the first lag are marked before the
+        // next forwarded event
+        let cid = insert(5);
+        drop(store);
+        let mut events = vec![];
+        while let Some(event) = task::block_on(net.next()) {
+            events.push(event);
+        }
+        assert_eq!(
+            events,
+            vec![NetworkEvent::Lagged, NetworkEvent::Provide(cid)]
+        );
+    }
+
+    #[test]
+    fn test_evict() {
+        fn insert(store: &Storage, bytes: &[u8], pin: bool) -> Cid {
+            let (cid, data) = create_block(bytes);
+            let mut block = Block::<Multicodec, Multihash>::new(cid.clone(), data.to_vec().into());
+            block.set_visibility(Visibility::Public);
+            store.insert_blocks(&[block], pin).unwrap();
+            cid
+        }
+
+        let tmp = TempDir::new("").unwrap();
+        let db = sled::open(tmp.path()).unwrap();
+        let tree = db.open_tree("ipfs_tree").unwrap();
+        let store = Storage::new(tree, Some(10)).unwrap();
+        let mut net = store.watch_network().unwrap();
+        let mut events = |n: usize| {
+            (0..n)
+                .map(|_| task::block_on(net.next()).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let a = insert(&store, b"aaaaa", false);
+        let b = insert(&store, b"bbbbb", false);
+        assert_eq!(store.size(), 10);
+        store.get_local(&a).unwrap();
+
+        // b is the least recently accessed block
+        let c = insert(&store, b"ccccc", false);
+        assert!(store.get_local(&b).unwrap().is_none());
+        assert_eq!(store.size(), 10);
+
+        let d = insert(&store, b"ddddd", true);
+        assert!(store.get_local(&a).unwrap().is_none());
+
+        // pinned blocks are never evicted
+        let e = insert(&store, b"eeeee", true);
+        assert!(store.get_local(&c).unwrap().is_none());
+        assert!(store.get_local(&d).unwrap().is_some());
+        assert!(store.get_local(&e).unwrap().is_some());
+        let f = insert(&store, b"fffff", true);
+        assert_eq!(store.size(), 15);
+
+        use NetworkEvent::{Provide, Unprovide};
+        assert_eq!(
+            events(9),
+            vec![
+                Provide(a.clone()),
+                Provide(b.clone()),
+                Provide(c.clone()),
+                Unprovide(b),
+                Provide(d),
+                Unprovide(a),
+                Provide(e),
+                Unprovide(c),
+                Provide(f),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_evict_index() {
+        fn insert(store: &Storage, bytes: &[u8], pin: bool) -> Cid {
+            let (cid, data) = create_block(bytes);
+            let block = Block::<Multicodec, Multihash>::new(cid.clone(), data.to_vec().into());
+            store.insert_blocks(&[block], pin).unwrap();
+            cid
+        }
+        let evictable = |store: &Storage| store.tree.scan_prefix(Key::Evict.prefix()).count();
+
+        let tmp = TempDir::new("").unwrap();
+        let db = sled::open(tmp.path()).unwrap();
+        let tree = db.open_tree("ipfs_tree").unwrap();
+        let store = Storage::new(tree.clone(), Some(10)).unwrap();
+        let a = insert(&store, b"aaaaa", true);
+        let b = insert(&store, b"bbbbb", false);
+        assert_eq!(evictable(&store), 1);
+
+        // a becomes evictable with its access time from before the pin
+        store.unpin(&a).unwrap();
+        assert_eq!(evictable(&store), 2);
+        let c = insert(&store, b"ccccc", false);
+        assert!(store.get_local(&a).unwrap().is_none());
+        assert_eq!(evictable(&store), 2);
+
+        // the index is rebuilt from the access times when the tree is opened
+        drop(store);
+        assert!(tree.remove(Key::evict(1, &b)).unwrap().is_some());
+        let store = Storage::new(tree, Some(10)).unwrap();
+        assert_eq!(evictable(&store), 2);
+        insert(&store, b"ddddd", false);
+        assert!(store.get_local(&b).unwrap().is_none());
+        assert!(store.get_local(&c).unwrap().is_some());
+    }
 }
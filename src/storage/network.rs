@@ -1,84 +1,118 @@
-use crate::storage::key::Key;
+use crate::storage::forward::{Forward, Forwarded};
+use crate::storage::key::{Key, Value};
 use crate::storage::Storage;
 use async_std::prelude::*;
 use async_std::task::{Context, Poll};
 use core::convert::TryFrom;
 use core::pin::Pin;
 use libipld::cid::Cid;
-use sled::{Event, Subscriber};
+use libipld::error::Result;
+use libp2p_bitswap::Priority;
+use sled::Event;
+
+/// Default number of events buffered between the storage and the network.
+pub(crate) const DEFAULT_NETWORK_CAPACITY: usize = 1024;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum NetworkEvent {
-    Want(Cid),
+    Want(Cid, Priority),
     Cancel(Cid),
     Provide(Cid),
     Unprovide(Cid),
+    Root(Cid),
+    Unroot(Cid),
+    /// The subscriber fell behind and events were dropped. The wants and
+    /// provides need to be read from the storage again.
+    Lagged,
 }
 
+/// Stream of the wants, provides and roots the network acts on.
+///
+/// Buffers up to the capacity set by `set_network_capacity`, after which
+/// events are dropped and `Lagged` is emitted, so a busy network doesn't
+/// grow memory. The events of a transaction are emitted in any order.
 pub struct NetworkSubscriber {
-    public: Subscriber,
-    want: Subscriber,
+    events: Forwarded<NetworkEvent>,
 }
 
 impl Storage {
-    pub fn watch_network(&self) -> NetworkSubscriber {
-        log::trace!("watching public() with prefix {:?}", Key::Public.prefix());
-        log::trace!("watching want() with prefix {:?}", Key::Want.prefix());
-        NetworkSubscriber {
-            public: self.tree.watch_prefix(Key::Public.prefix()),
-            want: self.tree.watch_prefix(Key::Want.prefix()),
-        }
+    /// Sets the number of events buffered by `watch_network` subscribers
+    /// before they lag.
+    pub fn set_network_capacity(&mut self, capacity: usize) {
+        self.network_capacity = capacity;
+    }
+
+    pub fn watch_network(&self) -> Result<NetworkSubscriber> {
+        log::trace!("watching public(), want() and root()");
+        // a single subscriber keeps the events in the order they were written
+        let subscriber = self.tree.watch_prefix(Vec::new());
+        Ok(NetworkSubscriber {
+            events: Forwarded::spawn(
+                "network subscriber",
+                subscriber,
+                self.network_capacity,
+                network_event,
+            )?,
+        })
     }
 }
 
+/// Maps the events of the watched prefixes to network events.
+fn network_event(event: Event) -> Option<NetworkEvent> {
+    let key = match &event {
+        Event::Insert { key, .. } => key,
+        Event::Remove { key } => key,
+    };
+    let prefix = match key.first() {
+        Some(prefix) => *prefix,
+        None => return None,
+    };
+    let is_insert = matches!(event, Event::Insert { .. });
+    let event = if prefix == Key::Want as u8 {
+        let cid = Cid::try_from(&key[1..]).expect("valid cid");
+        match event {
+            Event::Insert { value, .. } => {
+                log::trace!("emit want event {}", cid.to_string());
+                NetworkEvent::Want(cid, Value::from(value).into())
+            }
+            Event::Remove { .. } => {
+                log::trace!("emit cancel event {}", cid.to_string());
+                NetworkEvent::Cancel(cid)
+            }
+        }
+    } else if prefix == Key::Public as u8 {
+        let cid = Cid::try_from(&key[1..]).expect("valid cid");
+        if is_insert {
+            log::trace!("emit provide event {}", cid.to_string());
+            NetworkEvent::Provide(cid)
+        } else {
+            log::trace!("emit unprovide event {}", cid.to_string());
+            NetworkEvent::Unprovide(cid)
+        }
+    } else if prefix == Key::Root as u8 {
+        let cid = Cid::try_from(&key[1..]).expect("valid cid");
+        if is_insert {
+            log::trace!("emit root event {}", cid.to_string());
+            NetworkEvent::Root(cid)
+        } else {
+            log::trace!("emit unroot event {}", cid.to_string());
+            NetworkEvent::Unroot(cid)
+        }
+    } else {
+        return None;
+    };
+    Some(event)
+}
+
 impl Stream for NetworkSubscriber {
     type Item = NetworkEvent;
 
     fn poll_next(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Option<Self::Item>> {
-        match Pin::new(&mut self.want).poll(ctx) {
-            Poll::Ready(Some(event)) => {
-                let key = match &event {
-                    Event::Insert { key, .. } => key,
-                    Event::Remove { key } => key,
-                };
-                let cid = Cid::try_from(&key[1..]).expect("valid cid");
-                let event = match event {
-                    Event::Insert { .. } => {
-                        log::trace!("emit want event {}", cid.to_string());
-                        NetworkEvent::Want(cid)
-                    }
-                    Event::Remove { .. } => {
-                        log::trace!("emit cancel event {}", cid.to_string());
-                        NetworkEvent::Cancel(cid)
-                    }
-                };
-                return Poll::Ready(Some(event));
-            }
-            Poll::Ready(None) => return Poll::Ready(None),
-            Poll::Pending => {}
-        }
-        match Pin::new(&mut self.public).poll(ctx) {
-            Poll::Ready(Some(event)) => {
-                let key = match &event {
-                    Event::Insert { key, .. } => key,
-                    Event::Remove { key } => key,
-                };
-                let cid = Cid::try_from(&key[1..]).expect("valid cid");
-                let event = match event {
-                    Event::Insert { .. } => {
-                        log::trace!("emit provide event {}", cid.to_string());
-                        NetworkEvent::Provide(cid)
-                    }
-                    Event::Remove { .. } => {
-                        log::trace!("emit unprovide event {}", cid.to_string());
-                        NetworkEvent::Unprovide(cid)
-                    }
-                };
-                return Poll::Ready(Some(event));
-            }
-            Poll::Ready(None) => return Poll::Ready(None),
-            Poll::Pending => {}
-        }
-        Poll::Pending
+        Pin::new(&mut self.events)
+            .poll_next(ctx)
+            .map(|event| match event? {
+                Forward::Event(event) => Some(event),
+                Forward::Lagged => Some(NetworkEvent::Lagged),
+            })
     }
 }
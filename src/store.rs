@@ -1,62 +1,135 @@
 use crate::config::Config;
+use crate::error::{Error, Result};
 use crate::gc::GarbageCollector;
-use crate::network::Network;
-use crate::storage::{Metadata, Storage};
+use crate::network::{
+    Bandwidth, BandwidthStats, BitswapStats, ConnectionEvent, DhtRecord, Network, NetworkCommand,
+    PeerInfo, PubsubMessage, WantInfo,
+};
+use crate::storage::{
+    verify, BlockNotLocal, Collected, Metadata, NetworkEvent as StorageEvent, Page, Repaired,
+    Stats, Storage, DEFAULT_PRIORITY,
+};
 use async_std::future::timeout;
 use async_std::task;
 use core::marker::PhantomData;
+use futures::channel::{mpsc, oneshot};
+use futures::future::try_join_all;
+use futures::stream::{Stream, StreamExt};
 use libipld::block::Block;
 use libipld::cid::Cid;
-use libipld::codec::Codec;
-use libipld::error::{BlockNotFound, Result};
-use libipld::multihash::MultihashDigest;
+use libipld::codec::{Codec, Encode};
+use libipld::codec_impl::Multicodec;
+use libipld::error::{Error as IpldError, TypeError, TypeErrorType, UnsupportedMultihash};
+use libipld::ipld::Ipld;
+use libipld::multihash::{Multihash, MultihashDigest};
 use libipld::store::{AliasStore, ReadonlyStore, Store as WritableStore, StoreResult};
 use libp2p::core::{Multiaddr, PeerId};
+use libp2p::identity::PublicKey;
+use libp2p_bitswap::Priority;
 use sled::IVec;
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::sync::Arc;
 use std::time::Duration;
+use thiserror::Error;
+
+/// The network task isn't running anymore.
+#[derive(Debug, Error)]
+#[error("The network has stopped.")]
+pub struct NetworkStopped;
 
 #[derive(Clone, Debug)]
 pub struct Store<C: Codec, M: MultihashDigest> {
     _marker: PhantomData<(C, M)>,
     storage: Storage,
     timeout: Duration,
+    hash_code: u64,
     peer_id: PeerId,
-    address: Multiaddr,
+    public_key: PublicKey,
+    node_name: String,
+    agent_version: String,
+    listen_addresses: Vec<Multiaddr>,
+    bandwidth: Arc<Bandwidth>,
+    commands: mpsc::UnboundedSender<NetworkCommand>,
 }
 
+/// Store with the codecs and hashes supported by `libipld`.
+pub type Ipfs = Store<Multicodec, Multihash>;
+
 impl<C: Codec, M: MultihashDigest> Store<C, M> {
     pub fn new(config: Config) -> Result<Self> {
         let Config {
             tree,
             network,
             timeout,
+            max_storage_bytes,
+            verify_blocks,
+            hash_code,
+            max_block_size,
+            flush_every,
+            offline,
+            gc_threshold,
+            gc_interval,
+            #[cfg(feature = "compression")]
+            compress_blocks,
+            #[cfg(feature = "compression")]
+            compression_level,
         } = config;
+        if M::new(hash_code, &[]).is_err() {
+            return Err(Error::Codec(UnsupportedMultihash(hash_code).into()));
+        }
         let node_name = network.node_name.clone();
+        let agent_version = network
+            .agent_version
+            .clone()
+            .unwrap_or_else(|| node_name.clone());
         let peer_id = network.peer_id();
-        let storage = Storage::new(tree)?;
-        let (network, address) = task::block_on(Network::<C, M>::new(network, storage.clone()))?;
+        let public_key = network.public();
+        let mut storage = Storage::new(tree, max_storage_bytes)?;
+        storage.set_max_block_size(max_block_size);
+        storage.set_flush_every(flush_every);
+        storage.set_offline(offline);
+        storage.set_network_capacity(network.storage_events_capacity);
+        #[cfg(feature = "compression")]
+        if compress_blocks {
+            storage.set_compression(Some(compression_level));
+        }
+        if verify_blocks {
+            storage.set_verify(verify::<M>);
+        }
+        let gc = GarbageCollector::new(storage.clone(), gc_threshold, gc_interval)?;
+        let (commands, receiver) = mpsc::unbounded();
+        let (network, listen_addresses) =
+            task::block_on(Network::<C, M>::new(network, storage.clone(), receiver))?;
+        let bandwidth = network.bandwidth();
 
-        let address_str = address.to_string();
+        let address_str = listen_addresses
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
         let peer_id_str = peer_id.to_base58();
+        let name = node_name.clone();
         task::spawn(async move {
             // make sure async std logs the right task id
-            log::info!(
-                "{}: listening on {} as {}",
-                node_name,
-                address_str,
-                peer_id_str
-            );
+            log::info!("{}: listening on {} as {}", name, address_str, peer_id_str);
             network.await;
         });
 
-        task::spawn(GarbageCollector::new(storage.clone()));
+        task::spawn(gc);
 
         Ok(Self {
             _marker: PhantomData,
             storage,
             timeout,
+            hash_code,
             peer_id,
-            address,
+            public_key,
+            node_name,
+            agent_version,
+            listen_addresses,
+            bandwidth,
+            commands,
         })
     }
 
@@ -64,20 +137,456 @@ impl<C: Codec, M: MultihashDigest> Store<C, M> {
         &self.peer_id
     }
 
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    pub fn node_name(&self) -> &str {
+        &self.node_name
+    }
+
+    /// Agent version sent by identify.
+    pub fn agent_version(&self) -> &str {
+        &self.agent_version
+    }
+
+    /// Returns the first address the node listens on.
     pub fn address(&self) -> &Multiaddr {
-        &self.address
+        &self.listen_addresses[0]
+    }
+
+    /// Returns the addresses the listeners reported on startup, at least one
+    /// for each configured listen address. `addresses` also returns the ones
+    /// reported later.
+    pub fn listen_addresses(&self) -> &[Multiaddr] {
+        &self.listen_addresses
+    }
+
+    /// Multihash code of the cids of blocks created with `put`.
+    pub fn hash_code(&self) -> u64 {
+        self.hash_code
+    }
+
+    /// Returns the addresses the node listens on and the external addresses.
+    pub async fn addresses(&self) -> Result<Vec<Multiaddr>> {
+        let (tx, rx) = oneshot::channel();
+        self.commands
+            .unbounded_send(NetworkCommand::Addresses(tx))
+            .map_err(|_| NetworkStopped)?;
+        Ok(rx.await.map_err(|_| NetworkStopped)?)
+    }
+
+    /// Returns the connected peers with the addresses of their connections.
+    pub async fn peers(&self) -> Result<Vec<PeerInfo>> {
+        let (tx, rx) = oneshot::channel();
+        self.commands
+            .unbounded_send(NetworkCommand::Peers(tx))
+            .map_err(|_| NetworkStopped)?;
+        Ok(rx.await.map_err(|_| NetworkStopped)?)
+    }
+
+    /// Returns the bitswap counters since the store was created.
+    pub async fn bitswap_stats(&self) -> Result<BitswapStats> {
+        let (tx, rx) = oneshot::channel();
+        self.commands
+            .unbounded_send(NetworkCommand::BitswapStats(tx))
+            .map_err(|_| NetworkStopped)?;
+        Ok(rx.await.map_err(|_| NetworkStopped)?)
+    }
+
+    /// Lists the blocks that are currently wanted from the network, with the
+    /// peers they are requested from.
+    pub async fn wantlist(&self) -> Result<Vec<WantInfo>> {
+        let (tx, rx) = oneshot::channel();
+        self.commands
+            .unbounded_send(NetworkCommand::Wantlist(tx))
+            .map_err(|_| NetworkStopped)?;
+        Ok(rx.await.map_err(|_| NetworkStopped)?)
+    }
+
+    /// Returns the bytes transferred over all connections and the current
+    /// transfer rates.
+    pub fn bandwidth_stats(&self) -> BandwidthStats {
+        self.bandwidth.stats()
+    }
+
+    /// Looks up the providers of a block in the DHT. The providers aren't
+    /// dialed.
+    pub async fn providers(&self, cid: &Cid) -> Result<Vec<PeerId>> {
+        let (tx, rx) = oneshot::channel();
+        self.commands
+            .unbounded_send(NetworkCommand::Providers(cid.clone(), tx))
+            .map_err(|_| NetworkStopped)?;
+        Ok(rx.await.map_err(|_| NetworkStopped)?)
+    }
+
+    /// Announces in the DHT that we provide a block. Public blocks are
+    /// provided when they are inserted, this provides private blocks too.
+    /// Fails with `BlockNotLocal` if the block isn't in the store. Does
+    /// nothing offline.
+    pub async fn provide(&self, cid: &Cid) -> Result<()> {
+        if !self.storage.contains(cid)? {
+            return Err(BlockNotLocal(cid.to_string()).into());
+        }
+        let (tx, rx) = oneshot::channel();
+        self.commands
+            .unbounded_send(NetworkCommand::Provide(cid.clone(), tx))
+            .map_err(|_| NetworkStopped)?;
+        Ok(rx.await.map_err(|_| NetworkStopped)??)
+    }
+
+    /// Signs a value with the node key and stores it in the DHT under `key`,
+    /// overwriting our previous value. Fails if no peer stored the record.
+    pub async fn dht_put(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.commands
+            .unbounded_send(NetworkCommand::DhtPut(key.to_vec(), value, tx))
+            .map_err(|_| NetworkStopped)?;
+        Ok(rx.await.map_err(|_| NetworkStopped)??)
+    }
+
+    /// Looks up the records stored in the DHT under `key`. Each record has
+    /// a valid signature of its publisher, others are dropped. Fails with
+    /// `RecordNotFound` if there are none.
+    pub async fn dht_get(&self, key: &[u8]) -> Result<Vec<DhtRecord>> {
+        let (tx, rx) = oneshot::channel();
+        self.commands
+            .unbounded_send(NetworkCommand::DhtGet(key.to_vec(), tx))
+            .map_err(|_| NetworkStopped)?;
+        Ok(rx.await.map_err(|_| NetworkStopped)??)
+    }
+
+    /// Makes a local block public, which provides it in the DHT, or private,
+    /// which stops providing it. Fails with `BlockNotLocal` if the block isn't
+    /// in the store.
+    pub async fn set_public(&self, cid: &Cid, public: bool) -> Result<()> {
+        Ok(self.storage.set_public(cid, public)?)
+    }
+
+    /// Marks a local block as the root of a DAG, which makes it public, or
+    /// unmarks it. With `provide_roots_only` only roots are provided in the
+    /// DHT, the rest of the DAG is served to peers that want it. Fails with
+    /// `BlockNotLocal` if the block isn't in the store.
+    pub async fn set_root(&self, cid: &Cid, root: bool) -> Result<()> {
+        Ok(self.storage.set_root(cid, root)?)
+    }
+
+    /// Returns an iterator over the blocks marked as roots.
+    pub fn roots(&self) -> impl Iterator<Item = Result<Cid>> {
+        self.storage.roots().map(|res| Ok(res?))
+    }
+
+    /// Dials an address and returns the peer id of the remote. Addresses ending
+    /// with `/p2p/<peer id>` are added to the address book.
+    pub async fn connect(&self, address: Multiaddr) -> Result<PeerId> {
+        let (tx, rx) = oneshot::channel();
+        self.commands
+            .unbounded_send(NetworkCommand::Connect(address, tx))
+            .map_err(|_| NetworkStopped)?;
+        Ok(rx.await.map_err(|_| NetworkStopped)??)
+    }
+
+    /// Adds a peer to the denylist. Connections to the peer are closed and
+    /// new ones are refused.
+    pub fn ban(&self, peer_id: PeerId) -> Result<()> {
+        self.commands
+            .unbounded_send(NetworkCommand::Ban(peer_id))
+            .map_err(|_| NetworkStopped)?;
+        Ok(())
+    }
+
+    /// Removes a peer from the denylist.
+    pub fn unban(&self, peer_id: PeerId) -> Result<()> {
+        self.commands
+            .unbounded_send(NetworkCommand::Unban(peer_id))
+            .map_err(|_| NetworkStopped)?;
+        Ok(())
+    }
+
+    /// Stops dialing a configured boot node again while the node isn't
+    /// connected to any peer, and removes it from the DHT.
+    pub fn remove_boot_node(&self, peer_id: PeerId) -> Result<()> {
+        self.commands
+            .unbounded_send(NetworkCommand::RemoveBootNode(peer_id))
+            .map_err(|_| NetworkStopped)?;
+        Ok(())
+    }
+
+    /// Resolves once the DHT was bootstrapped, so that gets can find
+    /// providers. Resolves right away without boot nodes. If the boot nodes
+    /// are unreachable it waits until one of them is dialed successfully.
+    pub async fn bootstrapped(&self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.commands
+            .unbounded_send(NetworkCommand::Bootstrapped(tx))
+            .map_err(|_| NetworkStopped)?;
+        Ok(rx.await.map_err(|_| NetworkStopped)?)
+    }
+
+    /// Stops the network task after cancelling the outstanding wants and
+    /// flushing the storage. Network methods fail with `NetworkStopped`
+    /// afterwards, local blocks can still be read.
+    pub async fn shutdown(&self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.commands
+            .unbounded_send(NetworkCommand::Shutdown(tx))
+            .map_err(|_| NetworkStopped)?;
+        Ok(rx.await.map_err(|_| NetworkStopped)?)
+    }
+
+    /// Subscribes to a pubsub topic. The subscription ends when the stream is
+    /// dropped. Fails with `PubsubDisabled` unless pubsub is enabled in the
+    /// network config.
+    pub async fn subscribe(&self, topic: &str) -> Result<impl Stream<Item = PubsubMessage>> {
+        let (subscriber, messages) = mpsc::unbounded();
+        let (tx, rx) = oneshot::channel();
+        self.commands
+            .unbounded_send(NetworkCommand::Subscribe(topic.to_string(), subscriber, tx))
+            .map_err(|_| NetworkStopped)?;
+        rx.await.map_err(|_| NetworkStopped)??;
+        Ok(messages)
+    }
+
+    /// Returns a stream of connection events. The stream ends when the network
+    /// stops.
+    pub fn watch_connections(&self) -> Result<impl Stream<Item = ConnectionEvent>> {
+        let (watcher, events) = mpsc::unbounded();
+        self.commands
+            .unbounded_send(NetworkCommand::WatchConnections(watcher))
+            .map_err(|_| NetworkStopped)?;
+        Ok(events)
+    }
+
+    /// Publishes a message on a pubsub topic.
+    pub async fn publish(&self, topic: &str, data: Vec<u8>) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.commands
+            .unbounded_send(NetworkCommand::Publish(topic.to_string(), data, tx))
+            .map_err(|_| NetworkStopped)?;
+        Ok(rx.await.map_err(|_| NetworkStopped)??)
     }
 
     pub fn blocks(&self) -> impl Iterator<Item = Result<Cid>> {
-        self.storage.blocks()
+        self.storage.blocks().map(|res| Ok(res?))
+    }
+
+    /// Returns up to `limit` blocks, starting after the block `start_after`.
+    /// Pass the `next` cursor of a page to get the following page.
+    pub fn blocks_page(&self, start_after: Option<&Cid>, limit: usize) -> Result<Page> {
+        Ok(self.storage.blocks_page(start_after, limit)?)
+    }
+
+    /// Counts the stored blocks. Iterates over all blocks.
+    pub fn stats(&self) -> Result<Stats> {
+        Ok(self.storage.stats()?)
     }
 
     pub fn metadata(&self, cid: &Cid) -> Result<Metadata> {
-        self.storage.metadata(cid)
+        Ok(self.storage.metadata(cid)?)
+    }
+
+    /// Returns the metadata of many blocks, read in a single transaction.
+    pub fn metadata_batch(&self, cids: &[Cid]) -> Result<Vec<(Cid, Metadata)>> {
+        Ok(self.storage.metadata_batch(cids)?)
     }
 
     pub fn get_local(&self, cid: &Cid) -> Result<Option<IVec>> {
-        self.storage.get_local(cid)
+        Ok(self.storage.get_local(cid)?)
+    }
+
+    /// Returns `len` bytes of a locally stored block starting at `offset`, or
+    /// fewer if the block ends before, for example to answer HTTP range
+    /// requests. The whole block is still read from the database, but only
+    /// the range is returned.
+    pub fn get_range(&self, cid: &Cid, offset: usize, len: usize) -> Result<Option<IVec>> {
+        Ok(self.storage.get_range(cid, offset, len)?)
+    }
+
+    /// Returns true if the block is stored locally, without reading it.
+    pub fn contains(&self, cid: &Cid) -> Result<bool> {
+        Ok(self.storage.contains(cid)?)
+    }
+
+    /// Gets a block, fetching it from the network with `priority` if it isn't
+    /// stored locally. Higher priority wants are sent to peers first.
+    pub async fn get_with_priority(&self, cid: &Cid, priority: Priority) -> Result<Block<C, M>> {
+        let future = self.storage.get(cid, priority);
+        let block = timeout(self.timeout, future)
+            .await
+            .map_err(|_| Error::Timeout(cid.to_string()))??;
+        Ok(Block::new(cid.clone(), block.to_vec().into_boxed_slice()))
+    }
+
+    /// Fetches the DAG rooted at `cid` down to `max_depth` levels below the
+    /// root. The wants for each level are sent together, and the returned
+    /// future resolves once all blocks of the subgraph are stored locally.
+    /// Returns the number of blocks in the subgraph.
+    pub async fn get_graph(&self, cid: &Cid, max_depth: usize) -> Result<usize> {
+        let mut visited = HashSet::new();
+        let mut level = vec![cid.clone()];
+        for depth in 0..=max_depth {
+            visited.extend(level.iter().cloned());
+            let gets = level
+                .iter()
+                .map(|cid| self.get_with_priority(cid, DEFAULT_PRIORITY));
+            let blocks = try_join_all(gets).await?;
+            if depth == max_depth {
+                break;
+            }
+            let mut refs = HashSet::new();
+            for block in &blocks {
+                refs.extend(block.decode_ipld()?.references());
+            }
+            level = refs
+                .into_iter()
+                .filter(|cid| !visited.contains(cid))
+                .collect();
+            if level.is_empty() {
+                break;
+            }
+        }
+        Ok(visited.len())
+    }
+
+    /// Follows the links under `field` from block to block, fetching the
+    /// blocks that aren't stored locally, until a block that has no such
+    /// field or where it is null. Returns the cid of that last block and the
+    /// cids of the traversed blocks, from `cid` to the last block. Fails with
+    /// a `Codec` error if the field holds something other than a link.
+    pub async fn resolve_chain(&self, cid: &Cid, field: &str) -> Result<(Cid, Vec<Cid>)> {
+        let mut path = vec![cid.clone()];
+        loop {
+            let cid = path.last().unwrap();
+            let ipld = self
+                .get_with_priority(cid, DEFAULT_PRIORITY)
+                .await?
+                .decode_ipld()?;
+            let next = match ipld.get(field) {
+                Ok(Ipld::Link(next)) => next.clone(),
+                Ok(Ipld::Null) | Err(_) => return Ok((cid.clone(), path)),
+                Ok(other) => {
+                    let err = TypeError::new(TypeErrorType::Link, other);
+                    return Err(IpldError::from(err).into());
+                }
+            };
+            path.push(next);
+        }
+    }
+
+    /// Returns a stream of blocks inserted into the store, either locally or
+    /// received from the network. Blocks are dropped while the stream's
+    /// buffer is full, so subscribers that fall behind miss blocks. Fails if
+    /// the thread forwarding the blocks can't be spawned.
+    pub fn watch_blocks(&self) -> Result<impl Stream<Item = Block<C, M>>> {
+        Ok(self
+            .storage
+            .watch_blocks()?
+            .map(|(cid, data)| Block::new(cid, data.to_vec().into_boxed_slice())))
+    }
+
+    /// Returns a stream of the storage events the network acts on: blocks
+    /// that are wanted or cancelled, blocks that are provided or stop being
+    /// provided, and roots that are marked or unmarked. Events are dropped
+    /// and `Lagged` is emitted when the stream falls behind by more than
+    /// `storage_events_capacity`. Fails if the thread forwarding the events
+    /// can't be spawned.
+    pub fn watch_storage(&self) -> Result<impl Stream<Item = StorageEvent>> {
+        Ok(self.storage.watch_network()?)
+    }
+
+    /// Encodes `payload` with `codec` and inserts it as a pinned block. The
+    /// cid is computed with the hash code from the config.
+    pub async fn put<CE, T>(&self, codec: CE, payload: &T) -> Result<Block<C, M>>
+    where
+        CE: Codec + Into<C>,
+        T: Encode<CE> + ?Sized,
+    {
+        let block = Block::encode(codec, self.hash_code, payload)?;
+        self.storage.insert(&block)?;
+        Ok(block)
+    }
+
+    /// Inserts blocks in a single transaction. With `pin` the last block is
+    /// pinned in the same transaction, so it is never stored unpinned and
+    /// can't be collected in between. Returns the cids of the blocks that
+    /// weren't stored yet.
+    pub async fn insert_blocks(&self, batch: &[Block<C, M>], pin: bool) -> Result<Vec<Cid>> {
+        Ok(self.storage.insert_blocks(batch, pin)?)
+    }
+
+    /// Increases the ref count on a cid. Fails with `BlockNotLocal` if the block
+    /// isn't in the store.
+    pub async fn pin(&self, cid: &Cid) -> Result<()> {
+        Ok(self.storage.pin(cid)?)
+    }
+
+    /// Pins a cid under a label, like a dataset name. The block stays live
+    /// while any of its labels remain. Pinning twice under the same label
+    /// does nothing. Fails with `BlockNotLocal` if the block isn't in the
+    /// store.
+    pub async fn pin_named(&self, cid: &Cid, label: &str) -> Result<()> {
+        Ok(self.storage.pin_named(cid, label)?)
+    }
+
+    /// Removes the pin of a label. Pins of other labels are kept, and a plain
+    /// `unpin` doesn't remove the pins of labels.
+    pub async fn unpin_named(&self, cid: &Cid, label: &str) -> Result<()> {
+        Ok(self.storage.unpin_named(cid, label)?)
+    }
+
+    /// Removes all pins, including the pins of labels, so the next garbage
+    /// collection removes every block. Returns the number of unpinned blocks.
+    pub async fn unpin_all(&self) -> Result<usize> {
+        Ok(self.storage.unpin_all()?)
+    }
+
+    /// Returns an iterator over the pinned blocks.
+    pub fn pinned(&self) -> impl Iterator<Item = Result<Cid>> {
+        self.storage.pinned().map(|res| Ok(res?))
+    }
+
+    /// Returns the blocks reachable from `root` that aren't stored locally,
+    /// which need to be fetched to complete the DAG.
+    pub fn missing(&self, root: &Cid) -> Result<Vec<Cid>> {
+        Ok(self.storage.missing(root)?)
+    }
+
+    /// Writes the DAG rooted at `root` to a CARv1 file. Missing blocks fail
+    /// the export with `BlockNotLocal` unless `skip_missing` is set. Returns
+    /// the number of blocks written.
+    pub fn export_car<W: Write>(&self, root: &Cid, writer: W, skip_missing: bool) -> Result<usize> {
+        Ok(crate::car::export(
+            &self.storage,
+            root,
+            writer,
+            skip_missing,
+        )?)
+    }
+
+    /// Inserts the blocks of a CARv1 file. The blocks are checked against their
+    /// cids and aren't pinned, unless `pin` is set in which case the roots are
+    /// pinned. Returns the roots from the header.
+    pub fn import_car<R: Read>(&self, reader: R, pin: bool) -> Result<Vec<Cid>> {
+        Ok(crate::car::import::<C, M, R>(&self.storage, reader, pin)?)
+    }
+
+    /// Removes all blocks that aren't reachable from a pinned block. With
+    /// `dry_run` nothing is removed.
+    pub async fn collect_garbage(&self, dry_run: bool) -> Result<Collected> {
+        Ok(self.storage.collect_garbage(dry_run)?)
+    }
+
+    /// Rebuilds the references and referer counts of all blocks from their
+    /// data, for example after they got out of sync in a crash. Returns the
+    /// blocks that were fixed. The store shouldn't be used while it runs.
+    pub fn repair(&self) -> Result<Repaired> {
+        Ok(self.storage.repair::<C, M>()?)
+    }
+
+    /// Pins a cid and all blocks reachable from it. Returns the number of blocks
+    /// pinned. Nothing is pinned if one of the blocks isn't stored locally.
+    pub async fn pin_recursive(&self, cid: &Cid) -> Result<usize> {
+        Ok(self.storage.pin_recursive(cid)?)
     }
 }
 
@@ -88,11 +597,9 @@ impl<C: Codec, M: MultihashDigest> ReadonlyStore for Store<C, M> {
 
     fn get<'a>(&'a self, cid: Cid) -> StoreResult<'a, Block<C, M>> {
         Box::pin(async move {
-            let future = self.storage.get(&cid);
-            let block = timeout(self.timeout, future)
+            self.get_with_priority(&cid, DEFAULT_PRIORITY)
                 .await
-                .map_err(|_| BlockNotFound(cid.to_string()))??;
-            Ok(Block::new(cid, block.to_vec().into_boxed_slice()))
+                .map_err(Error::into_ipld_error)
         })
     }
 }
@@ -132,27 +639,46 @@ impl<C: Codec, M: MultihashDigest> AliasStore for Store<C, M> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::network::{NetworkConfig, Security};
+    use core::num::{NonZeroU64, NonZeroUsize};
     use libipld::block::{Block, Visibility};
     use libipld::cbor::DagCborCodec;
     use libipld::codec_impl::Multicodec;
+    use libipld::error::{BlockNotFound, BlockTooLarge};
     use libipld::ipld;
     use libipld::ipld::Ipld;
-    use libipld::multihash::{Multihash, MultihashDigest, SHA2_256};
+    use libipld::multihash::{Multihash, MultihashDigest, BLAKE2B_256, SHA2_256};
     use libipld::raw::RawCodec;
+    use libp2p::multiaddr::Protocol;
+    use libp2p::pnet::PreSharedKey;
     use std::time::Duration;
     use tempdir::TempDir;
 
     fn create_store(
         bootstrap: Vec<(Multiaddr, PeerId)>,
+    ) -> (Store<Multicodec, Multihash>, TempDir) {
+        create_store_with(bootstrap, |_| {})
+    }
+
+    /// Creates a store whose config is changed by `configure` before it is
+    /// opened.
+    fn create_store_with(
+        bootstrap: Vec<(Multiaddr, PeerId)>,
+        configure: impl FnOnce(&mut Config),
     ) -> (Store<Multicodec, Multihash>, TempDir) {
         let tmp = TempDir::new("").unwrap();
         let mut config = Config::from_path_local(tmp.path()).unwrap();
         config.network.enable_mdns = bootstrap.is_empty();
+        configure(&mut config);
         config.network.boot_nodes = bootstrap;
         let store = Store::new(config).unwrap();
         (store, tmp)
     }
 
+    fn disable_mdns(config: &mut Config) {
+        config.network.enable_mdns = false;
+    }
+
     fn create_block(bytes: &[u8]) -> Block<Multicodec, Multihash> {
         Block::encode(RawCodec, SHA2_256, bytes).unwrap()
     }
@@ -168,6 +694,41 @@ mod tests {
         assert_eq!(block.data, block2.data);
     }
 
+    #[async_std::test]
+    async fn test_watch_blocks() {
+        env_logger::try_init().ok();
+        let (store, _) = create_store(vec![]);
+        let mut blocks = store.watch_blocks().unwrap();
+        let block = create_block(b"test_watch_blocks");
+        store.insert(&block).await.unwrap();
+        let block2 = blocks.next().await.unwrap();
+        assert_eq!(block.cid, block2.cid);
+        assert_eq!(block.data, block2.data);
+    }
+
+    #[async_std::test]
+    async fn test_watch_storage() {
+        env_logger::try_init().ok();
+        let (store, _tmp) = create_store_with(vec![], disable_mdns);
+        let mut events = store.watch_storage().unwrap();
+        let block = create_block(b"test_watch_storage");
+        store.insert(&block).await.unwrap();
+        assert_eq!(
+            events.next().await,
+            Some(StorageEvent::Provide(block.cid.clone()))
+        );
+        // without peers there are no providers, so the get fails and its want
+        // is cancelled
+        let missing = create_block(b"test_watch_storage_missing");
+        let err = store.get_with_priority(&missing.cid, 5).await.unwrap_err();
+        assert!(matches!(err, Error::BlockNotFound(_)));
+        assert_eq!(
+            events.next().await,
+            Some(StorageEvent::Want(missing.cid.clone(), 5))
+        );
+        assert_eq!(events.next().await, Some(StorageEvent::Cancel(missing.cid)));
+    }
+
     #[async_std::test]
     #[cfg(not(target_os = "macos"))] // mdns doesn't work on macos in github actions
     async fn test_exchange_mdns() {
@@ -200,6 +761,63 @@ mod tests {
         assert_eq!(block.data, block2.data);
     }
 
+    #[async_std::test]
+    async fn test_exchange_memory() {
+        env_logger::try_init().ok();
+        let create_store = |bootstrap| {
+            create_store_with(bootstrap, |config| {
+                config.network = NetworkConfig::new_memory();
+            })
+        };
+        let (store1, _tmp1) = create_store(vec![]);
+        assert!(matches!(
+            store1.address().iter().next(),
+            Some(Protocol::Memory(_))
+        ));
+        let bootstrap = vec![(store1.address().clone(), store1.peer_id().clone())];
+        let (store2, _tmp2) = create_store(bootstrap);
+        let block = create_block(b"test_exchange_memory");
+        store1.insert(&block).await.unwrap();
+        let block2 = store2.get(block.cid).await.unwrap();
+        assert_eq!(block.data, block2.data);
+    }
+
+    #[async_std::test]
+    async fn test_listen_addresses() {
+        let (store, _tmp) = create_store_with(vec![], |config| {
+            config.network = NetworkConfig::new_memory();
+            let listen: Multiaddr = "/memory/0".parse().unwrap();
+            config.network.listen_addresses = vec![listen.clone(), listen];
+        });
+        let addrs = store.listen_addresses();
+        assert_eq!(addrs.len(), 2);
+        assert_ne!(addrs[0], addrs[1]);
+        assert_eq!(store.address(), &addrs[0]);
+
+        let tmp = TempDir::new("").unwrap();
+        let mut config = Config::from_path_local(tmp.path()).unwrap();
+        config.network = NetworkConfig::new_memory();
+        config.network.listen_addresses = vec![];
+        assert!(Store::<Multicodec, Multihash>::new(config).is_err());
+    }
+
+    #[test]
+    fn test_listen_address_in_use() {
+        let (store, _tmp) = create_store_with(vec![], |config| {
+            config.network = NetworkConfig::new_memory();
+        });
+
+        // the second listener can't bind the port of the first store
+        let tmp = TempDir::new("").unwrap();
+        let mut config = Config::from_path_local(tmp.path()).unwrap();
+        config.network = NetworkConfig::new_memory();
+        config
+            .network
+            .listen_addresses
+            .push(store.address().clone());
+        assert!(Store::<Multicodec, Multihash>::new(config).is_err());
+    }
+
     #[async_std::test]
     async fn test_exchange_kad() {
         let logger = env_logger::Builder::from_default_env().build();
@@ -222,6 +840,545 @@ mod tests {
         task::sleep(Duration::from_millis(500)).await;
         let block2 = store2.get(block.cid).await.unwrap();
         assert_eq!(block.data, block2.data);
+
+        let stats = store2.bitswap_stats().await.unwrap();
+        // the want is sent to the connected peers again if the DHT search
+        // finished before the block was received
+        assert!((1..=2).contains(&stats.wants_sent));
+        assert_eq!(stats.blocks_received, 1);
+        assert_eq!(stats.bytes_received, block.data.len() as u64);
+        let stats = store1.bitswap_stats().await.unwrap();
+        assert!(stats.wants_received >= 1);
+        assert!(stats.blocks_sent >= 1);
+    }
+
+    #[async_std::test]
+    async fn test_bootstrapped() {
+        env_logger::try_init().ok();
+        let (store, _) = create_store(vec![]);
+        store.bootstrapped().await.unwrap();
+        let bootstrap = vec![(store.address().clone(), store.peer_id().clone())];
+        let (store1, _) = create_store(bootstrap);
+        timeout(Duration::from_secs(10), store1.bootstrapped())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(store1.peers().await.unwrap().len(), 1);
+    }
+
+    #[async_std::test]
+    async fn test_providers() {
+        env_logger::try_init().ok();
+        let (store, _) = create_store(vec![]);
+        // make sure bootstrap node has started
+        task::sleep(Duration::from_millis(500)).await;
+        let bootstrap = vec![(store.address().clone(), store.peer_id().clone())];
+        let (store1, _) = create_store(bootstrap.clone());
+        let (store2, _) = create_store(bootstrap);
+        let block = create_block(b"test_providers");
+        store1.insert(&block).await.unwrap();
+        // make insert had enough time to propagate
+        task::sleep(Duration::from_millis(500)).await;
+        let providers = store2.providers(&block.cid).await.unwrap();
+        assert_eq!(providers, vec![store1.peer_id().clone()]);
+        let stats = store2.bitswap_stats().await.unwrap();
+        assert_eq!(stats.wants_sent, 0);
+    }
+
+    #[async_std::test]
+    async fn test_dht_records() {
+        env_logger::try_init().ok();
+        let (store, _) = create_store(vec![]);
+        // make sure bootstrap node has started
+        task::sleep(Duration::from_millis(500)).await;
+        let bootstrap = vec![(store.address().clone(), store.peer_id().clone())];
+        let (store1, _) = create_store(bootstrap.clone());
+        let (store2, _) = create_store(bootstrap);
+        task::sleep(Duration::from_millis(500)).await;
+        store1
+            .dht_put(b"test_dht_records", b"value".to_vec())
+            .await
+            .unwrap();
+        let records = store2.dht_get(b"test_dht_records").await.unwrap();
+        let expected = DhtRecord {
+            publisher: store1.peer_id().clone(),
+            value: b"value".to_vec(),
+        };
+        assert_eq!(records, vec![expected]);
+
+        let err = store2.dht_get(b"missing").await.unwrap_err();
+        assert!(matches!(err, Error::RecordNotFound(_)));
+    }
+
+    #[async_std::test]
+    async fn test_protocol_prefix() {
+        env_logger::try_init().ok();
+        let create_store = |bootstrap, prefix: &str| {
+            create_store_with(bootstrap, |config| {
+                config.network.enable_mdns = false;
+                config.network.protocol_prefix = prefix.to_string();
+            })
+        };
+        let (store, _) = create_store(vec![], "/private");
+        task::sleep(Duration::from_millis(500)).await;
+        let bootstrap = vec![(store.address().clone(), store.peer_id().clone())];
+        let (store1, _) = create_store(bootstrap.clone(), "/private");
+        let (store2, _) = create_store(bootstrap, "");
+        task::sleep(Duration::from_millis(500)).await;
+        store1
+            .dht_put(b"test_protocol_prefix", b"value".to_vec())
+            .await
+            .unwrap();
+        // the node without the prefix can't query the private dht
+        let err = store2.dht_get(b"test_protocol_prefix").await.unwrap_err();
+        assert!(matches!(err, Error::RecordNotFound(_)));
+    }
+
+    #[async_std::test]
+    async fn test_peers() {
+        env_logger::try_init().ok();
+        let (store, _) = create_store(vec![]);
+        let bootstrap = vec![(store.address().clone(), store.peer_id().clone())];
+        let (store1, _) = create_store(bootstrap);
+        task::sleep(Duration::from_millis(500)).await;
+        let peers = store1.peers().await.unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(&peers[0].peer_id, store.peer_id());
+        assert_eq!(&peers[0].addresses, &[store.address().clone()]);
+
+        // the agent version is known once identify completed
+        let mut peers = peers;
+        for _ in 0..50 {
+            if peers[0].agent_version.is_some() {
+                break;
+            }
+            task::sleep(Duration::from_millis(100)).await;
+            peers = store1.peers().await.unwrap();
+        }
+        assert_eq!(
+            peers[0].agent_version.as_deref(),
+            Some(store.agent_version())
+        );
+    }
+
+    #[async_std::test]
+    async fn test_pubsub() {
+        env_logger::try_init().ok();
+        let create_store = |bootstrap: Vec<(Multiaddr, PeerId)>| {
+            create_store_with(bootstrap, |config| {
+                config.network.enable_mdns = false;
+                config.network.enable_pubsub = true;
+            })
+        };
+        let (store, _tmp) = create_store(vec![]);
+        let bootstrap = vec![(store.address().clone(), store.peer_id().clone())];
+        let (store1, _tmp1) = create_store(bootstrap.clone());
+        let mut messages = store.subscribe("topic").await.unwrap();
+
+        // publishing fails until the subscription reached the peer
+        let mut res = store1.publish("topic", b"hello".to_vec()).await;
+        for _ in 0..50 {
+            if res.is_ok() {
+                break;
+            }
+            task::sleep(Duration::from_millis(100)).await;
+            res = store1.publish("topic", b"hello".to_vec()).await;
+        }
+        res.unwrap();
+        let message = messages.next().await.unwrap();
+        assert_eq!(message.topic, "topic");
+        assert_eq!(message.source.as_ref(), Some(store1.peer_id()));
+        assert_eq!(message.data, b"hello");
+
+        let (store2, _tmp2) = self::create_store(bootstrap);
+        let err = store2.publish("topic", vec![]).await.unwrap_err();
+        assert!(matches!(err, Error::PubsubDisabled(_)));
+    }
+
+    #[async_std::test]
+    async fn test_addresses() {
+        env_logger::try_init().ok();
+        let public: Multiaddr = "/ip4/1.2.3.4/tcp/4001".parse().unwrap();
+        let (store, _tmp) = create_store_with(vec![], |config| {
+            config.network.enable_mdns = false;
+            config.network.public_addresses = vec![public.clone()];
+        });
+        let addresses = store.addresses().await.unwrap();
+        assert_eq!(addresses, vec![store.address().clone(), public]);
+    }
+
+    #[async_std::test]
+    async fn test_connect() {
+        env_logger::try_init().ok();
+        let (store1, _) = create_store(vec![]);
+        let (store2, _) = create_store(vec![]);
+        let address = store1.address().clone();
+        let peer_id = store2.connect(address.clone()).await.unwrap();
+        assert_eq!(&peer_id, store1.peer_id());
+
+        let (store3, _) = create_store(vec![]);
+        let address = address.with(Protocol::P2p(store1.peer_id().clone().into()));
+        let peer_id = store3.connect(address).await.unwrap();
+        assert_eq!(&peer_id, store1.peer_id());
+    }
+
+    #[async_std::test]
+    async fn test_connect_dns() {
+        env_logger::try_init().ok();
+        let (store1, _) = create_store(vec![]);
+        let (store2, _) = create_store(vec![]);
+        let port = match store1.address().iter().last() {
+            Some(Protocol::Tcp(port)) => port,
+            _ => panic!("expected a tcp address"),
+        };
+        let address = Multiaddr::empty()
+            .with(Protocol::Dns4("localhost".into()))
+            .with(Protocol::Tcp(port));
+        let peer_id = store2.connect(address).await.unwrap();
+        assert_eq!(&peer_id, store1.peer_id());
+    }
+
+    #[async_std::test]
+    async fn test_connect_failed() {
+        env_logger::try_init().ok();
+        let (store, _) = create_store(vec![]);
+        let address = "/ip4/127.0.0.1/tcp/1".parse().unwrap();
+        let err = store.connect(address).await.unwrap_err();
+        assert!(matches!(err, Error::ConnectionFailed(_)));
+    }
+
+    #[async_std::test]
+    async fn test_psk() {
+        env_logger::try_init().ok();
+        let create_store = |psk| {
+            create_store_with(vec![], |config| {
+                config.network.enable_mdns = false;
+                config.network.psk = psk;
+            })
+        };
+        let psk = Some(PreSharedKey::new([1; 32]));
+        let (store1, _tmp1) = create_store(psk);
+        let (store2, _tmp2) = create_store(psk);
+        let address = store1.address().clone();
+        let peer_id = store2.connect(address.clone()).await.unwrap();
+        assert_eq!(&peer_id, store1.peer_id());
+
+        let (store3, _tmp3) = create_store(Some(PreSharedKey::new([2; 32])));
+        let err = store3.connect(address).await.unwrap_err();
+        assert!(matches!(err, Error::ConnectionFailed(_)));
+    }
+
+    #[async_std::test]
+    async fn test_security_both() {
+        env_logger::try_init().ok();
+        let create_store = |security| {
+            create_store_with(vec![], |config| {
+                config.network.enable_mdns = false;
+                config.network.security = security;
+            })
+        };
+        let (both, _tmp) = create_store(Security::Both);
+        let (noise, _tmp1) = create_store(Security::Noise);
+        let (secio, _tmp2) = create_store(Security::Secio);
+        for store in &[&noise, &secio] {
+            let peer_id = store.connect(both.address().clone()).await.unwrap();
+            assert_eq!(&peer_id, both.peer_id());
+            let peer_id = both.connect(store.address().clone()).await.unwrap();
+            assert_eq!(&peer_id, store.peer_id());
+        }
+        let err = secio.connect(noise.address().clone()).await.unwrap_err();
+        assert!(matches!(err, Error::ConnectionFailed(_)));
+    }
+
+    #[async_std::test]
+    async fn test_ban() {
+        env_logger::try_init().ok();
+        let (store1, _tmp1) = create_store_with(vec![], disable_mdns);
+        let (store2, _tmp2) = create_store_with(vec![], disable_mdns);
+        let address = store2
+            .address()
+            .clone()
+            .with(Protocol::P2p(store2.peer_id().clone().into()));
+        store1.ban(store2.peer_id().clone()).unwrap();
+        let err = store1.connect(address.clone()).await.unwrap_err();
+        assert!(matches!(err, Error::ConnectionFailed(_)));
+
+        store1.unban(store2.peer_id().clone()).unwrap();
+        let peer_id = store1.connect(address).await.unwrap();
+        assert_eq!(&peer_id, store2.peer_id());
+    }
+
+    #[async_std::test]
+    async fn test_watch_connections() {
+        env_logger::try_init().ok();
+        let (store1, _tmp1) = create_store_with(vec![], disable_mdns);
+        let (store2, _tmp2) = create_store_with(vec![], disable_mdns);
+        let mut events = store1.watch_connections().unwrap();
+        store1.connect(store2.address().clone()).await.unwrap();
+        match events.next().await {
+            Some(ConnectionEvent::Established(peer_id, address)) => {
+                assert_eq!(&peer_id, store2.peer_id());
+                assert_eq!(&address, store2.address());
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        store1.ban(store2.peer_id().clone()).unwrap();
+        match events.next().await {
+            Some(ConnectionEvent::Closed(peer_id, _)) => assert_eq!(&peer_id, store2.peer_id()),
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[async_std::test]
+    async fn test_max_block_size() {
+        env_logger::try_init().ok();
+        let create_store = |max_block_size| {
+            create_store_with(vec![], |config| {
+                config.network.enable_mdns = false;
+                config.timeout = Duration::from_millis(1000);
+                config.max_block_size = max_block_size;
+            })
+        };
+        let (store1, _tmp1) = create_store(crate::MAX_BLOCK_SIZE);
+        let (store2, _tmp2) = create_store(16);
+        let block = create_block(&[0; 100]);
+        let err = store2.insert(&block).await.unwrap_err();
+        assert!(err.downcast_ref::<BlockTooLarge>().is_some());
+
+        // the block is dropped when received
+        store1.insert(&block).await.unwrap();
+        store2.connect(store1.address().clone()).await.unwrap();
+        let err = store2.get_with_priority(&block.cid, 1).await.unwrap_err();
+        assert!(matches!(err, Error::Timeout(_)));
+        assert!(store2.get_local(&block.cid).unwrap().is_none());
+    }
+
+    #[async_std::test]
+    async fn test_duplicate_blocks() {
+        env_logger::try_init().ok();
+        let (store1, _tmp1) = create_store_with(vec![], disable_mdns);
+        let (store2, _tmp2) = create_store_with(vec![], disable_mdns);
+        let (store3, _tmp3) = create_store_with(vec![], disable_mdns);
+        let block = create_block(b"test_duplicate_blocks");
+        store1.insert(&block).await.unwrap();
+        store2.insert(&block).await.unwrap();
+        store3.connect(store1.address().clone()).await.unwrap();
+        store3.connect(store2.address().clone()).await.unwrap();
+        let block2 = store3.get(block.cid.clone()).await.unwrap();
+        assert_eq!(block.data, block2.data);
+        // give the second seeder time to respond
+        task::sleep(Duration::from_millis(500)).await;
+
+        // the block is inserted once, a second insert would pin it again
+        let stats = store3.bitswap_stats().await.unwrap();
+        assert_eq!(stats.blocks_received - stats.duplicate_blocks_received, 1);
+        assert_eq!(store3.metadata(&block.cid).unwrap().pins, 1);
+        assert!(store3.wantlist().await.unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_idle_connection_timeout() {
+        env_logger::try_init().ok();
+        let create_store = |idle_connection_timeout| {
+            create_store_with(vec![], |config| {
+                config.network.enable_mdns = false;
+                config.network.idle_connection_timeout = idle_connection_timeout;
+            })
+        };
+        let (store1, _tmp1) = create_store(Some(Duration::from_secs(1)));
+        let (store2, _tmp2) = create_store(None);
+        store1.connect(store2.address().clone()).await.unwrap();
+        assert_eq!(store1.peers().await.unwrap().len(), 1);
+        task::sleep(Duration::from_secs(3)).await;
+        assert!(store1.peers().await.unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_idle_connection_per_peer() {
+        env_logger::try_init().ok();
+        let create_store = |idle_connection_timeout| {
+            create_store_with(vec![], |config| {
+                config.network.enable_mdns = false;
+                config.network.idle_connection_timeout = idle_connection_timeout;
+                // the missing block stays wanted while the peers are checked
+                config.network.want_policy.connected_peers_fallback = Some(Duration::from_secs(10));
+            })
+        };
+        let (store1, _tmp1) = create_store(Some(Duration::from_secs(1)));
+        let (active, _tmp2) = create_store(None);
+        let (idle, _tmp3) = create_store(None);
+        store1.connect(active.address().clone()).await.unwrap();
+        store1.connect(idle.address().clone()).await.unwrap();
+        let missing = create_block(b"test_idle_connection_per_peer");
+        let _get = {
+            let store1 = store1.clone();
+            task::spawn(async move { store1.get(missing.cid).await })
+        };
+        // the active peer keeps fetching blocks from us
+        for i in 0..10u8 {
+            let mut block = create_block(&[i]);
+            block.set_visibility(Visibility::Private);
+            store1.insert(&block).await.unwrap();
+            active.get(block.cid.clone()).await.unwrap();
+            task::sleep(Duration::from_millis(300)).await;
+        }
+        assert_eq!(store1.wantlist().await.unwrap().len(), 1);
+        let peers: Vec<_> = store1
+            .peers()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|peer| peer.peer_id)
+            .collect();
+        assert_eq!(peers, vec![active.peer_id().clone()]);
+    }
+
+    #[async_std::test]
+    async fn test_bandwidth_stats() {
+        env_logger::try_init().ok();
+        let (store1, _tmp1) = create_store_with(vec![], disable_mdns);
+        let (store2, _tmp2) = create_store_with(vec![], disable_mdns);
+        assert_eq!(store1.bandwidth_stats().total_outbound, 0);
+        store1.connect(store2.address().clone()).await.unwrap();
+        let stats = store1.bandwidth_stats();
+        assert!(stats.total_inbound > 0);
+        assert!(stats.total_outbound > 0);
+    }
+
+    #[async_std::test]
+    async fn test_max_send_rate() {
+        env_logger::try_init().ok();
+        let create_store = |max_send_rate| {
+            create_store_with(vec![], |config| {
+                config.network.enable_mdns = false;
+                config.network.max_send_rate = max_send_rate;
+                // the last block is only sent after two seconds
+                config.network.want_policy.connected_peers_fallback = Some(Duration::from_secs(5));
+            })
+        };
+        let (store1, _tmp1) = create_store(NonZeroU64::new(1000));
+        let (store2, _tmp2) = create_store(None);
+        let blocks: Vec<_> = (0..3u8).map(|i| create_block(&[i; 1000])).collect();
+        for block in &blocks {
+            store1.insert(block).await.unwrap();
+        }
+        store2.connect(store1.address().clone()).await.unwrap();
+
+        // the first block is sent right away, the others wait for the budget
+        let start = std::time::Instant::now();
+        let gets = blocks.iter().map(|block| store2.get(block.cid.clone()));
+        for (block, block2) in blocks.iter().zip(futures::future::join_all(gets).await) {
+            assert_eq!(block.data, block2.unwrap().data);
+        }
+        assert!(start.elapsed() >= Duration::from_millis(1500));
+    }
+
+    #[async_std::test]
+    async fn test_shutdown() {
+        env_logger::try_init().ok();
+        let (store, _tmp) = create_store_with(vec![], |config| {
+            config.network.enable_mdns = false;
+            // keep the get pending until the shutdown
+            config.network.want_policy.retries = 100;
+            config.network.want_policy.backoff = Duration::from_millis(100);
+        });
+        let block = create_block(b"test_shutdown");
+        store.insert(&block).await.unwrap();
+
+        let missing = create_block(b"test_shutdown_missing");
+        let get = task::spawn({
+            let store = store.clone();
+            async move { store.get(missing.cid).await }
+        });
+        // make sure the want reached the network
+        task::sleep(Duration::from_millis(100)).await;
+        store.shutdown().await.unwrap();
+        assert!(get.await.is_err());
+        assert!(matches!(store.peers().await, Err(Error::NetworkStopped(_))));
+        assert!(store.get_local(&block.cid).unwrap().is_some());
+    }
+
+    #[async_std::test]
+    async fn test_wantlist() {
+        env_logger::try_init().ok();
+        let create_store = || {
+            create_store_with(vec![], |config| {
+                config.network.enable_mdns = false;
+                config.network.want_policy.retries = 100;
+                config.network.want_policy.backoff = Duration::from_millis(100);
+            })
+        };
+        let (store1, _tmp1) = create_store();
+        let (store2, _tmp2) = create_store();
+        store1.connect(store2.address().clone()).await.unwrap();
+        assert!(store1.wantlist().await.unwrap().is_empty());
+
+        let block = create_block(b"test_wantlist");
+        let get = task::spawn({
+            let store = store1.clone();
+            let cid = block.cid.clone();
+            async move { store.get_with_priority(&cid, 5).await }
+        });
+        task::sleep(Duration::from_millis(100)).await;
+        let wants = store1.wantlist().await.unwrap();
+        assert_eq!(wants.len(), 1);
+        assert_eq!(wants[0].cid, block.cid);
+        assert_eq!(wants[0].priority, 5);
+        assert_eq!(wants[0].peers, vec![store2.peer_id().clone()]);
+
+        // the want is removed once the block is received
+        store2.insert(&block).await.unwrap();
+        get.await.unwrap();
+        assert!(store1.wantlist().await.unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_max_wants_in_flight() {
+        env_logger::try_init().ok();
+        let create_store = |max_wants| {
+            create_store_with(vec![], |config| {
+                config.network.enable_mdns = false;
+                config.network.want_policy.max_wants_in_flight = max_wants;
+            })
+        };
+        let (store1, _tmp1) = create_store(NonZeroUsize::new(1));
+        let (store2, _tmp2) = create_store(None);
+        store1.connect(store2.address().clone()).await.unwrap();
+
+        let a = create_block(b"test_max_wants_in_flight_a");
+        let b = create_block(b"test_max_wants_in_flight_b");
+        let get = |block: &Block<Multicodec, Multihash>| {
+            let store = store1.clone();
+            let cid = block.cid.clone();
+            task::spawn(async move { store.get_with_priority(&cid, DEFAULT_PRIORITY).await })
+        };
+        let get_a = get(&a);
+        task::sleep(Duration::from_millis(100)).await;
+        let get_b = get(&b);
+        task::sleep(Duration::from_millis(100)).await;
+        let wants = store1.wantlist().await.unwrap();
+        assert_eq!(wants.len(), 1);
+        assert_eq!(wants[0].cid, a.cid);
+
+        // the want of b is sent once a is received
+        store2.insert(&b).await.unwrap();
+        store2.insert(&a).await.unwrap();
+        get_a.await.unwrap();
+        get_b.await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_websocket_address() {
+        env_logger::try_init().ok();
+        let (store, _tmp) = create_store_with(vec![], |config| {
+            config.network.enable_mdns = false;
+            config.network.enable_websocket = true;
+            config.network.listen_addresses = vec!["/ip4/127.0.0.1/tcp/0/ws".parse().unwrap()];
+        });
+        assert_eq!(
+            store.address().iter().last(),
+            Some(Protocol::Ws("/".into()))
+        );
     }
 
     #[async_std::test]
@@ -230,7 +1387,7 @@ mod tests {
         let (store1, _) = create_store(vec![]);
         let block = create_block(b"test_provider_not_found");
         if store1
-            .get(block.cid)
+            .get(block.cid.clone())
             .await
             .unwrap_err()
             .downcast_ref::<BlockNotFound>()
@@ -238,6 +1395,209 @@ mod tests {
         {
             panic!("expected block not found error");
         }
+        match store1.get_with_priority(&block.cid, DEFAULT_PRIORITY).await {
+            Err(Error::BlockNotFound(_)) => {}
+            res => panic!("expected block not found error, got {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[async_std::test]
+    async fn test_want_timeout() {
+        env_logger::try_init().ok();
+        let (store1, _tmp) = create_store_with(vec![], |config| {
+            config.network.enable_mdns = false;
+            config.network.want_policy.timeout = Some(Duration::from_millis(500));
+            config.network.want_policy.retries = 100;
+            config.network.want_policy.backoff = Duration::from_millis(100);
+        });
+
+        // there are no providers, but the searches are retried until the
+        // want times out long before the get
+        let block = create_block(b"test_want_timeout");
+        let start = std::time::Instant::now();
+        match store1.get_with_priority(&block.cid, DEFAULT_PRIORITY).await {
+            Err(Error::Timeout(_)) => {}
+            res => panic!("expected timeout error, got {:?}", res.map(|_| ())),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[async_std::test]
+    async fn test_provider_not_found_connected() {
+        env_logger::try_init().ok();
+        let create_store = || {
+            create_store_with(vec![], |config| {
+                config.network.enable_mdns = false;
+                config.network.want_policy.connected_peers_fallback = None;
+            })
+        };
+        let (store1, _tmp1) = create_store();
+        let (store2, _tmp2) = create_store();
+        store1.connect(store2.address().clone()).await.unwrap();
+
+        // connected peers don't keep the get alive until the store timeout
+        let block = create_block(b"test_provider_not_found_connected");
+        let start = std::time::Instant::now();
+        match store1.get_with_priority(&block.cid, DEFAULT_PRIORITY).await {
+            Err(Error::BlockNotFound(_)) => {}
+            res => panic!("expected block not found error, got {:?}", res.map(|_| ())),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[async_std::test]
+    async fn test_storage_events_backpressure() {
+        env_logger::try_init().ok();
+        let create_store = |capacity| {
+            create_store_with(vec![], |config| {
+                config.network.enable_mdns = false;
+                config.network.storage_events_capacity = capacity;
+                // the busy network takes a while to respond
+                config.network.want_policy.connected_peers_fallback = Some(Duration::from_secs(30));
+            })
+        };
+        let (store1, _tmp1) = create_store(1);
+        let (store2, _tmp2) = create_store(1024);
+        store1.connect(store2.address().clone()).await.unwrap();
+
+        let mut wanted = vec![];
+        for i in 0..50 {
+            let block = create_block(format!("wanted {}", i).as_bytes());
+            store2.insert(&block).await.unwrap();
+            wanted.push(block.cid);
+        }
+        // the network inserts the received blocks while the flood fills the
+        // buffers, which mustn't deadlock it
+        let flood = async {
+            for i in 0..2000 {
+                let mut block = create_block(format!("flood {}", i).as_bytes());
+                if i % 20 != 0 {
+                    block.set_visibility(Visibility::Private);
+                }
+                store1.insert(&block).await.unwrap();
+            }
+        };
+        let gets = try_join_all(
+            wanted
+                .iter()
+                .map(|cid| store1.get_with_priority(cid, DEFAULT_PRIORITY)),
+        );
+        let (_, blocks) = timeout(Duration::from_secs(60), futures::future::join(flood, gets))
+            .await
+            .unwrap();
+        assert_eq!(blocks.unwrap().len(), wanted.len());
+    }
+
+    #[async_std::test]
+    async fn test_connected_peers_fallback() {
+        env_logger::try_init().ok();
+        let create_store = || {
+            create_store_with(vec![], |config| {
+                config.network.enable_mdns = false;
+                config.timeout = Duration::from_secs(10);
+                config.network.want_policy.retries = 1;
+                config.network.want_policy.backoff = Duration::from_millis(100);
+                config.network.want_policy.connected_peers_fallback =
+                    Some(Duration::from_millis(500));
+            })
+        };
+        let (store1, _tmp1) = create_store();
+        let (store2, _tmp2) = create_store();
+        store1.connect(store2.address().clone()).await.unwrap();
+
+        // the connected peer is asked again, but doesn't have the block
+        let block = create_block(b"test_connected_peers_fallback");
+        let start = std::time::Instant::now();
+        match store1.get_with_priority(&block.cid, DEFAULT_PRIORITY).await {
+            Err(Error::BlockNotFound(_)) => {}
+            res => panic!("expected block not found error, got {:?}", res.map(|_| ())),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(store1.wantlist().await.unwrap().is_empty());
+
+        // the block is received if the peer gets it before the deadline
+        let get = {
+            let store1 = store1.clone();
+            let cid = block.cid.clone();
+            task::spawn(async move { store1.get_with_priority(&cid, DEFAULT_PRIORITY).await })
+        };
+        task::sleep(Duration::from_millis(300)).await;
+        store2.insert(&block).await.unwrap();
+        assert_eq!(get.await.unwrap().data, block.data);
+    }
+
+    #[async_std::test]
+    async fn test_offline() {
+        env_logger::try_init().ok();
+        let create_store = |offline| {
+            create_store_with(vec![], |config| {
+                config.network.enable_mdns = false;
+                config.offline = offline;
+            })
+        };
+        let (store1, _tmp1) = create_store(false);
+        let (store2, _tmp2) = create_store(true);
+        store2.connect(store1.address().clone()).await.unwrap();
+
+        // the connected peer has the block, but it isn't asked for it
+        let block = create_block(b"test_offline");
+        store1.insert(&block).await.unwrap();
+        match store2.get_with_priority(&block.cid, DEFAULT_PRIORITY).await {
+            Err(Error::BlockNotLocal(_)) => {}
+            res => panic!("expected block not local error, got {:?}", res.map(|_| ())),
+        }
+        assert!(store2.wantlist().await.unwrap().is_empty());
+
+        store2.insert(&block).await.unwrap();
+        store2
+            .get_with_priority(&block.cid, DEFAULT_PRIORITY)
+            .await
+            .unwrap();
+        store2.provide(&block.cid).await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_put_hash_code() {
+        env_logger::try_init().ok();
+        let create_store = |hash_code| {
+            create_store_with(vec![], |config| {
+                config.network.enable_mdns = false;
+                config.verify_blocks = true;
+                config.hash_code = hash_code;
+            })
+        };
+        let (store1, _tmp1) = create_store(SHA2_256);
+        let (store2, _tmp2) = create_store(BLAKE2B_256);
+        let block1 = store1
+            .put(RawCodec, &b"test_put_hash_code"[..])
+            .await
+            .unwrap();
+        let block2 = store2
+            .put(RawCodec, &b"test_put_hash_code"[..])
+            .await
+            .unwrap();
+        assert_ne!(block1.cid, block2.cid);
+        assert_eq!(block1.cid.hash().code(), SHA2_256);
+        assert_eq!(block2.cid.hash().code(), BLAKE2B_256);
+        // blocks are verified with the hash code of their cid
+        store2.insert(&block1).await.unwrap();
+        assert_eq!(
+            store2.get_local(&block1.cid).unwrap().unwrap(),
+            &block1.data[..]
+        );
+        assert_eq!(
+            store2.get_local(&block2.cid).unwrap().unwrap(),
+            &block2.data[..]
+        );
+
+        let tmp = TempDir::new("").unwrap();
+        let mut config = Config::from_path_local(tmp.path()).unwrap();
+        config.hash_code = 0x42;
+        assert!(matches!(
+            Store::<Multicodec, Multihash>::new(config),
+            Err(Error::Codec(_))
+        ));
     }
 
     async fn get<C: Codec, M: MultihashDigest>(store: &Store<C, M>, cid: &Cid) -> Option<Ipld> {
@@ -257,6 +1617,74 @@ mod tests {
         block.cid
     }
 
+    #[async_std::test]
+    async fn test_get_graph() {
+        env_logger::try_init().ok();
+        let (store1, _) = create_store(vec![]);
+        let (store2, _) = create_store(vec![]);
+        let leaf = insert(&store1, &ipld!({ "leaf": [] })).await;
+        let node = insert(&store1, &ipld!({ "node": [&leaf] })).await;
+        let root = insert(&store1, &ipld!({ "root": [&node] })).await;
+        store2.connect(store1.address().clone()).await.unwrap();
+
+        assert_eq!(store2.get_graph(&root, 1).await.unwrap(), 2);
+        assert!(get(&store2, &node).await.is_some());
+        assert!(get(&store2, &leaf).await.is_none());
+        assert_eq!(store2.get_graph(&root, 5).await.unwrap(), 3);
+        assert!(get(&store2, &leaf).await.is_some());
+    }
+
+    #[async_std::test]
+    async fn test_provide_roots_only() {
+        env_logger::try_init().ok();
+        let (store, _) = create_store(vec![]);
+        // make sure bootstrap node has started
+        task::sleep(Duration::from_millis(500)).await;
+        let bootstrap = vec![(store.address().clone(), store.peer_id().clone())];
+        let (store1, _tmp1) = create_store_with(bootstrap.clone(), |config| {
+            config.network.provide_roots_only = true;
+        });
+        let (store2, _) = create_store(bootstrap);
+        let leaf = insert(&store1, &ipld!({ "leaf": [] })).await;
+        let root = insert(&store1, &ipld!({ "root": [&leaf] })).await;
+        store1.set_root(&root, true).await.unwrap();
+        assert_eq!(store1.roots().next().unwrap().unwrap(), root);
+        assert!(store1.metadata(&root).unwrap().root);
+        assert!(!store1.metadata(&leaf).unwrap().root);
+        // make insert had enough time to propagate
+        task::sleep(Duration::from_millis(500)).await;
+        let providers = store2.providers(&root).await.unwrap();
+        assert_eq!(providers, vec![store1.peer_id().clone()]);
+        assert!(store2.providers(&leaf).await.unwrap().is_empty());
+
+        // the rest of the DAG is fetched from the provider of the root
+        assert_eq!(store2.get_graph(&root, 5).await.unwrap(), 2);
+        assert!(get(&store2, &leaf).await.is_some());
+    }
+
+    #[async_std::test]
+    async fn test_resolve_chain() {
+        env_logger::try_init().ok();
+        let (store1, _) = create_store(vec![]);
+        let (store2, _) = create_store(vec![]);
+        let first = insert(&store1, &ipld!({ "version": 1, "prev": null })).await;
+        let second = insert(&store1, &ipld!({ "version": 2, "prev": &first })).await;
+        let head = insert(&store1, &ipld!({ "version": 3, "prev": &second })).await;
+        store2.connect(store1.address().clone()).await.unwrap();
+
+        let (last, path) = store2.resolve_chain(&head, "prev").await.unwrap();
+        assert_eq!(last, first);
+        assert_eq!(path, vec![head.clone(), second, first]);
+        assert!(get(&store2, &last).await.is_some());
+
+        let (last, path) = store2.resolve_chain(&head, "next").await.unwrap();
+        assert_eq!(last, head);
+        assert_eq!(path, vec![head.clone()]);
+
+        let err = store2.resolve_chain(&head, "version").await.unwrap_err();
+        assert!(matches!(err, Error::Codec(_)));
+    }
+
     #[async_std::test]
     async fn test_gc() {
         env_logger::try_init().ok();
@@ -304,4 +1732,37 @@ mod tests {
         assert!(get(&store, &b).await.is_none());
         assert!(get(&store, &c).await.is_none());
     }
+
+    #[async_std::test]
+    async fn test_background_gc() {
+        env_logger::try_init().ok();
+        let (store, _tmp) = create_store_with(vec![], |config| {
+            config.network.enable_mdns = false;
+            config.gc_threshold = Some(10);
+            config.gc_interval = Duration::from_millis(100);
+        });
+        let small = create_block(b"small");
+        let pinned = create_block(b"test_background_gc_pinned");
+        store
+            .insert_blocks(std::slice::from_ref(&small), false)
+            .await
+            .unwrap();
+        store
+            .insert_blocks(std::slice::from_ref(&pinned), true)
+            .await
+            .unwrap();
+        task::sleep(Duration::from_millis(300)).await;
+        // below the threshold
+        assert!(store.contains(&small.cid).unwrap());
+
+        let dead = create_block(b"test_background_gc_dead");
+        store
+            .insert_blocks(std::slice::from_ref(&dead), false)
+            .await
+            .unwrap();
+        task::sleep(Duration::from_millis(300)).await;
+        assert!(!store.contains(&dead.cid).unwrap());
+        assert!(!store.contains(&small.cid).unwrap());
+        assert!(store.contains(&pinned.cid).unwrap());
+    }
 }